@@ -0,0 +1,909 @@
+use crate::KubeConfError;
+use crate::flatten::resolve_path;
+use crate::kubeconfig::{KubeConfig, NamedCluster};
+use crate::lint::{Finding, Severity};
+use base64::Engine;
+use std::fs;
+use std::net::IpAddr;
+use std::path::Path;
+use std::time::Duration;
+use x509_parser::asn1_rs::{Any, Class, FromDer, Oid};
+use x509_parser::oid_registry::{
+    OID_EC_P256, OID_KEY_TYPE_EC_PUBLIC_KEY, OID_NIST_EC_P384, OID_NIST_EC_P521,
+    OID_PKCS1_RSAENCRYPTION, OID_SIG_ED448, OID_SIG_ED25519,
+};
+use x509_parser::prelude::{ASN1Time, GeneralName, Pem, X509Certificate};
+use x509_parser::public_key::PublicKey;
+
+/// The interesting parts of a single X.509 certificate.
+pub struct CertificateInfo {
+    pub subject: String,
+    pub issuer: String,
+    pub sans: Vec<String>,
+    pub not_before: String,
+    pub not_after: String,
+    /// Seconds since the unix epoch.
+    pub not_after_timestamp: i64,
+    pub is_ca: bool,
+    /// Key type and size, e.g. `RSA 2048` or `EC P-256`.
+    pub key: String,
+}
+
+/// Certificates expiring within this time are highlighted as expiring soon.
+pub const EXPIRY_WARNING: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Expiry {
+    Expired,
+    ExpiringSoon,
+    Valid,
+}
+
+impl CertificateInfo {
+    /// Seconds until the certificate expires, negative if it already has.
+    pub fn seconds_until_expiry(&self) -> i64 {
+        self.not_after_timestamp - ASN1Time::now().timestamp()
+    }
+
+    pub fn expiry(&self, within: Duration) -> Expiry {
+        let remaining = self.seconds_until_expiry();
+        if remaining <= 0 {
+            Expiry::Expired
+        } else if remaining < within.as_secs() as i64 {
+            Expiry::ExpiringSoon
+        } else {
+            Expiry::Valid
+        }
+    }
+}
+
+/// Human readable time until or since expiry, e.g. `in 29d`, `in 5h` or `expired 3d ago`.
+pub fn format_expiry(seconds: i64) -> String {
+    let amount = seconds.abs();
+    let amount = if amount >= 24 * 60 * 60 {
+        format!("{}d", amount / (24 * 60 * 60))
+    } else if amount >= 60 * 60 {
+        format!("{}h", amount / (60 * 60))
+    } else {
+        format!("{}m", amount / 60)
+    };
+    if seconds <= 0 {
+        format!("expired {} ago", amount)
+    } else {
+        format!("in {}", amount)
+    }
+}
+
+/// Parses a duration like `30d`, `12h`, `2w`, `90m` or `3600s`.
+pub fn parse_duration(duration: &str) -> Result<Duration, String> {
+    let duration = duration.trim();
+    let split = duration
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(duration.len());
+    let (amount, unit) = duration.split_at(split);
+    let Ok(amount) = amount.parse::<u64>() else {
+        return Err(format!(
+            "`{}` is not a duration, expected e.g. 30d, 12h or 2w",
+            duration
+        ));
+    };
+    let seconds = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "" | "d" => 24 * 60 * 60,
+        "w" => 7 * 24 * 60 * 60,
+        _ => {
+            return Err(format!(
+                "unknown duration unit `{}`, expected one of s, m, h, d, w",
+                unit
+            ));
+        }
+    };
+    Ok(Duration::from_secs(amount * seconds))
+}
+
+/// The certificates found in one certificate field of a cluster or user.
+pub struct CertificateReport {
+    /// e.g.: cluster `prod` certificate-authority
+    pub description: String,
+    /// Either `embedded` or the path of the referenced file.
+    pub location: String,
+    /// The decoded certificates or why they could not be decoded.
+    pub certificates: Result<Vec<CertificateInfo>, String>,
+    /// Whether a client key is configured for the certificate.
+    pub has_key: bool,
+    /// Whether the client key belongs to the client certificate.
+    /// `None` if there is no key or it could not be determined.
+    pub key_matches: Option<bool>,
+}
+
+/// Reads the contents of a certificate or key field. Embedded data takes precedence over the file.
+/// Returns the contents and where they came from.
+pub fn read_field(
+    base_dir: &Path,
+    path: &Option<String>,
+    data: &Option<String>,
+) -> Option<(String, Result<Vec<u8>, String>)> {
+    if let Some(data) = data {
+        let contents = base64::engine::general_purpose::STANDARD
+            .decode(data)
+            .map_err(|e| format!("not valid base64: {}", e));
+        return Some(("embedded".to_string(), contents));
+    }
+
+    let path = path.as_ref()?;
+    let resolved = resolve_path(base_dir, path);
+    let contents = fs::read(&resolved).map_err(|e| e.to_string());
+    Some((resolved.display().to_string(), contents))
+}
+
+/// Decodes every PEM block with the given label.
+fn pem_blocks(contents: &[u8], label: Option<&str>) -> Result<Vec<Pem>, String> {
+    let mut blocks: Vec<Pem> = vec![];
+    for pem in Pem::iter_from_buffer(contents) {
+        let pem = pem.map_err(|e| format!("not valid PEM: {}", e))?;
+        if label.is_none_or(|label| pem.label == label) {
+            blocks.push(pem);
+        }
+    }
+    Ok(blocks)
+}
+
+fn curve_name(oid: &Oid) -> String {
+    if *oid == OID_EC_P256 {
+        "P-256".to_string()
+    } else if *oid == OID_NIST_EC_P384 {
+        "P-384".to_string()
+    } else if *oid == OID_NIST_EC_P521 {
+        "P-521".to_string()
+    } else {
+        oid.to_id_string()
+    }
+}
+
+fn key_description(certificate: &X509Certificate) -> String {
+    let public_key = certificate.public_key();
+    let algorithm = &public_key.algorithm.algorithm;
+    if *algorithm == OID_PKCS1_RSAENCRYPTION {
+        let size = public_key.parsed().map(|k| k.key_size()).unwrap_or(0);
+        format!("RSA {}", size)
+    } else if *algorithm == OID_KEY_TYPE_EC_PUBLIC_KEY {
+        match public_key
+            .algorithm
+            .parameters
+            .as_ref()
+            .and_then(|p| p.as_oid().ok())
+        {
+            Some(curve) => format!("EC {}", curve_name(&curve)),
+            None => "EC".to_string(),
+        }
+    } else if *algorithm == OID_SIG_ED25519 {
+        "Ed25519".to_string()
+    } else if *algorithm == OID_SIG_ED448 {
+        "Ed448".to_string()
+    } else {
+        algorithm.to_id_string()
+    }
+}
+
+fn general_name(name: &GeneralName) -> String {
+    match name {
+        GeneralName::DNSName(dns) => format!("DNS:{}", dns),
+        GeneralName::IPAddress(bytes) => {
+            let ip = match bytes.len() {
+                4 => <[u8; 4]>::try_from(*bytes).ok().map(IpAddr::from),
+                16 => <[u8; 16]>::try_from(*bytes).ok().map(IpAddr::from),
+                _ => None,
+            };
+            match ip {
+                Some(ip) => format!("IP:{}", ip),
+                None => format!("IP:{:02x?}", bytes),
+            }
+        }
+        GeneralName::RFC822Name(email) => format!("email:{}", email),
+        GeneralName::URI(uri) => format!("URI:{}", uri),
+        other => other.to_string(),
+    }
+}
+
+fn certificate_info(certificate: &X509Certificate) -> CertificateInfo {
+    let sans = match certificate.subject_alternative_name() {
+        Ok(Some(san)) => san.value.general_names.iter().map(general_name).collect(),
+        _ => vec![],
+    };
+    CertificateInfo {
+        subject: certificate.subject().to_string(),
+        issuer: certificate.issuer().to_string(),
+        sans,
+        not_before: certificate.validity().not_before.to_string(),
+        not_after: certificate.validity().not_after.to_string(),
+        not_after_timestamp: certificate.validity().not_after.timestamp(),
+        is_ca: certificate.is_ca(),
+        key: key_description(certificate),
+    }
+}
+
+fn decode_certificates(contents: &[u8]) -> Result<Vec<CertificateInfo>, String> {
+    let blocks = pem_blocks(contents, Some("CERTIFICATE"))?;
+    if blocks.is_empty() {
+        return Err("no PEM encoded certificate found".to_string());
+    }
+
+    let mut certificates: Vec<CertificateInfo> = vec![];
+    for pem in &blocks {
+        let certificate = pem
+            .parse_x509()
+            .map_err(|e| format!("not a valid X.509 certificate: {}", e))?;
+        certificates.push(certificate_info(&certificate));
+    }
+    Ok(certificates)
+}
+
+/// Splits DER contents into its top level elements.
+fn der_elements(mut data: &[u8]) -> Option<Vec<Any<'_>>> {
+    let mut elements: Vec<Any> = vec![];
+    while !data.is_empty() {
+        let (rest, element) = Any::from_der(data).ok()?;
+        elements.push(element);
+        data = rest;
+    }
+    Some(elements)
+}
+
+/// The elements of the DER sequence in `data`.
+fn der_sequence(data: &[u8]) -> Option<Vec<Any<'_>>> {
+    let (_, sequence) = Any::from_der(data).ok()?;
+    der_elements(sequence.data)
+}
+
+fn strip_leading_zeros(bytes: &[u8]) -> &[u8] {
+    let start = bytes.iter().position(|b| *b != 0).unwrap_or(bytes.len());
+    &bytes[start..]
+}
+
+/// The public part of a private key, as far as it can be told without doing any cryptography.
+enum PrivateKeyPublicPart<'a> {
+    Rsa {
+        modulus: &'a [u8],
+        exponent: &'a [u8],
+    },
+    /// The encoded public point of an EC key.
+    Ec(&'a [u8]),
+}
+
+/// RSAPrivateKey ::= SEQUENCE { version, modulus, publicExponent, ... } (RFC 8017)
+fn rsa_public_part(der: &[u8]) -> Option<PrivateKeyPublicPart<'_>> {
+    let elements = der_sequence(der)?;
+    Some(PrivateKeyPublicPart::Rsa {
+        modulus: elements.get(1)?.data,
+        exponent: elements.get(2)?.data,
+    })
+}
+
+/// ECPrivateKey ::= SEQUENCE { version, privateKey, [0] parameters OPTIONAL, [1] publicKey OPTIONAL } (RFC 5915)
+fn ec_public_part(der: &[u8]) -> Option<PrivateKeyPublicPart<'_>> {
+    let elements = der_sequence(der)?;
+    let public_key = elements
+        .iter()
+        .find(|e| e.class() == Class::ContextSpecific && e.tag().0 == 1)?;
+    let (_, bit_string) = Any::from_der(public_key.data).ok()?;
+    // The first byte of a bit string is the number of unused bits.
+    Some(PrivateKeyPublicPart::Ec(bit_string.data.get(1..)?))
+}
+
+/// PrivateKeyInfo ::= SEQUENCE { version, privateKeyAlgorithm, privateKey OCTET STRING, ... } (RFC 5208)
+fn pkcs8_public_part(der: &[u8]) -> Option<PrivateKeyPublicPart<'_>> {
+    let elements = der_sequence(der)?;
+    let algorithm = der_elements(elements.get(1)?.data)?;
+    let algorithm = Oid::try_from(algorithm.first()?.clone()).ok()?;
+    let private_key = elements.get(2)?.data;
+    if algorithm == OID_PKCS1_RSAENCRYPTION {
+        rsa_public_part(private_key)
+    } else if algorithm == OID_KEY_TYPE_EC_PUBLIC_KEY {
+        ec_public_part(private_key)
+    } else {
+        // e.g. Ed25519 keys only contain the seed.
+        None
+    }
+}
+
+/// Checks whether the private key in `key` belongs to the first certificate in `certificate`.
+/// Returns `None` if either can not be decoded or the key type does not carry its public part.
+fn key_matches_certificate(certificate: &[u8], key: &[u8]) -> Option<bool> {
+    let key = pem_blocks(key, None)
+        .ok()?
+        .into_iter()
+        .find(|pem| pem.label.ends_with("PRIVATE KEY"))?;
+    let public_part = match key.label.as_str() {
+        "RSA PRIVATE KEY" => rsa_public_part(&key.contents),
+        "EC PRIVATE KEY" => ec_public_part(&key.contents),
+        "PRIVATE KEY" => pkcs8_public_part(&key.contents),
+        _ => None,
+    }?;
+
+    let certificate = pem_blocks(certificate, Some("CERTIFICATE"))
+        .ok()?
+        .into_iter()
+        .next()?;
+    let certificate = certificate.parse_x509().ok()?;
+    let matches = match (certificate.public_key().parsed().ok()?, public_part) {
+        (PublicKey::RSA(rsa), PrivateKeyPublicPart::Rsa { modulus, exponent }) => {
+            strip_leading_zeros(rsa.modulus) == strip_leading_zeros(modulus)
+                && strip_leading_zeros(rsa.exponent) == strip_leading_zeros(exponent)
+        }
+        (PublicKey::EC(point), PrivateKeyPublicPart::Ec(public_point)) => {
+            point.data() == public_point
+        }
+        // Different key types never match.
+        _ => false,
+    };
+    Some(matches)
+}
+
+/// Decodes the certificates of the cluster and user of the given context.
+/// Relative paths are resolved against `base_dir`.
+pub fn inspect_context(
+    kubeconfig: &KubeConfig,
+    context_name: &str,
+    base_dir: &Path,
+) -> Result<Vec<CertificateReport>, KubeConfError> {
+    let Some(context) = kubeconfig.get_context(context_name) else {
+        return Err(KubeConfError::CertificateError(format!(
+            "Context `{}` not found in kubeconfig.",
+            context_name
+        )));
+    };
+    let mut reports: Vec<CertificateReport> = vec![];
+
+    if let Some(cluster) = kubeconfig.get_cluster(&context.context.cluster)
+        && let Some((location, contents)) = read_field(
+            base_dir,
+            &cluster.cluster.certificate_authority,
+            &cluster.cluster.certificate_authority_data,
+        )
+    {
+        reports.push(CertificateReport {
+            description: format!("cluster `{}` certificate-authority", cluster.name),
+            location,
+            certificates: contents.and_then(|c| decode_certificates(&c)),
+            has_key: false,
+            key_matches: None,
+        });
+    }
+
+    if let Some(user) = kubeconfig.get_user(&context.context.user)
+        && let Some((location, contents)) = read_field(
+            base_dir,
+            &user.user.client_certificate,
+            &user.user.client_certificate_data,
+        )
+    {
+        let key = read_field(base_dir, &user.user.client_key, &user.user.client_key_data);
+        let has_key = key.is_some();
+        let key_matches = match (&contents, key) {
+            (Ok(certificate), Some((_, Ok(key)))) => key_matches_certificate(certificate, &key),
+            _ => None,
+        };
+        reports.push(CertificateReport {
+            description: format!("user `{}` client-certificate", user.name),
+            location,
+            certificates: contents.and_then(|c| decode_certificates(&c)),
+            has_key,
+            key_matches,
+        });
+    }
+
+    Ok(reports)
+}
+
+/// The host of a server URL, without port and brackets.
+/// e.g.: `https://[::1]:6443` is `::1`, `https://api.example.com:6443/prefix` is `api.example.com`
+pub fn server_host(server: &str) -> Option<String> {
+    let rest = match server.split_once("://") {
+        Some((_, rest)) => rest,
+        None => server,
+    };
+    let authority = rest.split(['/', '?', '#']).next()?;
+    let authority = match authority.rsplit_once('@') {
+        Some((_, authority)) => authority,
+        None => authority,
+    };
+    let host = match authority.strip_prefix('[') {
+        Some(bracketed) => bracketed.split_once(']')?.0,
+        None => authority.split(':').next()?,
+    };
+    if host.is_empty() {
+        None
+    } else {
+        Some(host.to_lowercase())
+    }
+}
+
+fn dns_name_matches(pattern: &str, host: &str) -> bool {
+    let pattern = pattern.to_lowercase();
+    match pattern.strip_prefix("*.") {
+        // Wildcards only cover a single label.
+        Some(suffix) => host
+            .split_once('.')
+            .is_some_and(|(label, rest)| !label.is_empty() && rest == suffix),
+        None => pattern == host,
+    }
+}
+
+/// Whether the subject alternative names of the certificate cover the host.
+/// Like Go's TLS client, the common name is not considered.
+fn certificate_covers_host(certificate: &X509Certificate, host: &str) -> bool {
+    let Ok(Some(san)) = certificate.subject_alternative_name() else {
+        return false;
+    };
+    let ip = host.parse::<IpAddr>().ok();
+    san.value.general_names.iter().any(|name| match (name, ip) {
+        (GeneralName::IPAddress(bytes), Some(ip)) => match ip {
+            IpAddr::V4(ip) => *bytes == ip.octets(),
+            IpAddr::V6(ip) => *bytes == ip.octets(),
+        },
+        (GeneralName::DNSName(pattern), None) => dns_name_matches(pattern, host),
+        _ => false,
+    })
+}
+
+fn parse_certificates(pems: &[Pem]) -> Result<Vec<X509Certificate<'_>>, String> {
+    pems.iter()
+        .map(|pem| {
+            pem.parse_x509()
+                .map_err(|e| format!("not a valid X.509 certificate: {}", e))
+        })
+        .collect()
+}
+
+/// Checks that the certificate authority of the cluster only holds CAs and that a serving certificate
+/// chaining to it would be accepted for the server host (or `tls-server-name`).
+/// The serving certificate is either given in `server_certificate` or pinned as a non-CA certificate
+/// in the certificate authority itself. Without any serving certificate the host can not be checked offline.
+pub fn check_certificate_authority(
+    cluster: &NamedCluster,
+    base_dir: &Path,
+    server_certificate: Option<&[u8]>,
+) -> Vec<Finding> {
+    let mut findings: Vec<Finding> = vec![];
+    let entry = format!("cluster `{}`", cluster.name);
+    let mut finding = |severity: Severity, rule: &'static str, message: String| {
+        findings.push(Finding {
+            severity,
+            rule,
+            entry: entry.clone(),
+            message,
+        })
+    };
+
+    let Some((location, contents)) = read_field(
+        base_dir,
+        &cluster.cluster.certificate_authority,
+        &cluster.cluster.certificate_authority_data,
+    ) else {
+        return findings;
+    };
+    let authority_pems = match contents.and_then(|c| pem_blocks(&c, Some("CERTIFICATE"))) {
+        Ok(pems) if !pems.is_empty() => pems,
+        Ok(_) => {
+            finding(
+                Severity::Error,
+                "ca-unreadable",
+                format!("certificate-authority ({}) holds no certificate", location),
+            );
+            return findings;
+        }
+        Err(error) => {
+            finding(
+                Severity::Error,
+                "ca-unreadable",
+                format!("certificate-authority ({}) {}", location, error),
+            );
+            return findings;
+        }
+    };
+    let authority = match parse_certificates(&authority_pems) {
+        Ok(authority) => authority,
+        Err(error) => {
+            finding(
+                Severity::Error,
+                "ca-unreadable",
+                format!("certificate-authority ({}) {}", location, error),
+            );
+            return findings;
+        }
+    };
+
+    let (authorities, pinned): (Vec<&X509Certificate>, Vec<&X509Certificate>) =
+        authority.iter().partition(|c| c.is_ca());
+    for certificate in &pinned {
+        finding(
+            Severity::Warning,
+            "ca-not-a-ca",
+            format!(
+                "certificate-authority contains `{}` which is not a CA certificate",
+                certificate.subject()
+            ),
+        );
+    }
+
+    let host = cluster
+        .cluster
+        .tls_server_name
+        .as_ref()
+        .map(|name| name.to_lowercase())
+        .or_else(|| server_host(&cluster.cluster.server));
+    let Some(host) = host else {
+        return findings;
+    };
+
+    let server_pems = match server_certificate.map(|c| pem_blocks(c, Some("CERTIFICATE"))) {
+        Some(Ok(pems)) => pems,
+        Some(Err(error)) => {
+            finding(
+                Severity::Error,
+                "server-certificate-unreadable",
+                format!("server certificate {}", error),
+            );
+            return findings;
+        }
+        None => vec![],
+    };
+    let server = match parse_certificates(&server_pems) {
+        Ok(server) => server,
+        Err(error) => {
+            finding(
+                Severity::Error,
+                "server-certificate-unreadable",
+                format!("server certificate {}", error),
+            );
+            return findings;
+        }
+    };
+
+    // Only the leaf of a given chain is checked against the host.
+    if let Some(leaf) = server.first() {
+        let issued_by = |issuer: &X509Certificate, certificate: &X509Certificate| {
+            certificate.issuer() == issuer.subject()
+                && certificate
+                    .verify_signature(Some(issuer.public_key()))
+                    .is_ok()
+        };
+        let mut chained = authority.iter().any(|c| c.as_raw() == leaf.as_raw());
+        let mut current = leaf;
+        // Walk the intermediates given with the leaf until a certificate is signed by the CA.
+        for _ in 0..server.len() {
+            if authorities.iter().any(|ca| issued_by(ca, current)) {
+                chained = true;
+                break;
+            }
+            match server.iter().find(|c| issued_by(c, current)) {
+                Some(issuer) => current = issuer,
+                None => break,
+            }
+        }
+        if !chained {
+            finding(
+                Severity::Error,
+                "ca-mismatch",
+                format!(
+                    "server certificate `{}` issued by `{}` does not chain to the certificate-authority",
+                    leaf.subject(),
+                    leaf.issuer()
+                ),
+            );
+        }
+    }
+
+    let leaves: Vec<&X509Certificate> = server.first().into_iter().chain(pinned).collect();
+    for leaf in leaves {
+        if !certificate_covers_host(leaf, &host) {
+            let sans = match leaf.subject_alternative_name() {
+                Ok(Some(san)) => san
+                    .value
+                    .general_names
+                    .iter()
+                    .map(general_name)
+                    .collect::<Vec<String>>()
+                    .join(", "),
+                _ => "none".to_string(),
+            };
+            finding(
+                Severity::Error,
+                "server-not-covered",
+                format!(
+                    "server certificate `{}` is not valid for `{}`, its SANs are: {}",
+                    leaf.subject(),
+                    host,
+                    sans
+                ),
+            );
+        }
+    }
+
+    findings
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use base64::engine::general_purpose::STANDARD;
+    use std::path::PathBuf;
+
+    const CA: &[u8] = include_bytes!("../tests/fixtures/certs/ca.crt");
+    const SERVER: &[u8] = include_bytes!("../tests/fixtures/certs/server.crt");
+    const SERVER_KEY: &[u8] = include_bytes!("../tests/fixtures/certs/server.key");
+    const CLIENT: &[u8] = include_bytes!("../tests/fixtures/certs/client.crt");
+    const CLIENT_KEY: &[u8] = include_bytes!("../tests/fixtures/certs/client.key");
+
+    fn fixtures_dir() -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/certs")
+    }
+
+    #[test]
+    fn decodes_certificate_fields() {
+        let server = decode_certificates(SERVER).unwrap();
+        assert_eq!(server.len(), 1);
+        let server = &server[0];
+        assert_eq!(server.subject, "CN=api.example.com");
+        assert_eq!(server.issuer, "CN=kubeconf-test-ca");
+        assert_eq!(
+            server.sans,
+            [
+                "DNS:api.example.com",
+                "DNS:*.apps.example.com",
+                "IP:10.0.0.1"
+            ]
+        );
+        assert_eq!(server.key, "EC P-256");
+        assert!(!server.is_ca);
+        // 2125-01-01T00:00:00Z
+        assert_eq!(server.not_after_timestamp, 4891363200);
+
+        let bundle = [CA, CLIENT].concat();
+        let bundle = decode_certificates(&bundle).unwrap();
+        assert_eq!(bundle.len(), 2);
+        assert!(bundle[0].is_ca);
+        assert_eq!(bundle[0].subject, bundle[0].issuer);
+        assert_eq!(bundle[1].key, "RSA 2048");
+        assert!(bundle[1].sans.is_empty());
+
+        assert!(decode_certificates(b"not a certificate").is_err());
+        assert!(decode_certificates(SERVER_KEY).is_err());
+    }
+
+    #[test]
+    fn matches_keys_to_certificates() {
+        assert_eq!(key_matches_certificate(CLIENT, CLIENT_KEY), Some(true));
+        assert_eq!(key_matches_certificate(SERVER, SERVER_KEY), Some(true));
+        assert_eq!(key_matches_certificate(CLIENT, SERVER_KEY), Some(false));
+        assert_eq!(key_matches_certificate(CLIENT, b"no key"), None);
+    }
+
+    #[test]
+    fn inspects_embedded_and_referenced_certificates() {
+        let kubeconfig: KubeConfig = serde_yaml::from_str(&format!(
+            "
+apiVersion: v1
+kind: Config
+clusters:
+- name: prod
+  cluster:
+    server: https://api.example.com
+    certificate-authority-data: {}
+users:
+- name: admin
+  user:
+    client-certificate: client.crt
+    client-key: server.key
+contexts:
+- name: prod
+  context:
+    cluster: prod
+    user: admin
+- name: missing
+  context:
+    cluster: missing
+    user: missing
+",
+            STANDARD.encode(CA)
+        ))
+        .unwrap();
+
+        let reports = inspect_context(&kubeconfig, "prod", &fixtures_dir()).unwrap();
+        assert_eq!(reports.len(), 2);
+        assert_eq!(
+            reports[0].description,
+            "cluster `prod` certificate-authority"
+        );
+        assert_eq!(reports[0].location, "embedded");
+        assert_eq!(
+            reports[0].certificates.as_ref().unwrap()[0].subject,
+            "CN=kubeconf-test-ca"
+        );
+        assert_eq!(reports[1].description, "user `admin` client-certificate");
+        assert_eq!(
+            reports[1].location,
+            fixtures_dir().join("client.crt").display().to_string()
+        );
+        assert!(reports[1].has_key);
+        assert_eq!(reports[1].key_matches, Some(false));
+
+        assert!(
+            inspect_context(&kubeconfig, "missing", &fixtures_dir())
+                .unwrap()
+                .is_empty()
+        );
+        assert!(inspect_context(&kubeconfig, "other", &fixtures_dir()).is_err());
+    }
+
+    fn expiring_in(seconds: i64) -> CertificateInfo {
+        let mut certificate = decode_certificates(SERVER).unwrap().remove(0);
+        certificate.not_after_timestamp = ASN1Time::now().timestamp() + seconds;
+        certificate
+    }
+
+    #[test]
+    fn classifies_expiry_against_the_threshold() {
+        let day = 24 * 60 * 60;
+        assert_eq!(expiring_in(-day).expiry(EXPIRY_WARNING), Expiry::Expired);
+        assert_eq!(
+            expiring_in(29 * day).expiry(EXPIRY_WARNING),
+            Expiry::ExpiringSoon
+        );
+        assert_eq!(expiring_in(31 * day).expiry(EXPIRY_WARNING), Expiry::Valid);
+
+        let within = parse_duration("90d").unwrap();
+        assert_eq!(expiring_in(60 * day).expiry(within), Expiry::ExpiringSoon);
+        assert_eq!(expiring_in(100 * day).expiry(within), Expiry::Valid);
+
+        let client = decode_certificates(CLIENT).unwrap().remove(0);
+        assert_eq!(client.expiry(EXPIRY_WARNING), Expiry::Expired);
+        let server = decode_certificates(SERVER).unwrap().remove(0);
+        assert_eq!(server.expiry(parse_duration("52w").unwrap()), Expiry::Valid);
+    }
+
+    #[test]
+    fn parses_durations() {
+        assert_eq!(
+            parse_duration("30d").unwrap(),
+            Duration::from_secs(30 * 24 * 60 * 60)
+        );
+        assert_eq!(
+            parse_duration("30").unwrap(),
+            Duration::from_secs(30 * 24 * 60 * 60)
+        );
+        assert_eq!(
+            parse_duration("2w").unwrap(),
+            Duration::from_secs(14 * 24 * 60 * 60)
+        );
+        assert_eq!(
+            parse_duration(" 12h ").unwrap(),
+            Duration::from_secs(12 * 60 * 60)
+        );
+        assert_eq!(parse_duration("90m").unwrap(), Duration::from_secs(90 * 60));
+        assert_eq!(parse_duration("3600s").unwrap(), Duration::from_secs(3600));
+        assert!(parse_duration("d").is_err());
+        assert!(parse_duration("-1d").is_err());
+        assert!(parse_duration("1y").is_err());
+    }
+
+    #[test]
+    fn formats_expiry() {
+        assert_eq!(format_expiry(29 * 24 * 60 * 60 + 5), "in 29d");
+        assert_eq!(format_expiry(5 * 60 * 60 + 59 * 60), "in 5h");
+        assert_eq!(format_expiry(90), "in 1m");
+        assert_eq!(format_expiry(0), "expired 0m ago");
+        assert_eq!(format_expiry(-3 * 24 * 60 * 60), "expired 3d ago");
+    }
+
+    #[test]
+    fn extracts_server_hosts() {
+        assert_eq!(
+            server_host("https://api.example.com:6443/prefix").as_deref(),
+            Some("api.example.com")
+        );
+        assert_eq!(server_host("https://[::1]:6443").as_deref(), Some("::1"));
+        assert_eq!(
+            server_host("https://user@API.example.com").as_deref(),
+            Some("api.example.com")
+        );
+        assert_eq!(server_host("10.0.0.1:6443").as_deref(), Some("10.0.0.1"));
+        assert_eq!(server_host("https://"), None);
+    }
+
+    #[test]
+    fn matches_wildcards_for_a_single_label() {
+        assert!(dns_name_matches("api.example.com", "api.example.com"));
+        assert!(dns_name_matches("API.example.com", "api.example.com"));
+        assert!(dns_name_matches(
+            "*.apps.example.com",
+            "web.apps.example.com"
+        ));
+        assert!(!dns_name_matches("*.apps.example.com", "apps.example.com"));
+        assert!(!dns_name_matches(
+            "*.apps.example.com",
+            "a.web.apps.example.com"
+        ));
+        assert!(!dns_name_matches("*.apps.example.com", ".apps.example.com"));
+    }
+
+    fn cluster(server: &str, tls_server_name: Option<&str>, authority: &[u8]) -> NamedCluster {
+        let mut cluster: NamedCluster = serde_yaml::from_str(&format!(
+            "{{name: prod, cluster: {{server: '{}', certificate-authority-data: {}}}}}",
+            server,
+            STANDARD.encode(authority)
+        ))
+        .unwrap();
+        cluster.cluster.tls_server_name = tls_server_name.map(str::to_string);
+        cluster
+    }
+
+    fn rules(cluster: &NamedCluster, server_certificate: Option<&[u8]>) -> Vec<&'static str> {
+        check_certificate_authority(cluster, &fixtures_dir(), server_certificate)
+            .iter()
+            .map(|finding| finding.rule)
+            .collect()
+    }
+
+    #[test]
+    fn checks_the_server_certificate_against_the_authority_and_host() {
+        let other_ca: &[u8] = include_bytes!("../tests/fixtures/certs/other-ca.crt");
+        // server, tls-server-name, certificate-authority and the expected rules
+        type Case<'a> = (&'a str, Option<&'a str>, &'a [u8], &'a [&'a str]);
+        let cases: [Case; 9] = [
+            ("https://api.example.com:6443", None, CA, &[]),
+            ("https://10.0.0.1:6443", None, CA, &[]),
+            ("https://web.apps.example.com", None, CA, &[]),
+            (
+                "https://a.web.apps.example.com",
+                None,
+                CA,
+                &["server-not-covered"],
+            ),
+            (
+                "https://other.example.com",
+                None,
+                CA,
+                &["server-not-covered"],
+            ),
+            ("https://10.0.0.2", None, CA, &["server-not-covered"]),
+            ("https://10.0.0.2", Some("api.example.com"), CA, &[]),
+            ("https://api.example.com", None, other_ca, &["ca-mismatch"]),
+            (
+                "https://other.example.com",
+                None,
+                other_ca,
+                &["ca-mismatch", "server-not-covered"],
+            ),
+        ];
+        for (server, tls_server_name, authority, expected) in cases {
+            let cluster = cluster(server, tls_server_name, authority);
+            assert_eq!(rules(&cluster, Some(SERVER)), expected, "{}", server);
+        }
+
+        // The host can not be checked without a serving certificate.
+        assert!(rules(&cluster("https://other.example.com", None, CA), None).is_empty());
+    }
+
+    #[test]
+    fn checks_serving_certificates_pinned_in_the_authority() {
+        let pinned = [CA, SERVER].concat();
+        assert_eq!(
+            rules(&cluster("https://api.example.com", None, &pinned), None),
+            ["ca-not-a-ca"]
+        );
+        assert_eq!(
+            rules(&cluster("https://other.example.com", None, &pinned), None),
+            ["ca-not-a-ca", "server-not-covered"]
+        );
+        assert_eq!(
+            rules(
+                &cluster("https://api.example.com", None, b"not a certificate"),
+                None
+            ),
+            ["ca-unreadable"]
+        );
+    }
+}
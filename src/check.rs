@@ -0,0 +1,502 @@
+use crate::KubeConfError;
+use crate::certs::read_field;
+use crate::credentials::{Authorization, Credential, resolve_user_credential};
+use crate::kubeconfig::{Cluster, KubeConfig, User};
+use base64::Engine;
+use log::{debug, info};
+use rustls::client::WebPkiServerVerifier;
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::WebPkiSupportedAlgorithms;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+use rustls::{ClientConfig, DigitallySignedStruct, RootCertStore, SignatureScheme};
+use serde_json::Value;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// The outcome of checking a single context.
+pub struct CheckResult {
+    pub context: String,
+    pub server: String,
+    /// The `gitVersion` reported by `/version` if the server could be reached.
+    pub version: Option<String>,
+    /// `None` if it could not be determined, e.g. because the server was not reachable.
+    pub authenticated: Option<bool>,
+    /// User name and groups as seen by the server.
+    pub identity: Option<String>,
+    /// What went wrong, if anything.
+    pub error: Option<String>,
+}
+
+/// Accepts any server certificate, used for `insecure-skip-tls-verify`.
+/// Handshake signatures are still checked so the connection is at least consistent.
+#[derive(Debug)]
+struct InsecureVerifier {
+    algorithms: WebPkiSupportedAlgorithms,
+}
+
+impl ServerCertVerifier for InsecureVerifier {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.algorithms.supported_schemes()
+    }
+}
+
+/// Verifies the server certificate against `tls-server-name` instead of the host of the server URL.
+#[derive(Debug)]
+struct ServerNameVerifier {
+    inner: Arc<WebPkiServerVerifier>,
+    server_name: ServerName<'static>,
+}
+
+impl ServerCertVerifier for ServerNameVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        self.inner.verify_server_cert(
+            end_entity,
+            intermediates,
+            &self.server_name,
+            ocsp_response,
+            now,
+        )
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.inner.supported_verify_schemes()
+    }
+}
+
+/// Builds the TLS configuration the same way kubectl would for the given cluster and user.
+/// Without a certificate authority the bundled web PKI roots are trusted.
+fn tls_config(cluster: &Cluster, user: &User, base_dir: &Path) -> Result<ClientConfig, String> {
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let builder = ClientConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()
+        .map_err(|e| e.to_string())?;
+
+    let builder = if cluster.insecure_skip_tls_verify == Some(true) {
+        builder
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(InsecureVerifier {
+                algorithms: provider.signature_verification_algorithms,
+            }))
+    } else {
+        let mut roots = RootCertStore::empty();
+        match read_field(
+            base_dir,
+            &cluster.certificate_authority,
+            &cluster.certificate_authority_data,
+        ) {
+            Some((location, contents)) => {
+                let contents =
+                    contents.map_err(|e| format!("certificate-authority ({}) {}", location, e))?;
+                for certificate in CertificateDer::pem_slice_iter(&contents) {
+                    let certificate = certificate.map_err(|e| {
+                        format!(
+                            "certificate-authority ({}) is not valid PEM: {}",
+                            location, e
+                        )
+                    })?;
+                    roots.add(certificate).map_err(|e| {
+                        format!(
+                            "certificate-authority ({}) can not be used: {}",
+                            location, e
+                        )
+                    })?;
+                }
+            }
+            None => roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned()),
+        }
+        let verifier =
+            WebPkiServerVerifier::builder_with_provider(Arc::new(roots), provider.clone())
+                .build()
+                .map_err(|e| format!("certificate-authority can not be used: {}", e))?;
+
+        match &cluster.tls_server_name {
+            Some(tls_server_name) => {
+                let server_name = ServerName::try_from(tls_server_name.clone())
+                    .map_err(|e| format!("tls-server-name `{}`: {}", tls_server_name, e))?;
+                builder
+                    .dangerous()
+                    .with_custom_certificate_verifier(Arc::new(ServerNameVerifier {
+                        inner: verifier,
+                        server_name,
+                    }))
+            }
+            None => builder.with_webpki_verifier(verifier),
+        }
+    };
+
+    let certificate = read_field(
+        base_dir,
+        &user.client_certificate,
+        &user.client_certificate_data,
+    );
+    let key = read_field(base_dir, &user.client_key, &user.client_key_data);
+    match (certificate, key) {
+        (Some((certificate_location, certificate)), Some((key_location, key))) => {
+            let certificate = certificate
+                .map_err(|e| format!("client-certificate ({}) {}", certificate_location, e))?;
+            let key = key.map_err(|e| format!("client-key ({}) {}", key_location, e))?;
+            let chain = CertificateDer::pem_slice_iter(&certificate)
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(|e| {
+                    format!(
+                        "client-certificate ({}) is not valid PEM: {}",
+                        certificate_location, e
+                    )
+                })?;
+            let key = PrivateKeyDer::from_pem_slice(&key)
+                .map_err(|e| format!("client-key ({}) is not valid PEM: {}", key_location, e))?;
+            builder
+                .with_client_auth_cert(chain, key)
+                .map_err(|e| format!("client certificate can not be used: {}", e))
+        }
+        (None, None) => Ok(builder.with_no_client_auth()),
+        _ => Err("client-certificate and client-key need to be set together".to_string()),
+    }
+}
+
+/// The `Authorization` header for the credential, if any.
+fn authorization_header(credential: &Credential) -> Option<String> {
+    match credential.authorization.as_ref()? {
+        Authorization::Bearer(token) => Some(format!("Bearer {}", token)),
+        Authorization::Basic { username, password } => {
+            let credentials = base64::engine::general_purpose::STANDARD
+                .encode(format!("{}:{}", username, password));
+            Some(format!("Basic {}", credentials))
+        }
+    }
+}
+
+fn http_client(
+    cluster: &Cluster,
+    user: &User,
+    base_dir: &Path,
+    timeout: Duration,
+) -> Result<reqwest::blocking::Client, String> {
+    let mut builder = reqwest::blocking::Client::builder()
+        .use_preconfigured_tls(tls_config(cluster, user, base_dir)?)
+        .timeout(timeout)
+        .user_agent(concat!("kubeconf/", env!("CARGO_PKG_VERSION")));
+    if let Some(proxy_url) = &cluster.proxy_url {
+        let proxy = reqwest::Proxy::all(proxy_url)
+            .map_err(|e| format!("proxy-url `{}`: {}", proxy_url, e))?;
+        builder = builder.proxy(proxy);
+    }
+    builder.build().map_err(|e| e.to_string())
+}
+
+/// Renders the error including its sources, which is where reqwest keeps the interesting part.
+fn error_chain(error: &dyn std::error::Error) -> String {
+    let mut message = error.to_string();
+    let mut source = error.source();
+    while let Some(error) = source {
+        message = format!("{}: {}", message, error);
+        source = error.source();
+    }
+    message
+}
+
+/// Asks the server who we are. Falls back to the beta and alpha APIs for older clusters.
+/// Without any credentials the request is anonymous, which never counts as authenticated.
+fn self_subject_review(
+    client: &reqwest::blocking::Client,
+    server: &str,
+    authorization: Option<&str>,
+    has_credentials: bool,
+) -> Result<(bool, Option<String>), String> {
+    for version in ["v1", "v1beta1", "v1alpha1"] {
+        let url = format!(
+            "{}/apis/authentication.k8s.io/{}/selfsubjectreviews",
+            server, version
+        );
+        let body = serde_json::json!({
+            "apiVersion": format!("authentication.k8s.io/{}", version),
+            "kind": "SelfSubjectReview",
+        });
+        let mut request = client.post(&url).json(&body);
+        if let Some(authorization) = authorization {
+            request = request.header(reqwest::header::AUTHORIZATION, authorization);
+        }
+        let response = request.send().map_err(|e| error_chain(&e))?;
+        let status = response.status();
+        debug!("POST {} returned {}", url, status);
+
+        match status.as_u16() {
+            200 | 201 => {
+                let review: Value = response.json().map_err(|e| error_chain(&e))?;
+                let user_info = &review["status"]["userInfo"];
+                let mut identity = user_info["username"].as_str().unwrap_or("").to_string();
+                if let Some(groups) = user_info["groups"].as_array() {
+                    let groups: Vec<&str> = groups.iter().filter_map(|g| g.as_str()).collect();
+                    if !groups.is_empty() {
+                        identity = format!("{} ({})", identity, groups.join(", "));
+                    }
+                }
+                let anonymous = user_info["username"] == "system:anonymous";
+                return Ok((has_credentials && !anonymous, Some(identity)));
+            }
+            401 => return Ok((false, None)),
+            // Authenticated, but not allowed to ask.
+            403 => return Ok((has_credentials, None)),
+            404 => continue,
+            _ => return Err(format!("{} returned {}", url, status)),
+        }
+    }
+    Err("the server does not support SelfSubjectReview".to_string())
+}
+
+/// Connects to the cluster of the context with its credentials, fetches `/version`
+/// and asks the server for the identity of the user.
+pub fn check_context(
+    kubeconfig: &KubeConfig,
+    context_name: &str,
+    base_dir: &Path,
+    timeout: Duration,
+    exec_cache_dir: Option<&Path>,
+) -> Result<CheckResult, KubeConfError> {
+    let Some(context) = kubeconfig.get_context(context_name) else {
+        return Err(KubeConfError::CheckError(format!(
+            "Context `{}` not found in kubeconfig.",
+            context_name
+        )));
+    };
+    let (Some(cluster), Some(user)) = (
+        kubeconfig.get_cluster(&context.context.cluster),
+        kubeconfig.get_user(&context.context.user),
+    ) else {
+        return Err(KubeConfError::CheckError(format!(
+            "Context `{}` references a non-existent cluster or user.",
+            context_name
+        )));
+    };
+
+    let server = cluster.cluster.server.trim_end_matches('/').to_string();
+    let mut result = CheckResult {
+        context: context_name.to_string(),
+        server: server.clone(),
+        version: None,
+        authenticated: None,
+        identity: None,
+        error: None,
+    };
+
+    let credential = match resolve_user_credential(user, cluster, base_dir, exec_cache_dir, false) {
+        Ok(credential) => credential,
+        Err(error) => {
+            result.error = Some(error.to_string());
+            return Ok(result);
+        }
+    };
+    // A client certificate returned by an exec plugin replaces the one of the user.
+    let mut user = user.clone();
+    if let Some((certificate, key)) = credential
+        .as_ref()
+        .and_then(|c| c.exec_client_certificate.clone())
+    {
+        let engine = base64::engine::general_purpose::STANDARD;
+        user.user.client_certificate = None;
+        user.user.client_certificate_data = Some(engine.encode(certificate));
+        user.user.client_key = None;
+        user.user.client_key_data = Some(engine.encode(key));
+    }
+    let authorization = credential.as_ref().and_then(authorization_header);
+
+    let client = match http_client(&cluster.cluster, &user.user, base_dir, timeout) {
+        Ok(client) => client,
+        Err(error) => {
+            result.error = Some(error);
+            return Ok(result);
+        }
+    };
+
+    info!("Checking context `{}` against {}", context_name, server);
+    let url = format!("{}/version", server);
+    let mut request = client.get(&url);
+    if let Some(authorization) = &authorization {
+        request = request.header(reqwest::header::AUTHORIZATION, authorization);
+    }
+    match request.send() {
+        Ok(response) => {
+            debug!("GET {} returned {}", url, response.status());
+            let version = match response.json::<Value>() {
+                Ok(version) => version["gitVersion"].as_str().map(|v| v.to_string()),
+                Err(_) => None,
+            };
+            result.version = Some(version.unwrap_or("unknown".to_string()));
+        }
+        Err(error) => {
+            result.error = Some(error_chain(&error));
+            return Ok(result);
+        }
+    }
+
+    let has_credentials = authorization.is_some()
+        || user.user.client_certificate.is_some()
+        || user.user.client_certificate_data.is_some();
+    match self_subject_review(&client, &server, authorization.as_deref(), has_credentials) {
+        Ok((authenticated, identity)) => {
+            result.authenticated = Some(authenticated);
+            result.identity = identity;
+        }
+        Err(error) => result.error = Some(error),
+    }
+
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_http::{Request, serve};
+    use std::net::TcpListener;
+
+    fn kubeconfig(server: &str, token: &str) -> KubeConfig {
+        KubeConfig::from_yaml(&format!(
+            "
+apiVersion: v1
+kind: Config
+clusters:
+- name: local
+  cluster:
+    server: {server}
+users:
+- name: me
+  user:
+    token: {token}
+contexts:
+- name: local
+  context:
+    cluster: local
+    user: me
+"
+        ))
+        .unwrap()
+    }
+
+    fn api_server(_: &str, request: &Request) -> (u16, String) {
+        let (status, body) = match (request.line.as_str(), request.authorization.as_deref()) {
+            ("GET /version HTTP/1.1", _) => (200, r#"{"gitVersion": "v1.31.0"}"#),
+            (
+                "POST /apis/authentication.k8s.io/v1/selfsubjectreviews HTTP/1.1",
+                Some("Bearer valid"),
+            ) => (
+                201,
+                r#"{"status": {"userInfo": {"username": "me", "groups": ["system:authenticated"]}}}"#,
+            ),
+            ("POST /apis/authentication.k8s.io/v1/selfsubjectreviews HTTP/1.1", _) => (401, "{}"),
+            _ => (500, "{}"),
+        };
+        (status, body.to_string())
+    }
+
+    fn check(kubeconfig: &KubeConfig) -> CheckResult {
+        check_context(
+            kubeconfig,
+            "local",
+            Path::new("."),
+            Duration::from_secs(5),
+            None,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn reports_version_and_identity_of_valid_credentials() {
+        let server = serve(api_server);
+        let result = check(&kubeconfig(&server, "valid"));
+        assert_eq!(result.error, None);
+        assert_eq!(result.version.as_deref(), Some("v1.31.0"));
+        assert_eq!(result.authenticated, Some(true));
+        assert_eq!(
+            result.identity.as_deref(),
+            Some("me (system:authenticated)")
+        );
+    }
+
+    #[test]
+    fn reports_rejected_credentials() {
+        let server = serve(api_server);
+        let result = check(&kubeconfig(&server, "expired"));
+        assert_eq!(result.error, None);
+        assert_eq!(result.authenticated, Some(false));
+        assert_eq!(result.identity, None);
+    }
+
+    #[test]
+    fn reports_server_errors() {
+        let server = serve(|_, request| match request.line.as_str() {
+            "GET /version HTTP/1.1" => (200, r#"{"gitVersion": "v1.31.0"}"#.to_string()),
+            _ => (500, "{}".to_string()),
+        });
+        let result = check(&kubeconfig(&server, "valid"));
+        assert_eq!(result.authenticated, None);
+        assert!(result.error.unwrap().contains("returned 500"));
+    }
+
+    #[test]
+    fn reports_unreachable_servers() {
+        // Nothing listens on the port once the listener is dropped.
+        let address = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let result = check(&kubeconfig(&format!("http://{}", address), "valid"));
+        assert_eq!(result.version, None);
+        assert!(result.error.is_some());
+    }
+}
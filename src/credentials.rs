@@ -0,0 +1,306 @@
+use crate::KubeConfError;
+use crate::exec::exec_credential;
+use crate::flatten::resolve_path;
+use crate::jwt;
+use crate::kubeconfig::{KubeConfig, NamedCluster, NamedUser};
+use log::warn;
+use std::fs;
+use std::path::Path;
+
+/// What ends up in the `Authorization` header.
+pub enum Authorization {
+    Bearer(String),
+    Basic { username: String, password: String },
+}
+
+/// The credential kubectl would send for a context.
+pub struct Credential {
+    /// Where the credential comes from, e.g. `tokenFile /var/run/token` or `exec plugin kubelogin`.
+    pub source: String,
+    pub authorization: Option<Authorization>,
+    /// PEM encoded client certificate and key returned by an exec plugin.
+    pub exec_client_certificate: Option<(String, String)>,
+    /// Seconds since the unix epoch, from the exec plugin or the `exp` claim of a JWT.
+    pub expiration: Option<i64>,
+}
+
+impl Credential {
+    fn bearer(source: String, token: String) -> Credential {
+        let expiration = jwt::decode_claims(&token)
+            .as_ref()
+            .and_then(jwt::expiration);
+        Credential {
+            source,
+            authorization: Some(Authorization::Bearer(token)),
+            exec_client_certificate: None,
+            expiration,
+        }
+    }
+}
+
+/// Config keys of auth providers that hold a bearer token, in the order they are used.
+/// `oidc` sends the id-token, the removed `gcp` and `azure` providers sent their cached access-token.
+const AUTH_PROVIDER_TOKEN_KEYS: [&str; 2] = ["id-token", "access-token"];
+
+/// Auth providers that were removed from kubectl in favour of exec plugins.
+pub const REMOVED_AUTH_PROVIDERS: [&str; 2] = ["gcp", "azure"];
+
+/// Resolves the credential of the user of `cluster` with the precedence kubectl uses:
+/// `tokenFile`, `token`, `username`/`password`, `exec` and finally `auth-provider`.
+/// Returns `None` if the user has no bearer or basic credential, e.g. for client certificate only users.
+/// Exec plugins are run, using and filling the cache in `exec_cache_dir` unless `refresh` is set.
+pub fn resolve_user_credential(
+    user: &NamedUser,
+    cluster: &NamedCluster,
+    base_dir: &Path,
+    exec_cache_dir: Option<&Path>,
+    refresh: bool,
+) -> Result<Option<Credential>, KubeConfError> {
+    let config = &user.user;
+    let has_token = config.token.is_some() || config.token_file.is_some();
+    let has_basic = config.username.is_some() || config.password.is_some();
+    if has_token && has_basic {
+        return Err(KubeConfError::CredentialError(format!(
+            "User `{}` has both a token and username/password, kubectl refuses to use either.",
+            user.name
+        )));
+    }
+    if config.exec.is_some() && config.auth_provider.is_some() {
+        return Err(KubeConfError::CredentialError(format!(
+            "User `{}` has both exec and auth-provider, kubectl refuses to use either.",
+            user.name
+        )));
+    }
+
+    // The file is re-read by kubectl and wins over the token, which is only used if the file can not be read.
+    if let Some(token_file) = &config.token_file {
+        let resolved = resolve_path(base_dir, token_file);
+        match fs::read_to_string(&resolved) {
+            Ok(token) => {
+                return Ok(Some(Credential::bearer(
+                    format!("tokenFile {}", resolved.display()),
+                    token.trim().to_string(),
+                )));
+            }
+            Err(e) if config.token.is_some() => {
+                warn!(
+                    "Reading tokenFile {} of user `{}` failed, falling back to token: {}",
+                    resolved.display(),
+                    user.name,
+                    e
+                );
+            }
+            Err(e) => {
+                return Err(KubeConfError::CredentialError(format!(
+                    "Reading tokenFile {} of user `{}` failed: {}",
+                    resolved.display(),
+                    user.name,
+                    e
+                )));
+            }
+        }
+    }
+    if let Some(token) = &config.token {
+        return Ok(Some(Credential::bearer("token".to_string(), token.clone())));
+    }
+
+    if has_basic {
+        return Ok(Some(Credential {
+            source: "username/password".to_string(),
+            authorization: Some(Authorization::Basic {
+                username: config.username.clone().unwrap_or_default(),
+                password: config.password.clone().unwrap_or_default(),
+            }),
+            exec_client_certificate: None,
+            expiration: None,
+        }));
+    }
+
+    if let Some(exec) = &config.exec {
+        let status = exec_credential(&user.name, exec, cluster, base_dir, exec_cache_dir, refresh)?;
+        let expiration = status.expiration();
+        let mut credential = match status.token {
+            Some(token) => Credential::bearer(format!("exec plugin {}", exec.command), token),
+            None => Credential {
+                source: format!("exec plugin {}", exec.command),
+                authorization: None,
+                exec_client_certificate: None,
+                expiration: None,
+            },
+        };
+        if let (Some(certificate), Some(key)) =
+            (status.client_certificate_data, status.client_key_data)
+        {
+            credential.exec_client_certificate = Some((certificate, key));
+        }
+        // What the plugin says wins over the claims.
+        if expiration.is_some() {
+            credential.expiration = expiration;
+        }
+        return Ok(Some(credential));
+    }
+
+    if let Some(auth_provider) = &config.auth_provider {
+        if REMOVED_AUTH_PROVIDERS.contains(&auth_provider.name.as_str()) {
+            warn!(
+                "The {} auth-provider of user `{}` was removed from kubectl, it needs an exec plugin instead.",
+                auth_provider.name, user.name
+            );
+        }
+        for key in AUTH_PROVIDER_TOKEN_KEYS {
+            if let Some(token) = auth_provider.config.get(key) {
+                return Ok(Some(Credential::bearer(
+                    format!("auth-provider {} ({})", auth_provider.name, key),
+                    token.clone(),
+                )));
+            }
+        }
+        return Err(KubeConfError::CredentialError(format!(
+            "The {} auth-provider of user `{}` holds no token yet.",
+            auth_provider.name, user.name
+        )));
+    }
+
+    Ok(None)
+}
+
+/// Resolves the credential of the user of the given context, see `resolve_user_credential`.
+pub fn resolve_credential(
+    kubeconfig: &KubeConfig,
+    context_name: &str,
+    base_dir: &Path,
+    exec_cache_dir: Option<&Path>,
+    refresh: bool,
+) -> Result<(NamedUser, Option<Credential>), KubeConfError> {
+    let Some(context) = kubeconfig.get_context(context_name) else {
+        return Err(KubeConfError::CredentialError(format!(
+            "Context `{}` not found in kubeconfig.",
+            context_name
+        )));
+    };
+    let (Some(cluster), Some(user)) = (
+        kubeconfig.get_cluster(&context.context.cluster),
+        kubeconfig.get_user(&context.context.user),
+    ) else {
+        return Err(KubeConfError::CredentialError(format!(
+            "Context `{}` references a non-existent cluster or user.",
+            context_name
+        )));
+    };
+
+    let credential = resolve_user_credential(user, cluster, base_dir, exec_cache_dir, refresh)?;
+    Ok((user.clone(), credential))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::certs::read_field;
+
+    /// The expected outcome of resolving a user: the start of the source and the bearer token, or an error.
+    enum Expected {
+        Bearer(&'static str, &'static str),
+        Basic,
+        Nothing,
+        Error,
+    }
+
+    #[test]
+    fn resolves_credentials_with_kubectl_precedence() {
+        let base_dir =
+            std::env::temp_dir().join(format!("kubeconf-credentials-{}", std::process::id()));
+        fs::create_dir_all(&base_dir).unwrap();
+        fs::write(base_dir.join("token.txt"), "file-token\n").unwrap();
+        let cluster: NamedCluster =
+            serde_yaml::from_str("{name: prod, cluster: {server: https://prod}}").unwrap();
+        let exec = "exec: {apiVersion: client.authentication.k8s.io/v1, command: does-not-exist, interactiveMode: Never}";
+        let oidc = "auth-provider: {name: oidc, config: {id-token: oidc-token}}";
+
+        let cases = [
+            ("{token: static}", Expected::Bearer("token", "static")),
+            (
+                "{tokenFile: token.txt, token: static}",
+                Expected::Bearer("tokenFile", "file-token"),
+            ),
+            (
+                "{tokenFile: missing.txt, token: static}",
+                Expected::Bearer("token", "static"),
+            ),
+            ("{tokenFile: missing.txt}", Expected::Error),
+            (
+                &format!("{{token: static, {}}}", exec),
+                Expected::Bearer("token", "static"),
+            ),
+            (
+                &format!("{{token: static, {}}}", oidc),
+                Expected::Bearer("token", "static"),
+            ),
+            (
+                &format!("{{{}}}", oidc),
+                Expected::Bearer("auth-provider oidc", "oidc-token"),
+            ),
+            ("{username: u, password: p}", Expected::Basic),
+            (
+                "{username: u, password: p, client-certificate-data: Q0VSVAo=}",
+                Expected::Basic,
+            ),
+            ("{token: static, username: u, password: p}", Expected::Error),
+            ("{tokenFile: token.txt, password: p}", Expected::Error),
+            (&format!("{{{}, {}}}", exec, oidc), Expected::Error),
+            ("{client-certificate-data: Q0VSVAo=}", Expected::Nothing),
+        ];
+        for (config, expected) in cases {
+            let user: NamedUser =
+                serde_yaml::from_str(&format!("{{name: admin, user: {}}}", config)).unwrap();
+            let resolved = resolve_user_credential(&user, &cluster, &base_dir, None, false);
+            match (resolved, expected) {
+                (Ok(Some(credential)), Expected::Bearer(source, token)) => {
+                    assert!(
+                        credential.source.starts_with(source),
+                        "{}: {}",
+                        config,
+                        credential.source
+                    );
+                    assert!(
+                        matches!(credential.authorization, Some(Authorization::Bearer(t)) if t == token),
+                        "{}",
+                        config
+                    );
+                }
+                (Ok(Some(credential)), Expected::Basic) => {
+                    assert!(
+                        matches!(credential.authorization, Some(Authorization::Basic { username, password }) if username == "u" && password == "p"),
+                        "{}",
+                        config
+                    );
+                }
+                (Ok(None), Expected::Nothing) | (Err(_), Expected::Error) => {}
+                (resolved, _) => panic!(
+                    "{}: unexpected {:?}",
+                    config,
+                    resolved.map(|c| c.map(|c| c.source))
+                ),
+            }
+        }
+        fs::remove_dir_all(&base_dir).unwrap();
+    }
+
+    #[test]
+    fn embedded_data_wins_over_the_file() {
+        let base_dir = Path::new("/does-not-exist");
+        let (location, contents) = read_field(
+            base_dir,
+            &Some("ca.crt".to_string()),
+            &Some("Q0EK".to_string()),
+        )
+        .unwrap();
+        assert_eq!(location, "embedded");
+        assert_eq!(contents.unwrap(), b"CA\n");
+
+        let (location, contents) =
+            read_field(base_dir, &Some("ca.crt".to_string()), &None).unwrap();
+        assert_eq!(location, "/does-not-exist/ca.crt");
+        assert!(contents.is_err());
+        assert!(read_field(base_dir, &None, &None).is_none());
+    }
+}
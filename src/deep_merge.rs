@@ -0,0 +1,459 @@
+use crate::KubeConfError;
+use serde::Deserialize;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use serde_yaml::{Mapping, Value};
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum MergeStrategy {
+    /// Keep or (with `--force`) replace existing entries as a whole.
+    Replace,
+    /// Merge existing entries field by field according to the field rules.
+    Deep,
+}
+
+/// Which side wins for a field that is set in both main and the other kubeconfig.
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum FieldPrecedence {
+    /// Keep the value of main.
+    Ours,
+    /// Take the value of the other kubeconfig.
+    Theirs,
+    /// Union lists and maps, take the other value for anything else.
+    Union,
+}
+
+/// Per-field precedence rules keyed by the field path inside an entry.
+/// e.g.: `cluster.server`, `cluster.proxy-url`, `user.as-groups`, `context.namespace`
+pub struct FieldRules {
+    pub rules: HashMap<String, FieldPrecedence>,
+    /// Used for fields without a rule.
+    pub default: FieldPrecedence,
+}
+
+/// Field paths a rule can be set for, i.e. the fields of clusters, users and contexts and the mappings in them.
+const FIELD_PATHS: &[&str] = &[
+    "cluster",
+    "cluster.server",
+    "cluster.tls-server-name",
+    "cluster.insecure-skip-tls-verify",
+    "cluster.certificate-authority",
+    "cluster.certificate-authority-data",
+    "cluster.proxy-url",
+    "cluster.disable-compression",
+    "cluster.extensions",
+    "user",
+    "user.client-certificate",
+    "user.client-certificate-data",
+    "user.client-key",
+    "user.client-key-data",
+    "user.token",
+    "user.tokenFile",
+    "user.as",
+    "user.as-uid",
+    "user.as-groups",
+    "user.as-user-extra",
+    "user.username",
+    "user.password",
+    "user.auth-provider",
+    "user.auth-provider.name",
+    "user.auth-provider.config",
+    "user.exec",
+    "user.exec.command",
+    "user.exec.args",
+    "user.exec.env",
+    "user.exec.apiVersion",
+    "user.exec.installHint",
+    "user.exec.provideClusterInfo",
+    "user.exec.interactiveMode",
+    "user.extensions",
+    "context",
+    "context.cluster",
+    "context.user",
+    "context.namespace",
+    "context.extensions",
+    "extensions",
+];
+
+/// Mappings with arbitrary keys, their keys can have rules too.
+/// e.g.: `user.auth-provider.config.client-id`
+const FIELD_PATH_PREFIXES: &[&str] = &["user.auth-provider.config.", "user.as-user-extra."];
+
+fn validate_field_path(path: &str) -> Result<(), String> {
+    if FIELD_PATHS.contains(&path)
+        || FIELD_PATH_PREFIXES
+            .iter()
+            .any(|prefix| path.len() > prefix.len() && path.starts_with(prefix))
+    {
+        Ok(())
+    } else {
+        Err(format!(
+            "unknown field `{}`, expected a field path like cluster.server or user.exec.env",
+            path
+        ))
+    }
+}
+
+/// Parses a `--field-rule` value in the syntax path=precedence.
+/// e.g.: cluster.proxy-url=ours
+pub fn parse_field_rule(rule: &str) -> Result<(String, FieldPrecedence), String> {
+    let Some((path, precedence)) = rule.split_once('=') else {
+        return Err(
+            "field rules need to be in the syntax field.path=ours|theirs|union".to_string(),
+        );
+    };
+    match serde_yaml::from_str::<FieldPrecedence>(precedence) {
+        Ok(precedence) => {
+            validate_field_path(path)?;
+            Ok((path.to_string(), precedence))
+        }
+        Err(_) => Err(format!(
+            "unknown precedence `{}`, expected one of ours, theirs, union",
+            precedence
+        )),
+    }
+}
+
+/// Loads field rules from a yaml file mapping field paths to precedences.
+/// e.g.:
+/// ```yaml
+/// cluster.server: theirs
+/// cluster.proxy-url: ours
+/// user.as-groups: union
+/// ```
+pub fn load_field_rules_file<P: AsRef<Path>>(
+    path: P,
+) -> Result<HashMap<String, FieldPrecedence>, KubeConfError> {
+    let path = path.as_ref();
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) => {
+            return Err(KubeConfError::MergeError(format!(
+                "Reading field rules file {} failed: {}",
+                path.display(),
+                e
+            )));
+        }
+    };
+    let rules: HashMap<String, FieldPrecedence> = serde_yaml::from_str(&contents).map_err(|e| {
+        KubeConfError::MergeError(format!(
+            "Parsing field rules file {} failed: {}",
+            path.display(),
+            e
+        ))
+    })?;
+    for field in rules.keys() {
+        validate_field_path(field).map_err(|e| {
+            KubeConfError::MergeError(format!(
+                "Parsing field rules file {} failed: {}",
+                path.display(),
+                e
+            ))
+        })?;
+    }
+    Ok(rules)
+}
+
+/// Fields that only work together, taken as a whole from one side so a merge never mixes them.
+/// e.g. a certificate-authority of main with the certificate-authority-data of the other kubeconfig, or a token with an exec plugin.
+const EXCLUSIVE_FIELDS: &[(&str, &[&str])] = &[
+    (
+        "cluster",
+        &["certificate-authority", "certificate-authority-data"],
+    ),
+    (
+        "user",
+        &[
+            "client-certificate",
+            "client-certificate-data",
+            "client-key",
+            "client-key-data",
+        ],
+    ),
+    (
+        "user",
+        &[
+            "token",
+            "tokenFile",
+            "username",
+            "password",
+            "exec",
+            "auth-provider",
+        ],
+    ),
+];
+
+/// The name of a list item, e.g. of a named extension or an exec env variable.
+fn item_name(value: &Value) -> Option<&Value> {
+    value.as_mapping()?.get("name")
+}
+
+fn union_values(ours: &Value, theirs: &Value) -> Value {
+    match (ours, theirs) {
+        (Value::Sequence(ours), Value::Sequence(theirs)) => {
+            let mut merged = ours.clone();
+            for value in theirs {
+                // Named items, e.g. extensions, are unique by name and the other value replaces ours.
+                let same_name = item_name(value)
+                    .and_then(|name| merged.iter().position(|m| item_name(m) == Some(name)));
+                match same_name {
+                    Some(index) => merged[index] = value.clone(),
+                    None if !merged.contains(value) => merged.push(value.clone()),
+                    None => {}
+                }
+            }
+            Value::Sequence(merged)
+        }
+        (Value::Mapping(ours), Value::Mapping(theirs)) => {
+            let mut merged = ours.clone();
+            for (key, value) in theirs {
+                let value = match ours.get(key) {
+                    Some(existing) => union_values(existing, value),
+                    None => value.clone(),
+                };
+                merged.insert(key.clone(), value);
+            }
+            Value::Mapping(merged)
+        }
+        _ => theirs.clone(),
+    }
+}
+
+/// Makes both sides agree on the fields of each exclusive group at `path`, see `EXCLUSIVE_FIELDS`.
+/// If both sides set different fields of a group, the side winning by the rule of the first of them (or the default)
+/// gets to keep its fields and the losing side takes them over. Union counts as theirs, like for any other value.
+fn align_exclusive_fields(
+    path: &str,
+    ours: &mut Mapping,
+    theirs: &mut Mapping,
+    rules: &FieldRules,
+) {
+    for (_, fields) in EXCLUSIVE_FIELDS.iter().filter(|(p, _)| *p == path) {
+        let set = |map: &Mapping| -> Vec<&str> {
+            fields
+                .iter()
+                .copied()
+                .filter(|field| map.contains_key(*field))
+                .collect()
+        };
+        let (ours_set, theirs_set) = (set(ours), set(theirs));
+        if ours_set.is_empty() || theirs_set.is_empty() || ours_set == theirs_set {
+            continue;
+        }
+
+        let precedence = fields
+            .iter()
+            .filter(|field| ours_set.contains(field) || theirs_set.contains(field))
+            .find_map(|field| rules.rules.get(&format!("{}.{}", path, field)))
+            .unwrap_or(&rules.default);
+        let (winner, loser) = match precedence {
+            FieldPrecedence::Ours => (&*ours, &mut *theirs),
+            FieldPrecedence::Theirs | FieldPrecedence::Union => (&*theirs, &mut *ours),
+        };
+        for field in fields.iter().copied() {
+            match winner.get(field) {
+                Some(value) => loser.insert(field.into(), value.clone()),
+                None => loser.remove(field),
+            };
+        }
+    }
+}
+
+fn merge_values(path: &str, ours: &Value, theirs: &Value, rules: &FieldRules) -> Value {
+    if ours == theirs {
+        return ours.clone();
+    }
+
+    match rules.rules.get(path) {
+        Some(FieldPrecedence::Ours) => ours.clone(),
+        Some(FieldPrecedence::Theirs) => theirs.clone(),
+        Some(FieldPrecedence::Union) => union_values(ours, theirs),
+        None => match (ours, theirs) {
+            (Value::Mapping(ours_map), Value::Mapping(theirs_map)) => {
+                let (mut ours_map, mut theirs_map) = (ours_map.clone(), theirs_map.clone());
+                align_exclusive_fields(path, &mut ours_map, &mut theirs_map, rules);
+                let (ours_map, theirs_map) = (&ours_map, &theirs_map);
+                let mut merged = Mapping::new();
+                for (key, value) in ours_map {
+                    let key_path = match key.as_str() {
+                        Some(k) if path.is_empty() => k.to_string(),
+                        Some(k) => format!("{}.{}", path, k),
+                        None => format!("{}.{:?}", path, key),
+                    };
+                    let value = match theirs_map.get(key) {
+                        Some(their_value) => merge_values(&key_path, value, their_value, rules),
+                        None => value.clone(),
+                    };
+                    merged.insert(key.clone(), value);
+                }
+                for (key, value) in theirs_map {
+                    if !ours_map.contains_key(key) {
+                        merged.insert(key.clone(), value.clone());
+                    }
+                }
+                Value::Mapping(merged)
+            }
+            _ => match rules.default {
+                FieldPrecedence::Ours => ours.clone(),
+                FieldPrecedence::Theirs => theirs.clone(),
+                FieldPrecedence::Union => union_values(ours, theirs),
+            },
+        },
+    }
+}
+
+/// Merges two entries with the same name field by field.
+/// Fields only set on one side are kept, fields set on both sides follow the rules.
+/// Fields that only work together, like the certificate authority path and data, are never mixed, see `EXCLUSIVE_FIELDS`.
+pub fn merge_entry<T: Serialize + DeserializeOwned>(
+    ours: &T,
+    theirs: &T,
+    rules: &FieldRules,
+) -> Result<T, KubeConfError> {
+    let ours = serde_yaml::to_value(ours).map_err(|e| KubeConfError::MergeError(e.to_string()))?;
+    let theirs =
+        serde_yaml::to_value(theirs).map_err(|e| KubeConfError::MergeError(e.to_string()))?;
+
+    let merged = merge_values("", &ours, &theirs, rules);
+    serde_yaml::from_value(merged).map_err(|e| KubeConfError::MergeError(e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kubeconfig::NamedCluster;
+
+    fn cluster(yaml: &str) -> NamedCluster {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    fn rules(rules: &[(&str, FieldPrecedence)], default: FieldPrecedence) -> FieldRules {
+        FieldRules {
+            rules: rules.iter().map(|(p, r)| (p.to_string(), *r)).collect(),
+            default,
+        }
+    }
+
+    #[test]
+    fn field_rules_take_precedence_over_the_default() {
+        let ours = cluster("{name: a, cluster: {server: https://ours, proxy-url: http://ours}}");
+        let theirs = cluster(
+            "{name: a, cluster: {server: https://theirs, proxy-url: http://theirs, tls-server-name: t}}",
+        );
+        let rules = rules(
+            &[("cluster.proxy-url", FieldPrecedence::Ours)],
+            FieldPrecedence::Theirs,
+        );
+        let merged = merge_entry(&ours, &theirs, &rules).unwrap();
+        assert_eq!(merged.cluster.server, "https://theirs");
+        assert_eq!(merged.cluster.proxy_url.as_deref(), Some("http://ours"));
+        // Fields only set on one side are always kept.
+        assert_eq!(merged.cluster.tls_server_name.as_deref(), Some("t"));
+    }
+
+    #[test]
+    fn union_rule_merges_lists() {
+        let ours: crate::kubeconfig::NamedUser =
+            serde_yaml::from_str("{name: a, user: {as: me, as-groups: [x, y]}}").unwrap();
+        let theirs: crate::kubeconfig::NamedUser =
+            serde_yaml::from_str("{name: a, user: {as: them, as-groups: [y, z]}}").unwrap();
+        let rules = rules(
+            &[("user.as-groups", FieldPrecedence::Union)],
+            FieldPrecedence::Ours,
+        );
+        let merged = merge_entry(&ours, &theirs, &rules).unwrap();
+        assert_eq!(merged.user.impersonate.as_deref(), Some("me"));
+        assert_eq!(merged.user.impersonate_groups, vec!["x", "y", "z"]);
+    }
+
+    #[test]
+    fn rejects_rules_for_unknown_fields() {
+        assert_eq!(
+            parse_field_rule("cluster.server=ours"),
+            Ok(("cluster.server".to_string(), FieldPrecedence::Ours))
+        );
+        assert!(parse_field_rule("user.auth-provider.config.client-id=theirs").is_ok());
+        assert!(parse_field_rule("cluster.proxy_url=ours").is_err());
+        assert!(parse_field_rule("server=ours").is_err());
+        assert!(parse_field_rule("cluster.server=mine").is_err());
+    }
+
+    #[test]
+    fn takes_exclusive_fields_from_one_side() {
+        let ours =
+            cluster("{name: a, cluster: {server: https://a, certificate-authority: /ours/ca.crt}}");
+        let theirs = cluster(
+            "{name: a, cluster: {server: https://a, certificate-authority-data: dGhlaXJz}}",
+        );
+
+        let merged = merge_entry(&ours, &theirs, &rules(&[], FieldPrecedence::Theirs)).unwrap();
+        assert_eq!(merged.cluster.certificate_authority, None);
+        assert_eq!(
+            merged.cluster.certificate_authority_data.as_deref(),
+            Some("dGhlaXJz")
+        );
+
+        let merged = merge_entry(&ours, &theirs, &rules(&[], FieldPrecedence::Ours)).unwrap();
+        assert_eq!(
+            merged.cluster.certificate_authority.as_deref(),
+            Some("/ours/ca.crt")
+        );
+        assert_eq!(merged.cluster.certificate_authority_data, None);
+
+        // The rule of a field of the group decides for the whole group.
+        let rules = rules(
+            &[("cluster.certificate-authority", FieldPrecedence::Ours)],
+            FieldPrecedence::Theirs,
+        );
+        let merged = merge_entry(&ours, &theirs, &rules).unwrap();
+        assert_eq!(
+            merged.cluster.certificate_authority.as_deref(),
+            Some("/ours/ca.crt")
+        );
+        assert_eq!(merged.cluster.certificate_authority_data, None);
+    }
+
+    #[test]
+    fn never_mixes_authentication_methods() {
+        let ours: crate::kubeconfig::NamedUser =
+            serde_yaml::from_str("{name: a, user: {token: ours, as: me}}").unwrap();
+        let theirs: crate::kubeconfig::NamedUser = serde_yaml::from_str(
+            "{name: a, user: {username: u, password: p, exec: {apiVersion: client.authentication.k8s.io/v1, command: login}}}",
+        )
+        .unwrap();
+        let merged = merge_entry(&ours, &theirs, &rules(&[], FieldPrecedence::Theirs)).unwrap();
+        assert_eq!(merged.user.token, None);
+        assert_eq!(merged.user.username.as_deref(), Some("u"));
+        assert!(merged.user.exec.is_some());
+        // Fields outside of the group are merged as usual.
+        assert_eq!(merged.user.impersonate.as_deref(), Some("me"));
+
+        let merged = merge_entry(&ours, &theirs, &rules(&[], FieldPrecedence::Ours)).unwrap();
+        assert_eq!(merged.user.token.as_deref(), Some("ours"));
+        assert_eq!(merged.user.username, None);
+        assert!(merged.user.exec.is_none());
+    }
+
+    #[test]
+    fn union_of_named_lists_keeps_one_item_per_name() {
+        let ours = cluster(
+            "{name: a, cluster: {server: https://a, extensions: [{name: x, extension: ours}, {name: y, extension: y}]}}",
+        );
+        let theirs = cluster(
+            "{name: a, cluster: {server: https://a, extensions: [{name: x, extension: theirs}, {name: z, extension: z}]}}",
+        );
+        let merged = merge_entry(&ours, &theirs, &rules(&[], FieldPrecedence::Union)).unwrap();
+        let extensions = serde_yaml::to_value(&merged.cluster.extensions).unwrap();
+        assert_eq!(
+            extensions,
+            serde_yaml::from_str::<Value>(
+                "[{name: x, extension: theirs}, {name: y, extension: y}, {name: z, extension: z}]"
+            )
+            .unwrap()
+        );
+    }
+}
@@ -0,0 +1,480 @@
+use crate::KubeConfError;
+use crate::flatten;
+use crate::kubeconfig::KubeConfig;
+use crate::redact;
+use serde::Serialize;
+use serde_yaml::{Mapping, Value};
+use sha2::{Digest, Sha256};
+use std::env::home_dir;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Backups are kept apart from the kubeconfigs, a backup next to a fragment in a `config.d` directory would be synced as one.
+/// e.g.: ~/.kube/kubeconf/backups
+pub fn backup_dir() -> PathBuf {
+    let mut path = home_dir().unwrap_or_default();
+    path.push(".kube");
+    path.push("kubeconf");
+    path.push("backups");
+    path
+}
+
+/// The previous version of a kubeconfig is kept whenever kubeconf overwrites it.
+/// The name is made unique by a hash of the full path, so kubeconfigs with the same file name do not share a backup.
+/// e.g.: ~/.kube/kubeconf/backups/config-5d41402abc4b2a76.bak
+pub fn backup_path(backup_dir: &Path, path: &Path) -> PathBuf {
+    let full_path = fs::canonicalize(path)
+        .or_else(|_| std::path::absolute(path))
+        .unwrap_or_else(|_| path.to_path_buf());
+    let hash: String = Sha256::digest(full_path.as_os_str().as_encoded_bytes())[..8]
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect();
+    let file_name = path
+        .file_name()
+        .map(|n| n.to_string_lossy().to_string())
+        .unwrap_or_default();
+    backup_dir.join(format!("{}-{}.bak", file_name, hash))
+}
+
+/// Keeps `previous`, the contents of `path` before it is overwritten, as its backup.
+/// Like the kubeconfig it holds credentials, so only the owner gets to read it.
+pub fn store_backup(backup_dir: &Path, path: &Path, previous: &[u8]) -> std::io::Result<PathBuf> {
+    fs::create_dir_all(backup_dir)?;
+    let backup = backup_path(backup_dir, path);
+    flatten::write_private_file(&backup, previous)?;
+    Ok(backup)
+}
+
+/// A field that differs between the old and the new entry, `None` on the side it is not set.
+#[derive(Serialize, Debug)]
+pub struct FieldChange {
+    /// e.g.: `cluster.server`, `user.exec.env[AWS_PROFILE].value`
+    pub path: String,
+    pub old: Option<Value>,
+    pub new: Option<Value>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct ChangedEntry {
+    pub name: String,
+    pub fields: Vec<FieldChange>,
+}
+
+/// Names of the entries of one kind, grouped by how they differ.
+#[derive(Serialize, Debug, Default)]
+pub struct EntityDiff {
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+    pub changed: Vec<ChangedEntry>,
+}
+
+impl EntityDiff {
+    fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+}
+
+/// How two kubeconfigs differ, entries are matched by name.
+#[derive(Serialize, Debug, Default)]
+#[serde(rename_all = "kebab-case")]
+pub struct KubeConfigDiff {
+    pub clusters: EntityDiff,
+    pub users: EntityDiff,
+    pub contexts: EntityDiff,
+    pub extensions: EntityDiff,
+    pub preferences: Vec<FieldChange>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub current_context: Option<FieldChange>,
+}
+
+impl KubeConfigDiff {
+    pub fn is_empty(&self) -> bool {
+        self.clusters.is_empty()
+            && self.users.is_empty()
+            && self.contexts.is_empty()
+            && self.extensions.is_empty()
+            && self.preferences.is_empty()
+            && self.current_context.is_none()
+    }
+}
+
+/// A value of one kubeconfig together with the value reported for it, which has its secrets redacted.
+/// Both have the same structure as redacting only replaces strings.
+#[derive(Clone, Copy)]
+struct Side<'a> {
+    value: Option<&'a Value>,
+    shown: Option<&'a Value>,
+}
+
+impl<'a> Side<'a> {
+    fn field(&self, key: &Value) -> Side<'a> {
+        Side {
+            value: self
+                .value
+                .and_then(Value::as_mapping)
+                .and_then(|m| m.get(key)),
+            shown: self
+                .shown
+                .and_then(Value::as_mapping)
+                .and_then(|m| m.get(key)),
+        }
+    }
+
+    fn key(&self, key: &str) -> Side<'a> {
+        self.field(&Value::String(key.to_string()))
+    }
+
+    /// Unset values are replaced by `empty`.
+    fn or_empty(&self, empty: &'a Value) -> Side<'a> {
+        Side {
+            value: self.value.or(Some(empty)),
+            shown: self.shown.or(Some(empty)),
+        }
+    }
+
+    fn item(&self, name: &str) -> Side<'a> {
+        Side {
+            value: named_items(self.value).and_then(|items| find_item(&items, name)),
+            shown: named_items(self.shown).and_then(|items| find_item(&items, name)),
+        }
+    }
+}
+
+/// The items of a list of named entries like `clusters` or `env`, in their order.
+/// `None` if the value is not such a list, it is then compared as a whole.
+fn named_items(value: Option<&Value>) -> Option<Vec<(&str, &Value)>> {
+    value?
+        .as_sequence()?
+        .iter()
+        .map(|item| Some((item.get("name")?.as_str()?, item)))
+        .collect()
+}
+
+fn find_item<'a>(items: &[(&str, &'a Value)], name: &str) -> Option<&'a Value> {
+    items
+        .iter()
+        .find(|(n, _)| *n == name)
+        .map(|(_, item)| *item)
+}
+
+/// Names of both sides, the old ones first.
+fn union_names<'a>(old: &[(&'a str, &Value)], new: &[(&'a str, &Value)]) -> Vec<&'a str> {
+    let mut names: Vec<&str> = old.iter().map(|(name, _)| *name).collect();
+    for (name, _) in new {
+        if !names.contains(name) {
+            names.push(name);
+        }
+    }
+    names
+}
+
+/// Compares a single value. Mappings are compared key by key and lists of named entries name by name,
+/// so neither their order nor their formatting matters. Everything else is compared as a whole.
+fn diff_values(path: &str, old: Side, new: Side, changes: &mut Vec<FieldChange>) {
+    if old.value == new.value {
+        return;
+    }
+
+    if let (Some(Value::Mapping(old_map)), Some(Value::Mapping(new_map))) = (old.value, new.value) {
+        let mut keys: Vec<&Value> = old_map.keys().collect();
+        for key in new_map.keys() {
+            if !keys.contains(&key) {
+                keys.push(key);
+            }
+        }
+        for key in keys {
+            let key_path = match key.as_str() {
+                Some(k) if path.is_empty() => k.to_string(),
+                Some(k) => format!("{}.{}", path, k),
+                None => format!("{}.{:?}", path, key),
+            };
+            diff_values(&key_path, old.field(key), new.field(key), changes);
+        }
+        return;
+    }
+
+    if let (Some(old_items), Some(new_items)) = (named_items(old.value), named_items(new.value)) {
+        for name in union_names(&old_items, &new_items) {
+            let item_path = format!("{}[{}]", path, name);
+            diff_values(&item_path, old.item(name), new.item(name), changes);
+        }
+        return;
+    }
+
+    changes.push(FieldChange {
+        path: path.to_string(),
+        old: old.shown.cloned(),
+        new: new.shown.cloned(),
+    });
+}
+
+/// Compares the entries of one kind, e.g. `clusters`, by name.
+fn diff_entries(old: Side, new: Side) -> EntityDiff {
+    let old_items = named_items(old.value).unwrap_or_default();
+    let new_items = named_items(new.value).unwrap_or_default();
+
+    let mut diff = EntityDiff::default();
+    for name in union_names(&old_items, &new_items) {
+        match (find_item(&old_items, name), find_item(&new_items, name)) {
+            (None, _) => diff.added.push(name.to_string()),
+            (_, None) => diff.removed.push(name.to_string()),
+            (Some(_), Some(_)) => {
+                let mut fields: Vec<FieldChange> = vec![];
+                diff_values("", old.item(name), new.item(name), &mut fields);
+                if !fields.is_empty() {
+                    diff.changed.push(ChangedEntry {
+                        name: name.to_string(),
+                        fields,
+                    });
+                }
+            }
+        }
+    }
+    diff
+}
+
+fn to_value(kubeconfig: &KubeConfig) -> Result<Value, KubeConfError> {
+    serde_yaml::to_value(kubeconfig).map_err(|e| KubeConfError::DiffError(e.to_string()))
+}
+
+/// Compares two kubeconfigs entry by entry and field by field.
+/// Secrets that differ are reported redacted unless `show_secrets` is set.
+pub fn diff_kubeconfigs(
+    old: &KubeConfig,
+    new: &KubeConfig,
+    show_secrets: bool,
+) -> Result<KubeConfigDiff, KubeConfError> {
+    let (old_value, new_value) = (to_value(old)?, to_value(new)?);
+    let (old_shown, new_shown) = if show_secrets {
+        (old_value.clone(), new_value.clone())
+    } else {
+        let (mut old, mut new) = (old.clone(), new.clone());
+        redact::redact_kubeconfig(&mut old);
+        redact::redact_kubeconfig(&mut new);
+        (to_value(&old)?, to_value(&new)?)
+    };
+    let old = Side {
+        value: Some(&old_value),
+        shown: Some(&old_shown),
+    };
+    let new = Side {
+        value: Some(&new_value),
+        shown: Some(&new_shown),
+    };
+    // Unset preferences are the same as empty ones, so their fields are reported one by one.
+    let empty = Value::Mapping(Mapping::new());
+    let mut preferences: Vec<FieldChange> = vec![];
+    diff_values(
+        "",
+        old.key("preferences").or_empty(&empty),
+        new.key("preferences").or_empty(&empty),
+        &mut preferences,
+    );
+    let mut current_context: Vec<FieldChange> = vec![];
+    diff_values(
+        "current-context",
+        old.key("current-context"),
+        new.key("current-context"),
+        &mut current_context,
+    );
+
+    Ok(KubeConfigDiff {
+        clusters: diff_entries(old.key("clusters"), new.key("clusters")),
+        users: diff_entries(old.key("users"), new.key("users")),
+        contexts: diff_entries(old.key("contexts"), new.key("contexts")),
+        extensions: diff_entries(old.key("extensions"), new.key("extensions")),
+        preferences,
+        current_context: current_context.pop(),
+    })
+}
+
+/// A field value on a single line, e.g. for a table cell.
+pub fn format_value(value: Option<&Value>) -> String {
+    match value {
+        None | Some(Value::Null) => "-".to_string(),
+        Some(Value::String(value)) => value.clone(),
+        Some(Value::Bool(value)) => value.to_string(),
+        Some(Value::Number(value)) => value.to_string(),
+        Some(other) => serde_json::to_string(other).unwrap_or_else(|_| format!("{:?}", other)),
+    }
+}
+
+/// A line of a line based diff.
+pub enum DiffLine<'a> {
+    Unchanged(&'a str),
+    Removed(&'a str),
+    Added(&'a str),
+}
+
+/// Diffs two texts line by line using their longest common subsequence.
+pub fn diff_lines<'a>(old: &'a str, new: &'a str) -> Vec<DiffLine<'a>> {
+    let old: Vec<&str> = old.lines().collect();
+    let new: Vec<&str> = new.lines().collect();
+
+    // lengths[i][j] is the length of the longest common subsequence of old[i..] and new[j..].
+    let mut lengths = vec![vec![0usize; new.len() + 1]; old.len() + 1];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            lengths[i][j] = if old[i] == new[j] {
+                lengths[i + 1][j + 1] + 1
+            } else {
+                lengths[i + 1][j].max(lengths[i][j + 1])
+            };
+        }
+    }
+
+    let mut lines: Vec<DiffLine> = vec![];
+    let (mut i, mut j) = (0, 0);
+    while i < old.len() && j < new.len() {
+        if old[i] == new[j] {
+            lines.push(DiffLine::Unchanged(old[i]));
+            i += 1;
+            j += 1;
+        } else if lengths[i + 1][j] >= lengths[i][j + 1] {
+            lines.push(DiffLine::Removed(old[i]));
+            i += 1;
+        } else {
+            lines.push(DiffLine::Added(new[j]));
+            j += 1;
+        }
+    }
+    lines.extend(old[i..].iter().map(|line| DiffLine::Removed(line)));
+    lines.extend(new[j..].iter().map(|line| DiffLine::Added(line)));
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OLD: &str = "
+apiVersion: v1
+kind: Config
+clusters:
+- name: prod
+  cluster:
+    server: https://prod.example:6443
+- name: old
+  cluster:
+    server: https://old.example:6443
+users:
+- name: admin
+  user:
+    token: old-secret-token
+contexts:
+- name: prod
+  context:
+    cluster: prod
+    user: admin
+";
+
+    const NEW: &str = "
+apiVersion: v1
+kind: Config
+clusters:
+- name: prod
+  cluster:
+    server: https://prod.example:443
+    insecure-skip-tls-verify: true
+- name: new
+  cluster:
+    server: https://new.example:6443
+users:
+- name: admin
+  user:
+    token: new-secret-token
+contexts:
+- name: prod
+  context:
+    cluster: prod
+    user: admin
+";
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("kubeconf-diff-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn reports_added_removed_and_changed_fields() {
+        let old = KubeConfig::from_yaml(OLD).unwrap();
+        let new = KubeConfig::from_yaml(NEW).unwrap();
+        let difference = diff_kubeconfigs(&old, &new, true).unwrap();
+
+        assert_eq!(difference.clusters.added, vec!["new"]);
+        assert_eq!(difference.clusters.removed, vec!["old"]);
+        assert_eq!(difference.clusters.changed.len(), 1);
+        let prod = &difference.clusters.changed[0];
+        assert_eq!(prod.name, "prod");
+        let paths: Vec<&str> = prod.fields.iter().map(|f| f.path.as_str()).collect();
+        assert_eq!(
+            paths,
+            vec!["cluster.server", "cluster.insecure-skip-tls-verify"]
+        );
+        assert_eq!(
+            prod.fields[0].new,
+            Some(Value::String("https://prod.example:443".to_string()))
+        );
+        assert_eq!(prod.fields[1].old, None);
+        assert!(difference.contexts.is_empty());
+        assert!(!difference.is_empty());
+        assert!(diff_kubeconfigs(&old, &old, false).unwrap().is_empty());
+    }
+
+    #[test]
+    fn shows_changed_secrets_redacted() {
+        let old = KubeConfig::from_yaml(OLD).unwrap();
+        let new = KubeConfig::from_yaml(NEW).unwrap();
+
+        let redacted = diff_kubeconfigs(&old, &new, false).unwrap();
+        let token = &redacted.users.changed[0].fields[0];
+        assert_eq!(token.path, "user.token");
+        assert_eq!(format_value(token.old.as_ref()), "old-…REDACTED");
+        assert_eq!(format_value(token.new.as_ref()), "new-…REDACTED");
+
+        let shown = diff_kubeconfigs(&old, &new, true).unwrap();
+        let token = &shown.users.changed[0].fields[0];
+        assert_eq!(format_value(token.new.as_ref()), "new-secret-token");
+    }
+
+    #[test]
+    fn diffs_lines_by_their_longest_common_subsequence() {
+        let lines: Vec<String> = diff_lines("a\nb\nc\nd", "a\nc\nx\nd\ne")
+            .iter()
+            .map(|line| match line {
+                DiffLine::Unchanged(line) => format!("  {}", line),
+                DiffLine::Removed(line) => format!("- {}", line),
+                DiffLine::Added(line) => format!("+ {}", line),
+            })
+            .collect();
+        assert_eq!(lines, vec!["  a", "- b", "  c", "+ x", "  d", "+ e"]);
+        assert!(diff_lines("", "").is_empty());
+    }
+
+    #[test]
+    fn backups_are_not_picked_up_as_fragments() {
+        let fragments = temp_dir("fragments");
+        let backups = temp_dir("backups");
+        let fragment = fragments.join("ca.yaml");
+        fs::write(&fragment, OLD).unwrap();
+        // Left behind by older versions that kept the backup next to the kubeconfig.
+        fs::write(fragments.join("ca.yaml.bak"), OLD).unwrap();
+
+        let backup = store_backup(&backups, &fragment, OLD.as_bytes()).unwrap();
+        assert!(backup.starts_with(&backups));
+        assert_eq!(backup, backup_path(&backups, &fragment));
+        assert_ne!(backup, backup_path(&backups, &backups.join("ca.yaml")));
+
+        assert_eq!(
+            crate::expand_other_paths(vec![fragments.clone()]),
+            vec![fragment]
+        );
+
+        fs::remove_dir_all(fragments).unwrap();
+        fs::remove_dir_all(backups).unwrap();
+    }
+}
@@ -0,0 +1,609 @@
+use crate::KubeConfError;
+use crate::certs::read_field;
+use crate::flatten::{file_name_for, write_private_file};
+use crate::kubeconfig::{ExecConfig, InteractiveMode, NamedCluster};
+use base64::Engine;
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::IsTerminal;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use time::OffsetDateTime;
+use time::format_description::well_known::Rfc3339;
+
+/// Environment variable the ExecCredential request is passed in.
+pub const EXEC_INFO_ENV: &str = "KUBERNETES_EXEC_INFO";
+
+/// Name of the cluster extension passed to the plugin as `spec.cluster.config`.
+const EXEC_EXTENSION_NAME: &str = "client.authentication.k8s.io/exec";
+
+const SUPPORTED_API_VERSIONS: [&str; 2] = [
+    "client.authentication.k8s.io/v1",
+    "client.authentication.k8s.io/v1beta1",
+];
+
+/// The credential returned by an exec plugin.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ExecCredentialStatus {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub token: Option<String>,
+
+    /// PEM encoded, not base64 like the kubeconfig fields.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_certificate_data: Option<String>,
+
+    /// PEM encoded, not base64 like the kubeconfig fields.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_key_data: Option<String>,
+
+    /// RFC 3339 timestamp after which the credential has to be fetched again.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expiration_timestamp: Option<String>,
+}
+
+impl ExecCredentialStatus {
+    /// The expiration as seconds since the unix epoch, `None` if the credential does not expire.
+    pub fn expiration(&self) -> Option<i64> {
+        let timestamp = self.expiration_timestamp.as_ref()?;
+        match OffsetDateTime::parse(timestamp, &Rfc3339) {
+            Ok(expiration) => Some(expiration.unix_timestamp()),
+            Err(e) => {
+                warn!("Ignoring invalid expirationTimestamp {}: {}", timestamp, e);
+                None
+            }
+        }
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.expiration()
+            .is_some_and(|expiration| expiration <= OffsetDateTime::now_utc().unix_timestamp())
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ExecCredential {
+    api_version: String,
+    kind: String,
+    status: Option<ExecCredentialStatus>,
+}
+
+/// A credential stored on disk so the plugin does not have to run again until it expires.
+#[derive(Debug, Serialize, Deserialize)]
+struct CachedCredential {
+    /// The plugin configuration the credential was fetched with. The cache is ignored if it changed.
+    exec: serde_json::Value,
+    server: String,
+    status: ExecCredentialStatus,
+}
+
+/// Credentials are cached next to the main kubeconfig, one file per user and cluster.
+/// e.g.: ~/.kube/kubeconf/cache/exec/admin_prod.json
+pub fn exec_cache_dir(main_config: &Path) -> PathBuf {
+    let mut path = main_config
+        .parent()
+        .map(|p| p.to_path_buf())
+        .unwrap_or_default();
+    path.push("kubeconf");
+    path.push("cache");
+    path.push("exec");
+    path
+}
+
+fn cache_path(cache_dir: &Path, user: &str, cluster: &str) -> PathBuf {
+    cache_dir.join(format!(
+        "{}_{}.json",
+        file_name_for(user),
+        file_name_for(cluster)
+    ))
+}
+
+fn load_cached(
+    path: &Path,
+    exec: &serde_json::Value,
+    server: &str,
+) -> Option<ExecCredentialStatus> {
+    let contents = fs::read_to_string(path).ok()?;
+    let cached: CachedCredential = match serde_json::from_str(&contents) {
+        Ok(cached) => cached,
+        Err(e) => {
+            warn!(
+                "Ignoring invalid credential cache {}: {}",
+                path.display(),
+                e
+            );
+            return None;
+        }
+    };
+    if cached.exec != *exec || cached.server != server {
+        debug!(
+            "Credential cache {} is for another configuration.",
+            path.display()
+        );
+        return None;
+    }
+    if cached.status.is_expired() {
+        debug!("Cached credential {} has expired.", path.display());
+        return None;
+    }
+    Some(cached.status)
+}
+
+fn store_cached(path: &Path, cached: &CachedCredential) -> Result<(), KubeConfError> {
+    if let Some(parent) = path.parent()
+        && let Err(e) = fs::create_dir_all(parent)
+    {
+        return Err(KubeConfError::ExecError(format!(
+            "Creating credential cache directory {} failed: {}",
+            parent.display(),
+            e
+        )));
+    }
+    let contents = serde_json::to_vec_pretty(cached).map_err(|e| {
+        KubeConfError::ExecError(format!("Serializing the credential cache failed: {}", e))
+    })?;
+    write_private_file(path, &contents).map_err(|e| {
+        KubeConfError::ExecError(format!(
+            "Writing credential cache {} failed: {}",
+            path.display(),
+            e
+        ))
+    })
+}
+
+/// The `spec.cluster` of the ExecCredential request, only sent with `provideClusterInfo`.
+fn cluster_info(cluster: &NamedCluster, base_dir: &Path) -> serde_json::Value {
+    let mut info = serde_json::json!({ "server": cluster.cluster.server });
+    if let Some(tls_server_name) = &cluster.cluster.tls_server_name {
+        info["tls-server-name"] = tls_server_name.clone().into();
+    }
+    if let Some(insecure) = cluster.cluster.insecure_skip_tls_verify {
+        info["insecure-skip-tls-verify"] = insecure.into();
+    }
+    if let Some((location, Ok(contents))) = read_field(
+        base_dir,
+        &cluster.cluster.certificate_authority,
+        &cluster.cluster.certificate_authority_data,
+    ) {
+        debug!(
+            "Passing certificate-authority ({}) to the plugin.",
+            location
+        );
+        info["certificate-authority-data"] = base64::engine::general_purpose::STANDARD
+            .encode(contents)
+            .into();
+    }
+    if let Some(proxy_url) = &cluster.cluster.proxy_url {
+        info["proxy-url"] = proxy_url.clone().into();
+    }
+    if let Some(disable_compression) = cluster.cluster.disable_compression {
+        info["disable-compression"] = disable_compression.into();
+    }
+    if let Some(extension) = cluster
+        .cluster
+        .extensions
+        .iter()
+        .find(|e| e.name == EXEC_EXTENSION_NAME)
+        && let Ok(config) = serde_json::to_value(&extension.extension)
+    {
+        info["config"] = config;
+    }
+    info
+}
+
+/// Runs the exec plugin and returns the credential it printed.
+/// Commands containing a path separator are resolved relative to `base_dir`, like kubectl does.
+fn run_plugin(
+    exec: &ExecConfig,
+    cluster: &NamedCluster,
+    base_dir: &Path,
+) -> Result<ExecCredentialStatus, KubeConfError> {
+    let api_version = match exec.api_version.as_deref() {
+        Some(api_version) if SUPPORTED_API_VERSIONS.contains(&api_version) => api_version,
+        Some(api_version) => {
+            return Err(KubeConfError::ExecError(format!(
+                "Exec plugin apiVersion {} is not supported, expected one of {}.",
+                api_version,
+                SUPPORTED_API_VERSIONS.join(", ")
+            )));
+        }
+        None => {
+            return Err(KubeConfError::ExecError(
+                "Exec plugin is missing an apiVersion.".to_string(),
+            ));
+        }
+    };
+
+    // v1 requires the mode to be set explicitly, v1beta1 defaults to IfAvailable.
+    let interactive_mode = match (&exec.interactive_mode, api_version) {
+        (Some(mode), _) => mode.clone(),
+        (None, "client.authentication.k8s.io/v1beta1") => InteractiveMode::IfAvailable,
+        (None, _) => {
+            return Err(KubeConfError::ExecError(
+                "Exec plugin is missing interactiveMode, which is required for client.authentication.k8s.io/v1.".to_string(),
+            ));
+        }
+    };
+    let has_terminal = std::io::stdin().is_terminal();
+    let interactive = match interactive_mode {
+        InteractiveMode::Never => false,
+        InteractiveMode::IfAvailable => has_terminal,
+        InteractiveMode::Always if has_terminal => true,
+        InteractiveMode::Always => {
+            return Err(KubeConfError::ExecError(format!(
+                "Exec plugin {} requires an interactive terminal.",
+                exec.command
+            )));
+        }
+    };
+
+    let mut spec = serde_json::json!({ "interactive": interactive });
+    if exec.provide_cluster_info == Some(true) {
+        spec["cluster"] = cluster_info(cluster, base_dir);
+    }
+    let request = serde_json::json!({
+        "apiVersion": api_version,
+        "kind": "ExecCredential",
+        "spec": spec,
+    });
+
+    let command = if exec.command.contains(std::path::MAIN_SEPARATOR) || exec.command.contains('/')
+    {
+        base_dir.join(&exec.command)
+    } else {
+        PathBuf::from(&exec.command)
+    };
+    let mut process = Command::new(&command);
+    process
+        .args(exec.args.iter().flatten())
+        .env(EXEC_INFO_ENV, request.to_string())
+        .stdin(if interactive {
+            Stdio::inherit()
+        } else {
+            Stdio::null()
+        })
+        .stderr(Stdio::inherit());
+    for env in exec.env.iter().flatten() {
+        process.env(&env.name, &env.value);
+    }
+
+    info!("Running exec plugin {}", command.display());
+    let output = match process.output() {
+        Ok(output) => output,
+        Err(e) => {
+            let hint = match &exec.install_hint {
+                Some(hint) => format!(" {}", hint.trim()),
+                None => String::new(),
+            };
+            return Err(KubeConfError::ExecError(format!(
+                "Running exec plugin {} failed: {}.{}",
+                command.display(),
+                e,
+                hint
+            )));
+        }
+    };
+    if !output.status.success() {
+        return Err(KubeConfError::ExecError(format!(
+            "Exec plugin {} exited with {}.",
+            command.display(),
+            output.status
+        )));
+    }
+
+    let credential: ExecCredential = serde_json::from_slice(&output.stdout).map_err(|e| {
+        KubeConfError::ExecError(format!(
+            "Exec plugin {} returned an invalid ExecCredential: {}",
+            command.display(),
+            e
+        ))
+    })?;
+    if credential.kind != "ExecCredential" || credential.api_version != api_version {
+        return Err(KubeConfError::ExecError(format!(
+            "Exec plugin {} returned {} {}, expected ExecCredential {}.",
+            command.display(),
+            credential.kind,
+            credential.api_version,
+            api_version
+        )));
+    }
+
+    let Some(status) = credential.status else {
+        return Err(KubeConfError::ExecError(format!(
+            "Exec plugin {} returned no credential status.",
+            command.display()
+        )));
+    };
+    let has_certificate =
+        status.client_certificate_data.is_some() && status.client_key_data.is_some();
+    if status.token.is_none() && !has_certificate {
+        return Err(KubeConfError::ExecError(format!(
+            "Exec plugin {} returned neither a token nor a client certificate and key.",
+            command.display()
+        )));
+    }
+    Ok(status)
+}
+
+/// Gets the credential of a user with an exec plugin, talking to the given cluster.
+/// Credentials with an expiration are cached in `cache_dir` until they expire, unless `refresh` is set.
+pub fn exec_credential(
+    user: &str,
+    exec: &ExecConfig,
+    cluster: &NamedCluster,
+    base_dir: &Path,
+    cache_dir: Option<&Path>,
+    refresh: bool,
+) -> Result<ExecCredentialStatus, KubeConfError> {
+    let exec_value = serde_json::to_value(exec)
+        .map_err(|e| KubeConfError::ExecError(format!("Serializing exec config failed: {}", e)))?;
+    let cache_path = cache_dir.map(|dir| cache_path(dir, user, &cluster.name));
+
+    if let Some(cache_path) = &cache_path
+        && !refresh
+        && let Some(status) = load_cached(cache_path, &exec_value, &cluster.cluster.server)
+    {
+        info!("Using cached credential {}", cache_path.display());
+        return Ok(status);
+    }
+
+    let status = run_plugin(exec, cluster, base_dir)?;
+
+    // Without an expiration the credential is only valid for this process, like in kubectl.
+    if let Some(cache_path) = &cache_path
+        && status.expiration().is_some()
+    {
+        store_cached(
+            cache_path,
+            &CachedCredential {
+                exec: exec_value,
+                server: cluster.cluster.server.clone(),
+                status: status.clone(),
+            },
+        )?;
+    }
+    Ok(status)
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::sync::OnceLock;
+
+    /// Records the request and prints a token counting the runs. Per-test state lives in `$STATE_DIR`.
+    const PLUGIN: &str = r#"#!/bin/sh
+printf '%s' "$KUBERNETES_EXEC_INFO" > "$STATE_DIR/request.json"
+echo run >> "$STATE_DIR/runs"
+runs=$(wc -l < "$STATE_DIR/runs" | tr -d ' ')
+expiration=""
+if [ -n "$EXPIRATION" ]; then
+  expiration=", \"expirationTimestamp\": \"$EXPIRATION\""
+fi
+printf '{"apiVersion": "%s", "kind": "ExecCredential", "status": {"token": "token-%s"%s}}' \
+  "$RESPONSE_API_VERSION" "$runs" "$expiration"
+"#;
+
+    /// The plugin is written once, before any test runs it, as executing a file another thread still has open fails.
+    fn plugin_dir() -> &'static Path {
+        static DIR: OnceLock<PathBuf> = OnceLock::new();
+        DIR.get_or_init(|| {
+            use std::os::unix::fs::PermissionsExt;
+            let dir = std::env::temp_dir().join(format!("kubeconf-exec-{}", std::process::id()));
+            fs::create_dir_all(&dir).unwrap();
+            let plugin = dir.join("plugin.sh");
+            fs::write(&plugin, PLUGIN).unwrap();
+            fs::set_permissions(&plugin, fs::Permissions::from_mode(0o700)).unwrap();
+            dir
+        })
+    }
+
+    fn state_dir(name: &str) -> PathBuf {
+        let dir = plugin_dir().join(name);
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn exec(state_dir: &Path, api_version: &str, extra: &str) -> ExecConfig {
+        serde_yaml::from_str(&format!(
+            "
+command: ./plugin.sh
+apiVersion: {api_version}
+env:
+- {{name: STATE_DIR, value: {state_dir}}}
+- {{name: RESPONSE_API_VERSION, value: {api_version}}}
+{extra}
+",
+            state_dir = state_dir.display()
+        ))
+        .unwrap()
+    }
+
+    fn with_expiration(exec: &mut ExecConfig, expiration: &str) {
+        exec.env
+            .as_mut()
+            .unwrap()
+            .push(crate::kubeconfig::ExecEnvVar {
+                name: "EXPIRATION".to_string(),
+                value: expiration.to_string(),
+            });
+    }
+
+    fn cluster() -> NamedCluster {
+        serde_yaml::from_str(
+            "
+name: prod
+cluster:
+  server: https://prod.example:6443
+  tls-server-name: prod.internal
+  extensions:
+  - name: client.authentication.k8s.io/exec
+    extension: {audience: prod}
+",
+        )
+        .unwrap()
+    }
+
+    fn request(state_dir: &Path) -> serde_json::Value {
+        serde_json::from_str(&fs::read_to_string(state_dir.join("request.json")).unwrap()).unwrap()
+    }
+
+    fn runs(state_dir: &Path) -> usize {
+        fs::read_to_string(state_dir.join("runs"))
+            .map(|runs| runs.lines().count())
+            .unwrap_or(0)
+    }
+
+    #[test]
+    fn sends_v1_requests_without_cluster_info() {
+        let state = state_dir("v1");
+        let exec = exec(
+            &state,
+            "client.authentication.k8s.io/v1",
+            "interactiveMode: Never",
+        );
+        let status =
+            exec_credential("admin", &exec, &cluster(), plugin_dir(), None, false).unwrap();
+        assert_eq!(status.token.as_deref(), Some("token-1"));
+
+        let request = request(&state);
+        assert_eq!(request["apiVersion"], "client.authentication.k8s.io/v1");
+        assert_eq!(request["kind"], "ExecCredential");
+        // Never interactive, whether there is a terminal or not.
+        assert_eq!(request["spec"]["interactive"], false);
+        assert!(request["spec"].get("cluster").is_none());
+        fs::remove_dir_all(&state).unwrap();
+    }
+
+    #[test]
+    fn is_interactive_if_available_only_with_a_terminal() {
+        let state = state_dir("if-available");
+        // v1beta1 defaults to IfAvailable.
+        let exec = exec(&state, "client.authentication.k8s.io/v1beta1", "");
+        exec_credential("admin", &exec, &cluster(), plugin_dir(), None, false).unwrap();
+        assert_eq!(
+            request(&state)["spec"]["interactive"],
+            std::io::stdin().is_terminal()
+        );
+        fs::remove_dir_all(&state).unwrap();
+    }
+
+    #[test]
+    fn sends_cluster_info_to_v1beta1_plugins_that_ask_for_it() {
+        let state = state_dir("v1beta1");
+        let exec = exec(
+            &state,
+            "client.authentication.k8s.io/v1beta1",
+            "provideClusterInfo: true\ninteractiveMode: Never",
+        );
+        exec_credential("admin", &exec, &cluster(), plugin_dir(), None, false).unwrap();
+
+        let request = request(&state);
+        assert_eq!(
+            request["apiVersion"],
+            "client.authentication.k8s.io/v1beta1"
+        );
+        assert_eq!(request["spec"]["interactive"], false);
+        let cluster = &request["spec"]["cluster"];
+        assert_eq!(cluster["server"], "https://prod.example:6443");
+        assert_eq!(cluster["tls-server-name"], "prod.internal");
+        assert_eq!(cluster["config"]["audience"], "prod");
+        fs::remove_dir_all(&state).unwrap();
+    }
+
+    #[test]
+    fn rejects_invalid_plugin_configurations_and_responses() {
+        let state = state_dir("invalid");
+        // v1 requires interactiveMode.
+        let exec_v1 = exec(&state, "client.authentication.k8s.io/v1", "");
+        assert!(exec_credential("admin", &exec_v1, &cluster(), plugin_dir(), None, false).is_err());
+        let unsupported = exec(&state, "client.authentication.k8s.io/v1alpha1", "");
+        assert!(
+            exec_credential("admin", &unsupported, &cluster(), plugin_dir(), None, false).is_err()
+        );
+        assert_eq!(runs(&state), 0);
+
+        // A response of another api version than requested.
+        let mut mismatch = exec(
+            &state,
+            "client.authentication.k8s.io/v1",
+            "interactiveMode: Never",
+        );
+        mismatch.env.as_mut().unwrap()[1].value =
+            "client.authentication.k8s.io/v1beta1".to_string();
+        assert!(
+            exec_credential("admin", &mismatch, &cluster(), plugin_dir(), None, false).is_err()
+        );
+        fs::remove_dir_all(&state).unwrap();
+    }
+
+    #[test]
+    fn reuses_cached_credentials_until_they_expire() {
+        let state = state_dir("cache");
+        let cache_dir = state.join("cache");
+        let mut exec = exec(
+            &state,
+            "client.authentication.k8s.io/v1",
+            "interactiveMode: Never",
+        );
+        with_expiration(&mut exec, "2999-01-01T00:00:00Z");
+        let credential = |exec: &ExecConfig, refresh: bool| {
+            exec_credential(
+                "admin",
+                exec,
+                &cluster(),
+                plugin_dir(),
+                Some(&cache_dir),
+                refresh,
+            )
+            .unwrap()
+            .token
+            .unwrap()
+        };
+
+        assert_eq!(credential(&exec, false), "token-1");
+        assert_eq!(credential(&exec, false), "token-1");
+        assert_eq!(runs(&state), 1);
+        let cache = cache_path(&cache_dir, "admin", "prod");
+        assert!(fs::read_to_string(&cache).unwrap().contains("token-1"));
+
+        // The cache is for another exec configuration once it changes.
+        exec.args = Some(vec!["--profile=other".to_string()]);
+        assert_eq!(credential(&exec, false), "token-2");
+        assert_eq!(credential(&exec, true), "token-3");
+        assert_eq!(credential(&exec, false), "token-3");
+
+        // Expired credentials are fetched again.
+        exec.env.as_mut().unwrap()[2].value = "2000-01-01T00:00:00Z".to_string();
+        assert_eq!(credential(&exec, false), "token-4");
+        assert_eq!(credential(&exec, false), "token-5");
+        fs::remove_dir_all(&state).unwrap();
+    }
+
+    #[test]
+    fn does_not_cache_credentials_without_expiration() {
+        let state = state_dir("no-expiration");
+        let cache_dir = state.join("cache");
+        let exec = exec(
+            &state,
+            "client.authentication.k8s.io/v1",
+            "interactiveMode: Never",
+        );
+        for expected in ["token-1", "token-2"] {
+            let status = exec_credential(
+                "admin",
+                &exec,
+                &cluster(),
+                plugin_dir(),
+                Some(&cache_dir),
+                false,
+            )
+            .unwrap();
+            assert_eq!(status.token.as_deref(), Some(expected));
+        }
+        assert!(!cache_path(&cache_dir, "admin", "prod").exists());
+        fs::remove_dir_all(&state).unwrap();
+    }
+}
@@ -0,0 +1,292 @@
+use crate::KubeConfError;
+use crate::flatten::file_name_for;
+use crate::kubeconfig::{KubeConfig, NamedCluster, NamedContext, NamedUser, User};
+use crate::provenance::remove_provenance;
+
+/// Builds a self-contained kubeconfig with only the given contexts and the clusters and users they reference.
+/// The first given context becomes the current context. A SOPS encrypted kubeconfig is exported encrypted for the same keys.
+pub fn export_kubeconfig(
+    kubeconfig: &KubeConfig,
+    contexts: &[String],
+    no_credentials: bool,
+) -> Result<KubeConfig, KubeConfError> {
+    let mut exported = KubeConfig {
+        api_version: kubeconfig.api_version.clone(),
+        kind: kubeconfig.kind.clone(),
+        preferences: None,
+        clusters: vec![],
+        users: vec![],
+        contexts: vec![],
+        current_context: contexts.first().cloned(),
+        extensions: vec![],
+        sops: kubeconfig.sops.clone(),
+    };
+
+    for context_name in contexts {
+        if exported.get_context(context_name).is_some() {
+            continue;
+        }
+
+        let Some(context) = kubeconfig.get_context(context_name) else {
+            return Err(KubeConfError::ExportError(format!(
+                "Context `{}` not found in kubeconfig.",
+                context_name
+            )));
+        };
+        let mut context: NamedContext = context.clone();
+        remove_provenance(&mut context);
+
+        if exported.get_cluster(&context.context.cluster).is_none() {
+            let Some(cluster) = kubeconfig.get_cluster(&context.context.cluster) else {
+                return Err(KubeConfError::ExportError(format!(
+                    "Context `{}` references non-existent cluster `{}`.",
+                    context.name, context.context.cluster
+                )));
+            };
+            let mut cluster: NamedCluster = cluster.clone();
+            remove_provenance(&mut cluster);
+            exported.clusters.push(cluster);
+        }
+
+        if exported.get_user(&context.context.user).is_none() {
+            let Some(user) = kubeconfig.get_user(&context.context.user) else {
+                return Err(KubeConfError::ExportError(format!(
+                    "Context `{}` references non-existent user `{}`.",
+                    context.name, context.context.user
+                )));
+            };
+            let mut user: NamedUser = user.clone();
+            if no_credentials {
+                // Keep the user entry so the context stays valid, but drop everything in it.
+                user.user = User::default();
+            }
+            remove_provenance(&mut user);
+            exported.users.push(user);
+        }
+
+        exported.contexts.push(context);
+    }
+
+    if let Err(e) = exported.validate() {
+        return Err(KubeConfError::ExportError(format!(
+            "Exported kubeconfig would be invalid: {}",
+            e
+        )));
+    }
+
+    Ok(exported)
+}
+
+/// Name of the file holding clusters and users shared by several contexts when splitting with `factor_shared`.
+pub const SHARED_FILE_NAME: &str = "_shared.yaml";
+
+/// Splits the kubeconfig into one self-contained kubeconfig per context, named after the context.
+/// The files are SOPS encrypted like the kubeconfig, see `export_kubeconfig`.
+/// With `factor_shared`, clusters and users referenced by more than one context are moved into
+/// an additional `_shared.yaml` kubeconfig instead of being duplicated into every file.
+pub fn split_kubeconfig(
+    kubeconfig: &KubeConfig,
+    factor_shared: bool,
+) -> Result<Vec<(String, KubeConfig)>, KubeConfError> {
+    let mut files: Vec<(String, KubeConfig)> = vec![];
+    for context in &kubeconfig.contexts {
+        let file_name = format!("{}.yaml", file_name_for(&context.name));
+        if file_name == SHARED_FILE_NAME || files.iter().any(|(existing, _)| *existing == file_name)
+        {
+            return Err(KubeConfError::ExportError(format!(
+                "Context `{}` would be written to {} which is already taken.",
+                context.name, file_name
+            )));
+        }
+        let exported = export_kubeconfig(kubeconfig, std::slice::from_ref(&context.name), false)?;
+        files.push((file_name, exported));
+    }
+
+    if !factor_shared {
+        return Ok(files);
+    }
+
+    let shared_cluster_names: Vec<String> = kubeconfig
+        .clusters
+        .iter()
+        .filter(|c| {
+            kubeconfig
+                .contexts
+                .iter()
+                .filter(|context| context.context.cluster == c.name)
+                .count()
+                > 1
+        })
+        .map(|c| c.name.clone())
+        .collect();
+    let shared_user_names: Vec<String> = kubeconfig
+        .users
+        .iter()
+        .filter(|u| {
+            kubeconfig
+                .contexts
+                .iter()
+                .filter(|context| context.context.user == u.name)
+                .count()
+                > 1
+        })
+        .map(|u| u.name.clone())
+        .collect();
+    if shared_cluster_names.is_empty() && shared_user_names.is_empty() {
+        return Ok(files);
+    }
+
+    let mut shared = KubeConfig {
+        api_version: kubeconfig.api_version.clone(),
+        kind: kubeconfig.kind.clone(),
+        preferences: None,
+        clusters: vec![],
+        users: vec![],
+        contexts: vec![],
+        current_context: None,
+        extensions: vec![],
+        sops: kubeconfig.sops.clone(),
+    };
+    for (_, file) in &mut files {
+        for cluster in std::mem::take(&mut file.clusters) {
+            if !shared_cluster_names.contains(&cluster.name) {
+                file.clusters.push(cluster);
+            } else if shared.get_cluster(&cluster.name).is_none() {
+                shared.clusters.push(cluster);
+            }
+        }
+        for user in std::mem::take(&mut file.users) {
+            if !shared_user_names.contains(&user.name) {
+                file.users.push(user);
+            } else if shared.get_user(&user.name).is_none() {
+                shared.users.push(user);
+            }
+        }
+    }
+    files.push((SHARED_FILE_NAME.to_string(), shared));
+
+    Ok(files)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::flatten::rebase_paths;
+    use std::path::Path;
+
+    const KUBECONFIG: &str = "
+apiVersion: v1
+kind: Config
+clusters:
+- name: prod
+  cluster:
+    server: https://prod.example:6443
+    certificate-authority: certs/prod-ca.crt
+- name: staging
+  cluster:
+    server: https://staging.example:6443
+    certificate-authority: split/certs/staging-ca.crt
+- name: unused
+  cluster:
+    server: https://unused.example:6443
+users:
+- name: admin
+  user:
+    token: secret-token
+- name: unused
+  user:
+    token: unused
+contexts:
+- name: arn:aws:eks:eu-west-1:123:cluster/prod
+  context:
+    cluster: prod
+    user: admin
+- name: staging
+  context:
+    cluster: staging
+    user: admin
+    namespace: apps
+current-context: staging
+";
+
+    #[test]
+    fn exports_only_referenced_clusters_and_users() {
+        let kubeconfig = KubeConfig::from_yaml(KUBECONFIG).unwrap();
+        let exported = export_kubeconfig(&kubeconfig, &["staging".to_string()], false).unwrap();
+        assert_eq!(exported.current_context.as_deref(), Some("staging"));
+        assert_eq!(exported.contexts.len(), 1);
+        let clusters: Vec<&str> = exported.clusters.iter().map(|c| c.name.as_str()).collect();
+        assert_eq!(clusters, vec!["staging"]);
+        let users: Vec<&str> = exported.users.iter().map(|u| u.name.as_str()).collect();
+        assert_eq!(users, vec!["admin"]);
+        assert_eq!(
+            exported.users[0].user.token.as_deref(),
+            Some("secret-token")
+        );
+
+        let without_credentials =
+            export_kubeconfig(&kubeconfig, &["staging".to_string()], true).unwrap();
+        assert!(without_credentials.users[0].user.token.is_none());
+
+        assert!(export_kubeconfig(&kubeconfig, &["missing".to_string()], false).is_err());
+    }
+
+    #[test]
+    fn rebases_relative_paths_to_the_output_directory() {
+        let kubeconfig = KubeConfig::from_yaml(KUBECONFIG).unwrap();
+        let mut exported = export_kubeconfig(
+            &kubeconfig,
+            &[
+                "arn:aws:eks:eu-west-1:123:cluster/prod".to_string(),
+                "staging".to_string(),
+            ],
+            false,
+        )
+        .unwrap();
+        rebase_paths(
+            &mut exported,
+            Path::new("/home/me/.kube"),
+            Some(Path::new("/home/me/.kube/split")),
+        );
+        // Below the output directory it stays relative, anything else becomes absolute.
+        assert_eq!(
+            exported
+                .get_cluster("staging")
+                .unwrap()
+                .cluster
+                .certificate_authority
+                .as_deref(),
+            Some("certs/staging-ca.crt")
+        );
+        assert_eq!(
+            exported
+                .get_cluster("prod")
+                .unwrap()
+                .cluster
+                .certificate_authority
+                .as_deref(),
+            Some("/home/me/.kube/certs/prod-ca.crt")
+        );
+    }
+
+    #[test]
+    fn splits_into_files_named_after_the_contexts() {
+        let kubeconfig = KubeConfig::from_yaml(KUBECONFIG).unwrap();
+        let files = split_kubeconfig(&kubeconfig, false).unwrap();
+        let names: Vec<&str> = files.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(names.len(), 2);
+        // Sanitized, with a hash so that names differing only in replaced characters do not collide.
+        assert!(names[0].starts_with("arn_aws_eks_eu-west-1_123_cluster_prod-"));
+        assert!(names[0].ends_with(".yaml"));
+        assert_eq!(names[1], "staging.yaml");
+        assert_eq!(files[1].1.current_context.as_deref(), Some("staging"));
+
+        // The admin user is used by both contexts.
+        let files = split_kubeconfig(&kubeconfig, true).unwrap();
+        let (name, shared) = files.last().unwrap();
+        assert_eq!(name, SHARED_FILE_NAME);
+        assert_eq!(shared.users.len(), 1);
+        assert!(files[0].1.users.is_empty());
+        assert_eq!(files[0].1.clusters.len(), 1);
+    }
+}
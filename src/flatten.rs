@@ -0,0 +1,514 @@
+use crate::KubeConfError;
+use crate::kubeconfig::KubeConfig;
+use base64::Engine;
+use log::{info, warn};
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Resolves a path from a kubeconfig. Relative paths are relative to the directory of the kubeconfig file.
+pub fn resolve_path(base_dir: &Path, path: &str) -> PathBuf {
+    base_dir.join(path)
+}
+
+/// Directory of the given kubeconfig file, used to resolve relative paths in it.
+pub fn kubeconfig_dir(kubeconfig_path: &Path) -> PathBuf {
+    match kubeconfig_path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent.to_path_buf(),
+        _ => PathBuf::from("."),
+    }
+}
+
+/// Reads a referenced file and embeds it into the matching data field.
+/// Does nothing if the data field is already set, as it takes precedence over the file anyways.
+fn embed_file(
+    base_dir: &Path,
+    path: &mut Option<String>,
+    data: &mut Option<String>,
+    description: &str,
+    missing: &mut Vec<String>,
+) {
+    let Some(file) = path.take() else {
+        return;
+    };
+    if data.is_some() {
+        info!(
+            "Dropping {} path {} as the data is already embedded.",
+            description, file
+        );
+        return;
+    }
+
+    let resolved = resolve_path(base_dir, &file);
+    match fs::read(&resolved) {
+        Ok(contents) => {
+            *data = Some(base64::engine::general_purpose::STANDARD.encode(contents));
+        }
+        Err(e) => {
+            missing.push(format!("{} {}: {}", description, resolved.display(), e));
+            // Keep the reference so nothing is lost.
+            *path = Some(file);
+        }
+    }
+}
+
+/// Embeds all files referenced by clusters and users of the kubeconfig.
+/// Relative paths are resolved against `base_dir`. Fails listing every file that could not be read.
+pub fn flatten_kubeconfig(
+    kubeconfig: &mut KubeConfig,
+    base_dir: &Path,
+) -> Result<(), KubeConfError> {
+    let mut missing: Vec<String> = vec![];
+
+    for cluster in &mut kubeconfig.clusters {
+        embed_file(
+            base_dir,
+            &mut cluster.cluster.certificate_authority,
+            &mut cluster.cluster.certificate_authority_data,
+            &format!("cluster `{}` certificate-authority", cluster.name),
+            &mut missing,
+        );
+    }
+
+    for user in &mut kubeconfig.users {
+        embed_file(
+            base_dir,
+            &mut user.user.client_certificate,
+            &mut user.user.client_certificate_data,
+            &format!("user `{}` client-certificate", user.name),
+            &mut missing,
+        );
+        embed_file(
+            base_dir,
+            &mut user.user.client_key,
+            &mut user.user.client_key_data,
+            &format!("user `{}` client-key", user.name),
+            &mut missing,
+        );
+
+        // The token is embedded as is, not base64 encoded.
+        if let Some(token_file) = user.user.token_file.take() {
+            let resolved = resolve_path(base_dir, &token_file);
+            match fs::read_to_string(&resolved) {
+                Ok(token) => {
+                    user.user.token = Some(token.trim().to_string());
+                }
+                Err(e) => {
+                    missing.push(format!(
+                        "user `{}` tokenFile {}: {}",
+                        user.name,
+                        resolved.display(),
+                        e
+                    ));
+                    user.user.token_file = Some(token_file);
+                }
+            }
+        }
+    }
+
+    if !missing.is_empty() {
+        return Err(KubeConfError::FlattenError(format!(
+            "Could not read the following referenced files: {}",
+            missing.join(", ")
+        )));
+    }
+
+    Ok(())
+}
+
+/// Turns a cluster or user name into something safe to use in a file name.
+/// Names that had to be changed get a short hash of the original name appended, so e.g. `a/b` and `a_b`
+/// or two EKS cluster ARNs differing only in their separators do not end up in the same file.
+pub fn file_name_for(name: &str) -> String {
+    let file_name: String = name
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' || c == '.' || c == '_' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    if file_name == name {
+        return file_name;
+    }
+
+    let hash: String = Sha256::digest(name.as_bytes())[..4]
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect();
+    format!("{}-{}", file_name, hash)
+}
+
+/// Writes the file readable by the owner only.
+pub fn write_private_file(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    #[cfg(unix)]
+    {
+        use std::io::Write;
+        use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+
+        let mut file = fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(path)?;
+        // The mode only applies to newly created files.
+        file.set_permissions(fs::Permissions::from_mode(0o600))?;
+        file.write_all(contents)
+    }
+    #[cfg(not(unix))]
+    {
+        fs::write(path, contents)
+    }
+}
+
+/// Files referenced by unflatten, and those of them it created.
+#[derive(Default)]
+struct Extracted {
+    files: Vec<PathBuf>,
+    created: Vec<PathBuf>,
+}
+
+/// Decodes a data field into `dir/file_name` and replaces it with a path reference.
+/// Entries that already reference a file are left alone, that reference might be used elsewhere.
+/// An existing file is only reused if it has the same contents, it might belong to another kubeconfig.
+fn extract_data(
+    dir: &Path,
+    file_name: String,
+    path: &mut Option<String>,
+    data: &mut Option<String>,
+    dry_run: bool,
+    extracted: &mut Extracted,
+) -> Result<(), KubeConfError> {
+    if data.is_none() {
+        return Ok(());
+    }
+    if let Some(existing) = path {
+        warn!(
+            "Not extracting {} as the entry already references {}.",
+            file_name, existing
+        );
+        return Ok(());
+    }
+    let Some(encoded) = data.take() else {
+        return Ok(());
+    };
+
+    let contents = match base64::engine::general_purpose::STANDARD.decode(&encoded) {
+        Ok(contents) => contents,
+        Err(e) => {
+            return Err(KubeConfError::FlattenError(format!(
+                "Data for {} is not valid base64: {}",
+                file_name, e
+            )));
+        }
+    };
+
+    let file = dir.join(file_name);
+    if file.exists() {
+        match fs::read(&file) {
+            Ok(existing) if existing == contents => {
+                info!("Reusing {} as it has the same contents.", file.display());
+            }
+            Ok(_) => {
+                return Err(KubeConfError::FlattenError(format!(
+                    "Refusing to overwrite {} as it has different contents, choose another `--dir`.",
+                    file.display()
+                )));
+            }
+            Err(e) => {
+                return Err(KubeConfError::FlattenError(format!(
+                    "Reading existing {} failed: {}",
+                    file.display(),
+                    e
+                )));
+            }
+        }
+    } else {
+        if !dry_run && let Err(e) = write_private_file(&file, &contents) {
+            return Err(KubeConfError::FlattenError(format!(
+                "Writing {} failed: {}",
+                file.display(),
+                e
+            )));
+        }
+        info!("Extracted {}", file.display());
+        extracted.created.push(file.clone());
+    }
+    *path = Some(file.display().to_string());
+    extracted.files.push(file);
+
+    Ok(())
+}
+
+/// Moves all embedded certificates and keys into files in `dir` and references them by absolute path.
+/// Files are named after the cluster or user, e.g. `cluster-prod-ca.crt`, `user-admin.crt` and `user-admin.key`.
+/// Existing files are reused if they have the same contents, otherwise unflatten fails.
+/// Returns the referenced files. With `dry_run` nothing is written to disk.
+/// If extracting fails, the files created so far are removed again.
+pub fn unflatten_kubeconfig(
+    kubeconfig: &mut KubeConfig,
+    dir: &Path,
+    dry_run: bool,
+) -> Result<Vec<PathBuf>, KubeConfError> {
+    let mut extracted = Extracted::default();
+    match extract_all(kubeconfig, dir, dry_run, &mut extracted) {
+        Ok(()) => Ok(extracted.files),
+        Err(e) => {
+            if !dry_run {
+                for file in &extracted.created {
+                    if let Err(remove_error) = fs::remove_file(file) {
+                        warn!("Removing {} failed: {}", file.display(), remove_error);
+                    }
+                }
+            }
+            Err(e)
+        }
+    }
+}
+
+fn extract_all(
+    kubeconfig: &mut KubeConfig,
+    dir: &Path,
+    dry_run: bool,
+    extracted: &mut Extracted,
+) -> Result<(), KubeConfError> {
+    if !dry_run {
+        #[cfg(unix)]
+        let result = {
+            use std::os::unix::fs::DirBuilderExt;
+            fs::DirBuilder::new()
+                .recursive(true)
+                .mode(0o700)
+                .create(dir)
+        };
+        #[cfg(not(unix))]
+        let result = fs::create_dir_all(dir);
+        if let Err(e) = result {
+            return Err(KubeConfError::FlattenError(format!(
+                "Creating directory {} failed: {}",
+                dir.display(),
+                e
+            )));
+        }
+    }
+    let dir = match fs::canonicalize(dir) {
+        Ok(dir) => dir,
+        // Only happens on dry runs where the directory was not created.
+        Err(_) => dir.to_path_buf(),
+    };
+
+    for cluster in &mut kubeconfig.clusters {
+        extract_data(
+            &dir,
+            format!("cluster-{}-ca.crt", file_name_for(&cluster.name)),
+            &mut cluster.cluster.certificate_authority,
+            &mut cluster.cluster.certificate_authority_data,
+            dry_run,
+            extracted,
+        )?;
+    }
+
+    for user in &mut kubeconfig.users {
+        extract_data(
+            &dir,
+            format!("user-{}.crt", file_name_for(&user.name)),
+            &mut user.user.client_certificate,
+            &mut user.user.client_certificate_data,
+            dry_run,
+            extracted,
+        )?;
+        extract_data(
+            &dir,
+            format!("user-{}.key", file_name_for(&user.name)),
+            &mut user.user.client_key,
+            &mut user.user.client_key_data,
+            dry_run,
+            extracted,
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Rewrites a single path reference, see `rebase_paths`.
+fn rebase_path(
+    path: &mut Option<String>,
+    source_dir: &Path,
+    destination_dir: Option<&Path>,
+    description: &str,
+) {
+    let Some(current) = path.as_ref() else {
+        return;
+    };
+
+    let resolved = resolve_path(source_dir, current);
+    if !resolved.exists() {
+        warn!(
+            "{} references {} which does not exist.",
+            description,
+            resolved.display()
+        );
+    }
+
+    let rebased = match destination_dir {
+        Some(destination_dir) => match resolved.strip_prefix(destination_dir) {
+            Ok(relative) => relative.to_path_buf(),
+            // Not below the destination, an absolute path is the only thing that works.
+            Err(_) => resolved,
+        },
+        None => resolved,
+    };
+    let rebased = rebased.display().to_string();
+    if *current != rebased {
+        info!("Rewriting {} path {} to {}", description, current, rebased);
+        *path = Some(rebased);
+    }
+}
+
+/// Makes the file references of a kubeconfig read from `source_dir` valid in another location.
+/// Relative paths are resolved against `source_dir`. They are made absolute, or relative to
+/// `destination_dir` if given and the file lies below it. Warns about referenced files that do not exist.
+pub fn rebase_paths(
+    kubeconfig: &mut KubeConfig,
+    source_dir: &Path,
+    destination_dir: Option<&Path>,
+) {
+    let source_dir = fs::canonicalize(source_dir).unwrap_or(source_dir.to_path_buf());
+    let destination_dir = destination_dir.map(|d| fs::canonicalize(d).unwrap_or(d.to_path_buf()));
+
+    for cluster in &mut kubeconfig.clusters {
+        rebase_path(
+            &mut cluster.cluster.certificate_authority,
+            &source_dir,
+            destination_dir.as_deref(),
+            &format!("Cluster `{}` certificate-authority", cluster.name),
+        );
+    }
+
+    for user in &mut kubeconfig.users {
+        rebase_path(
+            &mut user.user.client_certificate,
+            &source_dir,
+            destination_dir.as_deref(),
+            &format!("User `{}` client-certificate", user.name),
+        );
+        rebase_path(
+            &mut user.user.client_key,
+            &source_dir,
+            destination_dir.as_deref(),
+            &format!("User `{}` client-key", user.name),
+        );
+        rebase_path(
+            &mut user.user.token_file,
+            &source_dir,
+            destination_dir.as_deref(),
+            &format!("User `{}` tokenFile", user.name),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn file_names_of_different_names_differ() {
+        assert_eq!(file_name_for("prod-1.example"), "prod-1.example");
+        assert_ne!(file_name_for("a/b"), file_name_for("a_b"));
+        assert_ne!(
+            file_name_for("arn:aws:eks:eu-west-1:123:cluster/a"),
+            file_name_for("arn:aws:eks:eu-west-1:123:cluster_a")
+        );
+    }
+
+    #[test]
+    fn unflatten_keeps_path_references_and_cleans_up_on_failure() {
+        let dir = std::env::temp_dir().join(format!("kubeconf-unflatten-{}", std::process::id()));
+        let mut kubeconfig: KubeConfig = serde_yaml::from_str(
+            "
+apiVersion: v1
+kind: Config
+clusters:
+- name: a
+  cluster:
+    server: https://a
+    certificate-authority-data: Q0EK
+- name: b
+  cluster:
+    server: https://b
+    certificate-authority: /etc/b.crt
+    certificate-authority-data: Q0EK
+users:
+- name: c
+  user:
+    client-certificate-data: not base64!
+contexts: []
+",
+        )
+        .unwrap();
+        assert!(unflatten_kubeconfig(&mut kubeconfig, &dir, false).is_err());
+        assert_eq!(
+            kubeconfig.clusters[1]
+                .cluster
+                .certificate_authority
+                .as_deref(),
+            Some("/etc/b.crt")
+        );
+        assert!(!dir.join("cluster-a-ca.crt").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn unflatten_never_overwrites_existing_files() {
+        let dir = std::env::temp_dir().join(format!(
+            "kubeconf-unflatten-existing-{}",
+            std::process::id()
+        ));
+        fs::create_dir_all(&dir).unwrap();
+        // e.g. written by unflattening another kubeconfig with a user of the same name.
+        fs::write(dir.join("user-admin.key"), "other key").unwrap();
+        fs::write(dir.join("cluster-a-ca.crt"), "CA\n").unwrap();
+        let yaml = "
+apiVersion: v1
+kind: Config
+clusters:
+- name: a
+  cluster:
+    server: https://a
+    certificate-authority-data: Q0EK
+users:
+- name: admin
+  user:
+    client-certificate-data: Q0VSVAo=
+    client-key-data: S0VZCg==
+contexts: []
+";
+        let mut kubeconfig: KubeConfig = serde_yaml::from_str(yaml).unwrap();
+        assert!(unflatten_kubeconfig(&mut kubeconfig, &dir, false).is_err());
+        // Files that existed before are kept, only the created certificate is removed.
+        assert_eq!(
+            fs::read_to_string(dir.join("user-admin.key")).unwrap(),
+            "other key"
+        );
+        assert_eq!(
+            fs::read_to_string(dir.join("cluster-a-ca.crt")).unwrap(),
+            "CA\n"
+        );
+        assert!(!dir.join("user-admin.crt").exists());
+
+        // A file with the same contents is reused.
+        fs::write(dir.join("user-admin.key"), "KEY\n").unwrap();
+        let mut kubeconfig: KubeConfig = serde_yaml::from_str(yaml).unwrap();
+        let files = unflatten_kubeconfig(&mut kubeconfig, &dir, false).unwrap();
+        assert_eq!(files.len(), 3);
+        assert_eq!(
+            fs::read_to_string(dir.join("user-admin.crt")).unwrap(),
+            "CERT\n"
+        );
+        assert!(kubeconfig.users[0].user.client_key_data.is_none());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,170 @@
+use crate::flatten::resolve_path;
+use crate::kubeconfig::NamedUser;
+use base64::Engine;
+use log::debug;
+use serde_json::Value;
+use std::fs;
+use std::path::Path;
+
+/// Decodes the claims of a JWT without verifying its signature.
+/// Returns `None` if the token is not a JWT.
+pub fn decode_claims(token: &str) -> Option<Value> {
+    let mut parts = token.split('.');
+    let (Some(_header), Some(payload), Some(_signature), None) =
+        (parts.next(), parts.next(), parts.next(), parts.next())
+    else {
+        return None;
+    };
+    let payload = base64::engine::general_purpose::URL_SAFE_NO_PAD
+        .decode(payload.trim_end_matches('='))
+        .ok()?;
+    match serde_json::from_slice(&payload).ok()? {
+        Value::Object(claims) => Some(Value::Object(claims)),
+        _ => None,
+    }
+}
+
+/// The `exp` claim as seconds since the unix epoch.
+pub fn expiration(claims: &Value) -> Option<i64> {
+    claims["exp"].as_i64()
+}
+
+/// Decodes the header of a JWT, e.g. `alg` and `kid`.
+pub fn decode_header(token: &str) -> Option<Value> {
+    let header = token.split('.').next()?;
+    let header = base64::engine::general_purpose::URL_SAFE_NO_PAD
+        .decode(header.trim_end_matches('='))
+        .ok()?;
+    match serde_json::from_slice(&header).ok()? {
+        Value::Object(header) => Some(Value::Object(header)),
+        _ => None,
+    }
+}
+
+/// A token stored in a user entry or a file it references.
+pub struct StoredToken {
+    /// e.g.: token, tokenFile /var/run/token, auth-provider oidc id-token
+    pub source: String,
+    pub token: String,
+    /// Whether kubectl refreshes the token on its own once it expired.
+    pub refreshable: bool,
+}
+
+/// All tokens stored for the user, without running exec plugins. Relative paths are resolved against `base_dir`.
+pub fn stored_tokens(user: &NamedUser, base_dir: &Path) -> Vec<StoredToken> {
+    let mut tokens: Vec<StoredToken> = vec![];
+    if let Some(token) = &user.user.token {
+        tokens.push(StoredToken {
+            source: "token".to_string(),
+            token: token.clone(),
+            refreshable: false,
+        });
+    }
+    if let Some(token_file) = &user.user.token_file {
+        let resolved = resolve_path(base_dir, token_file);
+        match fs::read_to_string(&resolved) {
+            Ok(token) => tokens.push(StoredToken {
+                source: format!("tokenFile {}", resolved.display()),
+                token: token.trim().to_string(),
+                // Whoever writes the file usually keeps it fresh.
+                refreshable: true,
+            }),
+            Err(e) => debug!("Reading tokenFile {} failed: {}", resolved.display(), e),
+        }
+    }
+    if let Some(auth_provider) = &user.user.auth_provider {
+        let has_refresh_token = auth_provider.config.contains_key("refresh-token");
+        for key in ["id-token", "access-token"] {
+            if let Some(token) = auth_provider.config.get(key) {
+                tokens.push(StoredToken {
+                    source: format!("auth-provider {} {}", auth_provider.name, key),
+                    token: token.clone(),
+                    refreshable: has_refresh_token,
+                });
+            }
+        }
+    }
+    tokens
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+
+    /// An unsigned JWT with the given claims.
+    fn token(claims: &str) -> String {
+        format!(
+            "{}.{}.signature",
+            URL_SAFE_NO_PAD.encode(r#"{"alg":"RS256","kid":"key-1"}"#),
+            URL_SAFE_NO_PAD.encode(claims)
+        )
+    }
+
+    #[test]
+    fn decodes_claims_and_expiration() {
+        let valid = token(r#"{"sub":"admin","exp":4891363200}"#);
+        let claims = decode_claims(&valid).unwrap();
+        assert_eq!(claims["sub"], "admin");
+        assert_eq!(expiration(&claims), Some(4891363200));
+        assert_eq!(decode_header(&valid).unwrap()["kid"], "key-1");
+
+        let expired = token(r#"{"exp":1000}"#);
+        assert_eq!(
+            decode_claims(&expired).as_ref().and_then(expiration),
+            Some(1000)
+        );
+
+        // Padded segments are accepted as well.
+        let padded = format!("{}==", token(r#"{"exp":1}"#).trim_end_matches(".signature"));
+        assert_eq!(
+            decode_claims(&format!("{}.signature", padded))
+                .as_ref()
+                .and_then(expiration),
+            Some(1)
+        );
+
+        let no_exp = decode_claims(&token(r#"{"sub":"admin"}"#)).unwrap();
+        assert_eq!(expiration(&no_exp), None);
+        let string_exp = decode_claims(&token(r#"{"exp":"soon"}"#)).unwrap();
+        assert_eq!(expiration(&string_exp), None);
+    }
+
+    #[test]
+    fn rejects_tokens_that_are_not_jwts() {
+        assert!(decode_claims("static-token").is_none());
+        assert!(decode_claims("a.b").is_none());
+        assert!(decode_claims(&format!("{}.extra", token("{}"))).is_none());
+        assert!(decode_claims("header.not*base64.signature").is_none());
+        assert!(decode_claims(&format!("h.{}.s", URL_SAFE_NO_PAD.encode("not json"))).is_none());
+        assert!(decode_claims(&format!("h.{}.s", URL_SAFE_NO_PAD.encode("[1]"))).is_none());
+        assert!(decode_header("not*base64.payload.signature").is_none());
+    }
+
+    #[test]
+    fn collects_stored_tokens() {
+        let base_dir = std::env::temp_dir().join(format!("kubeconf-jwt-{}", std::process::id()));
+        fs::create_dir_all(&base_dir).unwrap();
+        fs::write(base_dir.join("token"), "file-token\n").unwrap();
+        let user: NamedUser = serde_yaml::from_str(
+            "{name: admin, user: {token: static, tokenFile: token, auth-provider: {name: oidc, config: {id-token: id, refresh-token: refresh}}}}",
+        )
+        .unwrap();
+
+        let tokens = stored_tokens(&user, &base_dir);
+        let tokens: Vec<(&str, &str, bool)> = tokens
+            .iter()
+            .map(|t| (t.source.as_str(), t.token.as_str(), t.refreshable))
+            .collect();
+        let token_file = format!("tokenFile {}", base_dir.join("token").display());
+        assert_eq!(
+            tokens,
+            [
+                ("token", "static", false),
+                (token_file.as_str(), "file-token", true),
+                ("auth-provider oidc id-token", "id", true),
+            ]
+        );
+        fs::remove_dir_all(&base_dir).unwrap();
+    }
+}
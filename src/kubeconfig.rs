@@ -4,9 +4,10 @@ use std::collections::HashMap;
 use std::fs;
 use std::path::Path;
 use base64::{self, Engine};
+use crate::sops::{self, SopsMetadata};
 
 /// Spec according to https://kubernetes.io/docs/reference/config-api/kubeconfig.v1/
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "kebab-case", deny_unknown_fields)]
 pub struct KubeConfig {
     #[serde(rename = "apiVersion")]
@@ -30,9 +31,13 @@ pub struct KubeConfig {
 
     #[serde(default, skip_serializing_if = "Vec::is_empty")]
     pub extensions: Vec<NamedExtension>,
+
+    /// Set if the file was encrypted with SOPS, so it can be encrypted again when written.
+    #[serde(skip)]
+    pub sops: Option<SopsMetadata>,
 }
 
-#[derive(Debug, Serialize, Deserialize, Default)]
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
 #[serde(deny_unknown_fields)]
 pub struct Preferences {
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -42,14 +47,14 @@ pub struct Preferences {
     pub extensions: Vec<NamedExtension>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(deny_unknown_fields)]
 pub struct NamedCluster {
     pub name: String,
     pub cluster: Cluster,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "kebab-case", deny_unknown_fields)]
 pub struct Cluster {
     pub server: String,
@@ -76,14 +81,14 @@ pub struct Cluster {
     pub extensions: Vec<NamedExtension>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(deny_unknown_fields)]
 pub struct NamedUser {
     pub name: String,
     pub user: User,
 }
 
-#[derive(Debug, Serialize, Deserialize, Default)]
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
 #[serde(rename_all = "kebab-case",deny_unknown_fields)]
 pub struct User {
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -132,7 +137,7 @@ pub struct User {
     pub extensions: Vec<NamedExtension>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(deny_unknown_fields)]
 pub struct AuthProvider {
     pub name: String,
@@ -141,7 +146,7 @@ pub struct AuthProvider {
     pub config: HashMap<String, String>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase", deny_unknown_fields)]
 pub struct ExecConfig {
     pub command: String,
@@ -165,14 +170,14 @@ pub struct ExecConfig {
     pub interactive_mode: Option<InteractiveMode>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(deny_unknown_fields)]
 pub struct ExecEnvVar {
     pub name: String,
     pub value: String,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(deny_unknown_fields)]
 pub enum InteractiveMode {
     Never,
@@ -180,14 +185,14 @@ pub enum InteractiveMode {
     Always,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(deny_unknown_fields)]
 pub struct NamedContext {
     pub name: String,
     pub context: Context,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(deny_unknown_fields)]
 pub struct Context {
     pub cluster: String,
@@ -200,7 +205,7 @@ pub struct Context {
     pub extensions: Vec<NamedExtension>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(deny_unknown_fields)]
 pub struct NamedExtension {
     pub name: String,
@@ -214,19 +219,53 @@ impl KubeConfig {
     /// Load and parse a kubeconfig from a file path
     pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, KubeConfigError> {
         let contents = fs::read_to_string(path)
-            .map_err(|e| KubeConfigError::IoError(e))?;
+            .map_err(KubeConfigError::IoError)?;
         Self::from_yaml(&contents)
     }
 
     /// Parse a kubeconfig from a YAML string
     pub fn from_yaml(yaml: &str) -> Result<Self, KubeConfigError> {
-        let config: KubeConfig = serde_yaml::from_str(yaml)
-            .map_err(|e| KubeConfigError::ParseError(e))?;
+        let config = Self::parse_yaml(yaml)?;
 
         config.validate()?;
         Ok(config)
     }
 
+    /// Load and parse a kubeconfig fragment from a file path without validating it.
+    /// Fragments may reference clusters and users defined in other fragments, so the
+    /// combined kubeconfig needs to be validated instead.
+    pub fn from_file_unvalidated<P: AsRef<Path>>(path: P) -> Result<Self, KubeConfigError> {
+        let contents = fs::read_to_string(path)
+            .map_err(KubeConfigError::IoError)?;
+        Self::parse_yaml(&contents)
+    }
+
+    /// Parse a kubeconfig, decrypting it first if it was encrypted with SOPS.
+    fn parse_yaml(yaml: &str) -> Result<Self, KubeConfigError> {
+        let mut document: Value = serde_yaml::from_str(yaml)
+            .map_err(KubeConfigError::ParseError)?;
+        if !sops::is_encrypted(&document) {
+            // Parsed again from the string to keep line numbers in errors.
+            return serde_yaml::from_str(yaml)
+                .map_err(KubeConfigError::ParseError);
+        }
+
+        let metadata = sops::decrypt(&mut document)?;
+        let mut config: KubeConfig = serde_yaml::from_value(document)
+            .map_err(KubeConfigError::ParseError)?;
+        config.sops = Some(metadata);
+        Ok(config)
+    }
+
+    /// Serialize the kubeconfig to YAML, encrypted again if it was read from a SOPS encrypted file.
+    pub fn to_yaml(&self) -> Result<String, KubeConfigError> {
+        match &self.sops {
+            Some(metadata) => sops::to_encrypted_yaml(self, metadata),
+            None => serde_yaml::to_string(self)
+                .map_err(KubeConfigError::ParseError),
+        }
+    }
+
     /// Validate the kubeconfig
     pub fn validate(&self) -> Result<(), KubeConfigError> {
         // Check API version
@@ -244,12 +283,12 @@ impl KubeConfig {
         }
 
         // Validate current context exists if specified
-        if let Some(ref current) = self.current_context {
-            if !self.contexts.iter().any(|c| c.name == *current) {
-                return Err(KubeConfigError::ValidationError(
-                    format!("Current context '{}' not found in contexts", current)
-                ));
-            }
+        if let Some(ref current) = self.current_context
+            && !self.contexts.iter().any(|c| c.name == *current)
+        {
+            return Err(KubeConfigError::ValidationError(
+                format!("Current context '{}' not found in contexts", current)
+            ));
         }
 
         // Validate all contexts reference existing clusters and users
@@ -333,10 +372,12 @@ impl KubeConfig {
 }
 
 #[derive(Debug)]
+#[allow(clippy::enum_variant_names)]
 pub enum KubeConfigError {
     IoError(std::io::Error),
     ParseError(serde_yaml::Error),
     ValidationError(String),
+    SopsError(String),
 }
 
 impl std::fmt::Display for KubeConfigError {
@@ -345,6 +386,7 @@ impl std::fmt::Display for KubeConfigError {
             KubeConfigError::IoError(e) => write!(f, "IO error: {}", e),
             KubeConfigError::ParseError(e) => write!(f, "Parse error: {}", e),
             KubeConfigError::ValidationError(msg) => write!(f, "Validation error: {}", msg),
+            KubeConfigError::SopsError(msg) => write!(f, "SOPS error: {}", msg),
         }
     }
 }
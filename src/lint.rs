@@ -0,0 +1,229 @@
+use crate::certs::{check_certificate_authority, format_expiry};
+use crate::credentials::REMOVED_AUTH_PROVIDERS;
+use crate::jwt;
+use crate::kubeconfig::{KubeConfig, NamedUser};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use time::OffsetDateTime;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+/// A single problem found in the kubeconfig.
+pub struct Finding {
+    pub severity: Severity,
+    /// Short identifier of the check, e.g. `server-not-covered`.
+    pub rule: &'static str,
+    /// e.g.: cluster `prod`
+    pub entry: String,
+    pub message: String,
+}
+
+/// Parses a `--server-certificate` value in the syntax cluster=path.
+pub fn parse_server_certificate(value: &str) -> Result<(String, PathBuf), String> {
+    match value.split_once('=') {
+        Some((cluster, path)) if !cluster.is_empty() && !path.is_empty() => {
+            Ok((cluster.to_string(), PathBuf::from(path)))
+        }
+        _ => Err("server certificates need to be in the syntax cluster=path".to_string()),
+    }
+}
+
+/// Runs all offline checks against the kubeconfig. Relative paths are resolved against `base_dir`.
+/// `server_certificates` maps cluster names to the serving certificate of that cluster, if known.
+pub fn lint_kubeconfig(
+    kubeconfig: &KubeConfig,
+    base_dir: &Path,
+    server_certificates: &HashMap<String, Vec<u8>>,
+) -> Vec<Finding> {
+    let mut findings: Vec<Finding> = vec![];
+
+    for cluster in &kubeconfig.clusters {
+        findings.extend(check_certificate_authority(
+            cluster,
+            base_dir,
+            server_certificates.get(&cluster.name).map(|c| c.as_slice()),
+        ));
+    }
+
+    for user in &kubeconfig.users {
+        findings.extend(check_tokens(user, base_dir));
+        findings.extend(check_auth_provider(user));
+    }
+
+    findings
+}
+
+/// Flags expired JWTs stored in the user. Tokens kubectl refreshes on its own are only a warning.
+fn check_tokens(user: &NamedUser, base_dir: &Path) -> Vec<Finding> {
+    let now = OffsetDateTime::now_utc().unix_timestamp();
+    let mut findings: Vec<Finding> = vec![];
+    for stored in jwt::stored_tokens(user, base_dir) {
+        let Some(expiration) = jwt::decode_claims(&stored.token)
+            .as_ref()
+            .and_then(jwt::expiration)
+        else {
+            continue;
+        };
+        if expiration > now {
+            continue;
+        }
+        findings.push(Finding {
+            severity: if stored.refreshable {
+                Severity::Warning
+            } else {
+                Severity::Error
+            },
+            rule: "token-expired",
+            entry: format!("user `{}`", user.name),
+            message: format!(
+                "JWT in {} {}",
+                stored.source,
+                format_expiry(expiration - now)
+            ),
+        });
+    }
+    findings
+}
+
+/// Flags auth providers kubectl removed in 1.26, which fail before any request is sent.
+fn check_auth_provider(user: &NamedUser) -> Option<Finding> {
+    let auth_provider = user.user.auth_provider.as_ref()?;
+    if !REMOVED_AUTH_PROVIDERS.contains(&auth_provider.name.as_str()) {
+        return None;
+    }
+    Some(Finding {
+        severity: Severity::Error,
+        rule: "auth-provider-removed",
+        entry: format!("user `{}`", user.name),
+        message: format!(
+            "The {} auth-provider was removed from kubectl, run `kubeconf migrate auth` to replace it with an exec plugin",
+            auth_provider.name
+        ),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use base64::Engine;
+    use base64::engine::general_purpose::STANDARD;
+
+    const CA: &[u8] = include_bytes!("../tests/fixtures/certs/ca.crt");
+    const SERVER: &[u8] = include_bytes!("../tests/fixtures/certs/server.crt");
+
+    #[test]
+    fn checks_each_cluster_against_its_server_certificate() {
+        let kubeconfig: KubeConfig = serde_yaml::from_str(&format!(
+            "
+apiVersion: v1
+kind: Config
+clusters:
+- name: api
+  cluster:
+    server: https://api.example.com:6443
+    certificate-authority-data: {ca}
+- name: moved
+  cluster:
+    server: https://api.example.org:6443
+    certificate-authority-data: {ca}
+- name: unchecked
+  cluster:
+    server: https://api.example.net:6443
+    certificate-authority-data: {ca}
+",
+            ca = STANDARD.encode(CA)
+        ))
+        .unwrap();
+        let server_certificates = HashMap::from([
+            ("api".to_string(), SERVER.to_vec()),
+            ("moved".to_string(), SERVER.to_vec()),
+        ]);
+
+        let findings = lint_kubeconfig(&kubeconfig, Path::new("."), &server_certificates);
+        assert_eq!(findings.len(), 1);
+        assert_eq!(findings[0].severity, Severity::Error);
+        assert_eq!(findings[0].rule, "server-not-covered");
+        assert_eq!(findings[0].entry, "cluster `moved`");
+        assert!(findings[0].message.contains("`api.example.org`"));
+        assert!(findings[0].message.contains("DNS:api.example.com"));
+    }
+
+    #[test]
+    fn parses_server_certificate_arguments() {
+        assert_eq!(
+            parse_server_certificate("prod=certs/prod.crt").unwrap(),
+            ("prod".to_string(), PathBuf::from("certs/prod.crt"))
+        );
+        assert!(parse_server_certificate("prod").is_err());
+        assert!(parse_server_certificate("=prod.crt").is_err());
+        assert!(parse_server_certificate("prod=").is_err());
+    }
+
+    #[test]
+    fn flags_expired_tokens_and_removed_auth_providers() {
+        let token = |claims: &str| {
+            let encode = |part: &str| base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(part);
+            format!(
+                "{}.{}.signature",
+                encode(r#"{"alg":"RS256"}"#),
+                encode(claims)
+            )
+        };
+        let base_dir = std::env::temp_dir().join(format!("kubeconf-lint-{}", std::process::id()));
+        std::fs::create_dir_all(&base_dir).unwrap();
+        std::fs::write(base_dir.join("token"), token(r#"{"exp":1000}"#)).unwrap();
+        let kubeconfig: KubeConfig = serde_yaml::from_str(&format!(
+            "
+apiVersion: v1
+kind: Config
+users:
+- name: expired
+  user:
+    token: {expired}
+- name: valid
+  user:
+    token: {valid}
+- name: no-exp
+  user:
+    token: {no_exp}
+- name: static
+  user:
+    token: static-token
+- name: file
+  user:
+    tokenFile: token
+- name: gcp
+  user:
+    auth-provider:
+      name: gcp
+      config:
+        access-token: {expired}
+        refresh-token: refresh
+",
+            expired = token(r#"{"exp":1000}"#),
+            valid = token(r#"{"exp":4891363200}"#),
+            no_exp = token(r#"{"sub":"admin"}"#),
+        ))
+        .unwrap();
+
+        let findings = lint_kubeconfig(&kubeconfig, &base_dir, &HashMap::new());
+        let findings: Vec<(&str, &str, Severity)> = findings
+            .iter()
+            .map(|f| (f.entry.as_str(), f.rule, f.severity))
+            .collect();
+        assert_eq!(
+            findings,
+            [
+                ("user `expired`", "token-expired", Severity::Error),
+                ("user `file`", "token-expired", Severity::Warning),
+                ("user `gcp`", "token-expired", Severity::Warning),
+                ("user `gcp`", "auth-provider-removed", Severity::Error),
+            ]
+        );
+        std::fs::remove_dir_all(&base_dir).unwrap();
+    }
+}
@@ -1,22 +1,46 @@
 use clap::{Parser, Subcommand};
 use std::{
+    collections::HashMap,
     env::home_dir,
-    io::{Read, Write, stdin, stdout},
+    io::{IsTerminal, Read, Write, stdin, stdout},
     path::PathBuf,
     process::exit,
     vec,
 };
+mod certs;
+mod check;
+mod credentials;
+mod deep_merge;
+mod diff;
+mod exec;
+mod export;
+mod flatten;
+mod jwt;
 mod kubeconfig;
+mod lint;
+mod migrate;
+mod oidc;
+mod provenance;
+mod redact;
+mod sops;
+mod sync;
+#[cfg(test)]
+mod test_http;
+mod three_way;
+mod vault;
+use crate::deep_merge::{FieldPrecedence, FieldRules, MergeStrategy};
 use crate::kubeconfig::{KubeConfig, NamedCluster, NamedContext, NamedUser, Preferences};
+use crate::three_way::Baseline;
 use colored::Colorize;
-use log::{info, warn};
+use log::{LevelFilter, info, warn};
 use regex::Regex;
+use serde::Serialize;
 use std::fs;
 use tabled::{
     Table, Tabled,
     settings::{
         Color, Padding, Style,
-        object::{Columns, Rows},
+        object::{Cell, Columns, Rows},
     },
 };
 
@@ -24,7 +48,7 @@ fn default_kubeconfig_path() -> std::path::PathBuf {
     let p = home_dir().unwrap();
     let mut p = p.into_os_string();
     p.push("/.kube/config");
-    return p.into();
+    p.into()
 }
 
 #[derive(Parser, Debug)]
@@ -41,22 +65,41 @@ struct Args {
     #[arg(short, long, default_value_os_t = default_kubeconfig_path())]
     config: std::path::PathBuf,
 
+    /// Increase log verbosity. `-v` shows info, `-vv` debug and `-vvv` trace messages.
+    #[arg(short, long, action = clap::ArgAction::Count, global = true)]
+    verbose: u8,
+
+    /// Decrease log verbosity. `-q` only shows errors, `-qq` silences logging completely.
+    #[arg(short, long, action = clap::ArgAction::Count, global = true, conflicts_with = "verbose")]
+    quiet: u8,
+
+    /// Print tokens, passwords, client keys and auth-provider secrets instead of redacting them.
+    /// Needed to get a usable kubeconfig from `export` to stdout or from `--dry-run`.
+    #[arg(long, default_value_t = false, global = true)]
+    show_secrets: bool,
+
     #[command(subcommand)]
     command: Commands,
 }
 
 #[derive(Subcommand, Debug)]
 enum Commands {
-    /// Merge a given kubeconfig with main
+    /// Merge one or more given kubeconfigs with main
     Merge {
-        /// Path to the kubeconfig file to merge into the main.
-        #[arg(short, long)]
-        other: std::path::PathBuf,
+        /// Path to the kubeconfig file to merge into the main. Can be repeated and accepts directories and glob patterns.
+        /// Files found in a directory or glob are merged in sorted order. Use `-` to read from stdin.
+        #[arg(short, long, required = true)]
+        other: Vec<std::path::PathBuf>,
 
         /// Force and override existing values with the given ones.
         #[arg(short, long, default_value_t = false)]
         force: bool,
 
+        /// Import clusters, users and contexts that differ from an existing one with the same name under a new name, e.g. `prod-2`,
+        /// instead of skipping them. Imported contexts are pointed at the renamed clusters and users.
+        #[arg(long, default_value_t = false, conflicts_with = "force")]
+        rename_conflicts: bool,
+
         /// Only print the resulting merged kubeconfig file and do not write it to disk.
         #[arg(long, default_value_t = false)]
         dry_run: bool,
@@ -64,11 +107,247 @@ enum Commands {
         /// Merge preferences from the given other kubeconfig.
         #[arg(long, default_value_t = false)]
         include_preferences: bool,
+
+        /// Set the current context after merging. Without a value the current-context of the (last) other kubeconfig is adopted.
+        /// e.g.: `--use` or `--use imported-context-name`
+        #[arg(long = "use", value_name = "CONTEXT", num_args = 0..=1, default_missing_value = "")]
+        use_context: Option<String>,
+
+        /// Three-way merge against the version of each other file imported last time.
+        /// Upstream changes are applied while local edits (e.g. namespace or renames) are preserved, true conflicts keep the local value and are reported.
+        #[arg(long, default_value_t = false)]
+        three_way: bool,
+
+        /// How to merge clusters, users and contexts that exist in both main and the other kubeconfig.
+        /// `deep` merges them field by field, fields set on both sides keep the main value (or take the other value with `--force`) unless a field rule says otherwise.
+        /// Certificate authority, client certificate and key, and authentication fields (token, tokenFile, username and password, exec, auth-provider) are each taken from one side as a whole.
+        #[arg(long, value_enum, default_value_t = MergeStrategy::Replace)]
+        strategy: MergeStrategy,
+
+        /// Field precedence for `--strategy deep`. Syntax is the field path and one of ours, theirs or union separated by equals sign. Can be repeated.
+        /// e.g.: `--field-rule cluster.proxy-url=ours --field-rule user.as-groups=union`
+        #[arg(long, value_parser = deep_merge::parse_field_rule)]
+        field_rule: Vec<(String, FieldPrecedence)>,
+
+        /// Yaml file mapping field paths to precedences for `--strategy deep`, e.g. `cluster.server: theirs`.
+        #[arg(long)]
+        field_rules_file: Option<std::path::PathBuf>,
+
+        /// Relative certificate, key and token file paths of the other kubeconfig are made absolute while merging.
+        /// With this flag they are made relative to the directory of the main kubeconfig instead, if the file lies below it.
+        #[arg(long, default_value_t = false)]
+        relative_paths: bool,
+
+        /// Format of the merge report. The human report is printed to stderr.
+        /// The json report is printed to stdout, or to stderr with `--dry-run` as the merged kubeconfig occupies stdout.
+        #[arg(long, value_enum, default_value_t = ReportFormat::Human)]
+        report: ReportFormat,
+    },
+
+    /// Sync a directory of kubeconfig fragments into main, treating the directory as the source of truth.
+    /// Entries imported from a fragment are updated or removed with it, hand-added entries are never touched.
+    Sync {
+        /// Path to the directory containing one kubeconfig fragment per file, e.g. ~/.kube/config.d
+        #[arg(long)]
+        from: std::path::PathBuf,
+
+        /// Only print the resulting synced kubeconfig file and do not write it to disk.
+        #[arg(long, default_value_t = false)]
+        dry_run: bool,
+    },
+
+    /// Export the given contexts with their clusters and users into a new self-contained kubeconfig.
+    Export {
+        /// The context names to export. The first one becomes the current context.
+        #[arg(required = true)]
+        contexts: Vec<String>,
+
+        /// Path to write the exported kubeconfig to. Printed to stdout if not given.
+        /// A SOPS encrypted main kubeconfig is written encrypted for the same keys.
+        #[arg(short, long)]
+        output: Option<std::path::PathBuf>,
+
+        /// Strip all credentials from the exported users so only endpoint information is shared.
+        #[arg(long, default_value_t = false)]
+        no_credentials: bool,
+
+        /// Embed all referenced certificate, key and token files into the exported kubeconfig.
+        #[arg(long, default_value_t = false)]
+        flatten: bool,
+    },
+
+    /// Split the kubeconfig into one self-contained kubeconfig per context, e.g. to migrate into a config.d layout.
+    Split {
+        /// Directory to write one `<context>.yaml` file per context to.
+        /// A SOPS encrypted main kubeconfig is split into files encrypted for the same keys.
+        #[arg(long)]
+        out_dir: std::path::PathBuf,
+
+        /// Move clusters and users used by more than one context into a common `_shared.yaml` instead of duplicating them.
+        #[arg(long, default_value_t = false)]
+        factor_shared: bool,
+
+        /// Only print the resulting files and do not write them to disk.
+        #[arg(long, default_value_t = false)]
+        dry_run: bool,
+
+        /// Overwrite files that already exist in the output directory.
+        #[arg(short, long, default_value_t = false)]
+        force: bool,
+    },
+
+    /// Move all embedded certificates and keys into separate files readable by the owner only and reference them by path.
+    Unflatten {
+        /// Directory to write the certificate and key files to, e.g. ~/.kube/certs
+        /// Existing files are never overwritten, they are only reused if they have the same contents.
+        #[arg(long)]
+        dir: std::path::PathBuf,
+
+        /// Only print the resulting kubeconfig file and do not write anything to disk.
+        #[arg(long, default_value_t = false)]
+        dry_run: bool,
+    },
+
+    /// Embed all certificate, key and token files referenced by clusters and users into the kubeconfig.
+    /// Relative paths are resolved against the directory of the kubeconfig.
+    Flatten {
+        /// Only print the resulting flattened kubeconfig file and do not write it to disk.
+        #[arg(long, default_value_t = false)]
+        dry_run: bool,
+    },
+
+    /// Show the certificates of the cluster and user of a context: subject, issuer, SANs, validity, key type
+    /// and whether the client key matches the client certificate.
+    Certs {
+        /// The context to inspect. All contexts are inspected if omitted.
+        context: Option<String>,
+
+        /// Only list certificates that are expired or expire within the given time and exit non-zero if there are any
+        /// or if a certificate could not be read.
+        /// e.g.: 30d, 12h, 2w
+        #[arg(long, value_parser = certs::parse_duration)]
+        expiring_within: Option<std::time::Duration>,
+    },
+
+    /// Check the kubeconfig for problems without contacting any cluster and exit non-zero if errors are found.
+    /// e.g. a certificate-authority that does not hold CA certificates or does not match the server.
+    Lint {
+        /// The serving certificate of a cluster to check against its certificate-authority and server hostname.
+        /// Can be repeated. Syntax is cluster name and path to a PEM file separated by `=`.
+        /// e.g.: prod=/tmp/prod-apiserver.crt
+        #[arg(long, value_parser = lint::parse_server_certificate)]
+        server_certificate: Vec<(String, PathBuf)>,
+    },
+
+    /// Connect to the cluster of each context with its credentials and report whether it is reachable,
+    /// whether the credentials are accepted and who the server thinks you are.
+    /// Exits non-zero if any context is unreachable or not authenticated.
+    Check {
+        /// Only check contexts matching this name or glob pattern. All contexts are checked if omitted.
+        /// e.g.: prod-*
+        selector: Option<String>,
+
+        /// How long to wait for each request.
+        /// e.g.: 5s, 1m
+        #[arg(long, default_value = "5s", value_parser = certs::parse_duration)]
+        timeout: std::time::Duration,
+    },
+
+    /// Print the credential kubectl would send for a context, where it comes from and its JWT claims.
+    /// Uses `tokenFile`, `token`, `username`/`password`, `exec` and `auth-provider` in that order.
+    /// Exec plugin credentials with an expiration are cached until they expire.
+    Token {
+        /// The context whose credential to print.
+        context: String,
+
+        /// Ignore the cached exec plugin credential and run the plugin again.
+        #[arg(long, default_value_t = false)]
+        refresh: bool,
+
+        /// Only print the bare token (or the password of basic auth) without redacting it, for use in scripts.
+        /// e.g.: curl -H "Authorization: Bearer $(kubeconf token my-context --raw)" ...
+        #[arg(long, default_value_t = false)]
+        raw: bool,
+    },
+
+    /// Refresh the tokens of the oidc auth-provider of the user of a context with its refresh token
+    /// and write them back to the kubeconfig.
+    Refresh {
+        /// The context whose user to refresh.
+        context: String,
+
+        /// How long to wait for each request to the identity provider.
+        /// e.g.: 10s, 1m
+        #[arg(long, default_value = "10s", value_parser = certs::parse_duration)]
+        timeout: std::time::Duration,
+
+        /// Only check that the user can be refreshed. The identity provider is not contacted,
+        /// as it may rotate the refresh token and the new one would be lost.
+        #[arg(long, default_value_t = false)]
+        dry_run: bool,
+    },
+
+    /// Migrate deprecated parts of the kubeconfig.
+    Migrate {
+        #[command(subcommand)]
+        command: MigrateCommands,
+    },
+
+    /// Move inline credentials into an encrypted vault and back.
+    Vault {
+        #[command(subcommand)]
+        command: VaultCommands,
+    },
+
+    /// Print a credential sealed in the vault as ExecCredential. Run by kubectl for users sealed with `vault seal`.
+    Credential {
+        /// The name of the credential, which is the name of the user it was sealed from.
+        name: String,
+
+        /// Path to the vault. Defaults to `kubeconf/vault.age` next to the main kubeconfig.
+        #[arg(long)]
+        vault: Option<PathBuf>,
+
+        /// age identity file to decrypt the vault with instead of a passphrase.
+        #[arg(short, long)]
+        identity: Option<PathBuf>,
+    },
+
+    /// Decode the JWTs stored for the user of a context: `token`, `tokenFile` and auth-provider tokens.
+    /// Shows header and claims but never the raw token or its signature unless `--show-secrets` is given.
+    Jwt {
+        /// The context to inspect. All contexts are inspected if omitted.
+        context: Option<String>,
+    },
+
+    /// Compare two kubeconfigs by the names of their entries instead of line by line: added, removed and changed
+    /// clusters, users, contexts and extensions with the fields that differ, and changed preferences.
+    /// Ordering and formatting are ignored. Exits non-zero if the kubeconfigs differ.
+    Diff {
+        /// Path to the old kubeconfig.
+        #[arg(
+            required_unless_present = "against_backup",
+            conflicts_with = "against_backup"
+        )]
+        old: Option<PathBuf>,
+
+        /// Path to the new kubeconfig. Defaults to the main kubeconfig.
+        new: Option<PathBuf>,
+
+        /// Compare the main kubeconfig against its backup, the version before kubeconf last wrote it.
+        /// Backups are kept in `~/.kube/kubeconf/backups` and are readable by the owner only. `vault seal` and `migrate auth` remove it instead,
+        /// as it would keep the secrets they moved out of the kubeconfig.
+        #[arg(long, default_value_t = false)]
+        against_backup: bool,
+
+        /// Format of the differences. The json report includes the full old and new values.
+        #[arg(long, value_enum, default_value_t = ReportFormat::Human)]
+        report: ReportFormat,
     },
 
     /// List all clusters in the kubeconfig.
     List {
-        /// Include the currently selected namespace.
+        /// Include the currently selected namespace and when certificates and tokens expire.
         #[arg(short, long, default_value_t = false)]
         long: bool,
     },
@@ -121,145 +400,665 @@ enum Commands {
     },
 }
 
+#[derive(Subcommand, Debug)]
+enum MigrateCommands {
+    /// Replace the gcp and azure auth-providers removed from kubectl with the equivalent exec plugins.
+    /// gcp uses gke-gcloud-auth-plugin, azure uses kubelogin.
+    Auth {
+        /// Only migrate the given user.
+        #[arg(short, long)]
+        user: Option<String>,
+
+        /// Also replace the oidc auth-provider with the oidc-login kubectl plugin.
+        #[arg(long, default_value_t = false)]
+        include_oidc: bool,
+
+        /// Pass the client-secret of oidc auth-providers to oidc-login as argument.
+        /// Left out by default as arguments are visible to every user of the machine in the process list.
+        #[arg(long, default_value_t = false, requires = "include_oidc")]
+        include_client_secret: bool,
+
+        /// Only print the changes and do not write them to disk.
+        #[arg(long, default_value_t = false)]
+        dry_run: bool,
+
+        /// Skip interactive confirmation.
+        #[arg(short, long, default_value_t = false)]
+        yes: bool,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum VaultCommands {
+    /// Move `token` and `client-key-data` of users into the vault, encrypted with age, and replace them
+    /// with an exec block running `kubeconf credential`. The passphrase can be given in `KUBECONF_VAULT_PASSPHRASE`.
+    /// Passwords of basic auth stay in the kubeconfig in plain text, as exec plugins can not return them.
+    Seal {
+        /// Only seal the given user.
+        #[arg(short, long)]
+        user: Option<String>,
+
+        /// Path to the vault. Defaults to `kubeconf/vault.age` next to the main kubeconfig.
+        #[arg(long)]
+        vault: Option<PathBuf>,
+
+        /// age identity file to encrypt the vault to instead of a passphrase.
+        #[arg(short, long)]
+        identity: Option<PathBuf>,
+
+        /// Only print the resulting kubeconfig file and do not write it or the vault to disk.
+        #[arg(long, default_value_t = false)]
+        dry_run: bool,
+    },
+
+    /// Put the credentials of sealed users back into the kubeconfig and remove them from the vault.
+    Unseal {
+        /// Only unseal the given user.
+        #[arg(short, long)]
+        user: Option<String>,
+
+        /// Path to the vault. Defaults to `kubeconf/vault.age` next to the main kubeconfig.
+        #[arg(long)]
+        vault: Option<PathBuf>,
+
+        /// age identity file to decrypt the vault with instead of a passphrase.
+        #[arg(short, long)]
+        identity: Option<PathBuf>,
+
+        /// Only print the resulting kubeconfig file and do not write it or the vault to disk.
+        #[arg(long, default_value_t = false)]
+        dry_run: bool,
+    },
+}
+
 #[derive(Debug)]
 pub enum KubeConfError {
     MergeError(String),
+    SyncError(String),
+    ExportError(String),
+    FlattenError(String),
+    CertificateError(String),
+    CheckError(String),
+    ExecError(String),
+    CredentialError(String),
+    RefreshError(String),
+    MigrateError(String),
+    VaultError(String),
+    DiffError(String),
+}
+
+/// Only the message, for errors shown to the user as part of regular output.
+impl std::fmt::Display for KubeConfError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            KubeConfError::MergeError(message)
+            | KubeConfError::SyncError(message)
+            | KubeConfError::ExportError(message)
+            | KubeConfError::FlattenError(message)
+            | KubeConfError::CertificateError(message)
+            | KubeConfError::CheckError(message)
+            | KubeConfError::ExecError(message)
+            | KubeConfError::CredentialError(message)
+            | KubeConfError::RefreshError(message)
+            | KubeConfError::MigrateError(message)
+            | KubeConfError::VaultError(message)
+            | KubeConfError::DiffError(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+#[derive(Tabled)]
+struct PrettyPrintedMergeSummary {
+    #[tabled(rename = "FILE")]
+    file: String,
+    #[tabled(rename = "KIND")]
+    kind: String,
+    #[tabled(rename = "ADDED")]
+    added: String,
+    #[tabled(rename = "SKIPPED")]
+    skipped: String,
+    #[tabled(rename = "OVERRIDDEN")]
+    overridden: String,
+    #[tabled(rename = "RENAMED")]
+    renamed: String,
+    #[tabled(rename = "MERGED")]
+    merged: String,
+    #[tabled(rename = "UPDATED")]
+    updated: String,
+    #[tabled(rename = "REMOVED")]
+    removed: String,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq)]
+enum ReportFormat {
+    Human,
+    Json,
+}
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+enum MergeDecision {
+    Added,
+    Skipped,
+    Overridden,
+}
+
+/// What merging does with an entry of the other kubeconfig whose name already exists in main.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum OnConflict {
+    Skip,
+    Override,
+    Rename,
+}
+
+/// An entry imported under a new name because a different entry with its name exists in main.
+#[derive(Serialize, Debug)]
+struct MergeRename {
+    from: String,
+    to: String,
+}
+
+/// Names of the entries of one kind, grouped by what merging did with them.
+#[derive(Serialize, Debug, Default)]
+struct MergeEntityReport {
+    added: Vec<String>,
+    skipped: Vec<String>,
+    overridden: Vec<String>,
+    /// Conflicting entries imported under a new name with `--rename-conflicts`.
+    renamed: Vec<MergeRename>,
+    /// Existing entries merged field by field with `--strategy deep`.
+    merged: Vec<String>,
+    /// Upstream changes applied by a three-way merge.
+    updated: Vec<String>,
+    /// Entries removed upstream and unchanged locally, three-way merge only.
+    removed: Vec<String>,
+    /// Fields changed both locally and upstream, three-way merge only.
+    conflicts: Vec<String>,
+}
+
+#[derive(Serialize, Debug, Default)]
+struct MergePreferencesReport {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    colors: Option<MergeDecision>,
+    extensions: MergeEntityReport,
+}
+
+/// What a single merge did with each entry of the other kubeconfig.
+#[derive(Serialize, Debug, Default)]
+#[serde(rename_all = "kebab-case")]
+struct MergeReport {
+    file: String,
+    clusters: MergeEntityReport,
+    users: MergeEntityReport,
+    contexts: MergeEntityReport,
+    extensions: MergeEntityReport,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    preferences: Option<MergePreferencesReport>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    current_context: Option<String>,
+}
+
+#[derive(Tabled)]
+struct PrettyPrintedSyncSummary {
+    #[tabled(rename = "FILE")]
+    file: String,
+    #[tabled(rename = "ADDED")]
+    added: usize,
+    #[tabled(rename = "UPDATED")]
+    updated: usize,
+    #[tabled(rename = "UNCHANGED")]
+    unchanged: usize,
+    #[tabled(rename = "REMOVED")]
+    removed: usize,
+    #[tabled(rename = "SKIPPED")]
+    skipped: usize,
+}
+
+#[derive(Tabled)]
+struct PrettyPrintedExpiringCertificate {
+    #[tabled(rename = "CONTEXT")]
+    context: String,
+    #[tabled(rename = "CERTIFICATE")]
+    certificate: String,
+    #[tabled(rename = "SUBJECT")]
+    subject: String,
+    #[tabled(rename = "EXPIRES")]
+    expires: String,
+}
+
+#[derive(Tabled)]
+struct PrettyPrintedFinding {
+    #[tabled(rename = "SEVERITY")]
+    severity: String,
+    #[tabled(rename = "RULE")]
+    rule: String,
+    #[tabled(rename = "ENTRY")]
+    entry: String,
+    #[tabled(rename = "MESSAGE")]
+    message: String,
+}
+
+#[derive(Tabled)]
+struct PrettyPrintedCheckResult {
+    #[tabled(rename = "CONTEXT")]
+    context: String,
+    #[tabled(rename = "SERVER")]
+    server: String,
+    #[tabled(rename = "VERSION")]
+    version: String,
+    #[tabled(rename = "AUTHENTICATED")]
+    authenticated: String,
+    #[tabled(rename = "IDENTITY")]
+    identity: String,
+    #[tabled(rename = "ERROR")]
+    error: String,
+}
+
+#[derive(Tabled)]
+struct PrettyPrintedDifference {
+    #[tabled(rename = "ENTRY")]
+    entry: String,
+    #[tabled(rename = "CHANGE")]
+    change: String,
+    #[tabled(rename = "FIELD")]
+    field: String,
+    #[tabled(rename = "OLD")]
+    old: String,
+    #[tabled(rename = "NEW")]
+    new: String,
 }
 
 #[derive(Tabled)]
 struct PrettyPrintedContextNamespace {
-    CONTEXT: String,
-    NAMESPACE: String,
+    #[tabled(rename = "CONTEXT")]
+    context: String,
+    #[tabled(rename = "NAMESPACE")]
+    namespace: String,
+    #[tabled(rename = "CERT EXPIRY")]
+    cert_expiry: String,
+    #[tabled(rename = "TOKEN EXPIRY")]
+    token_expiry: String,
+}
+
+/// The certificate of the context that expires first, if any could be decoded.
+fn first_expiring_certificate(
+    kubeconfig: &KubeConfig,
+    context: &str,
+    base_dir: &std::path::Path,
+) -> Option<certs::CertificateInfo> {
+    certs::inspect_context(kubeconfig, context, base_dir)
+        .ok()?
+        .into_iter()
+        .filter_map(|report| report.certificates.ok())
+        .flatten()
+        .min_by_key(|certificate| certificate.not_after_timestamp)
+}
+
+/// Expiration of the JWT stored for the user of the context that expires first, in seconds since the unix epoch,
+/// and whether kubectl refreshes it on its own.
+fn first_expiring_token(
+    kubeconfig: &KubeConfig,
+    context: &str,
+    base_dir: &std::path::Path,
+) -> Option<(i64, bool)> {
+    let context = kubeconfig.get_context(context)?;
+    let user = kubeconfig.get_user(&context.context.user)?;
+    jwt::stored_tokens(user, base_dir)
+        .iter()
+        .filter_map(|stored| {
+            let expiration = jwt::expiration(&jwt::decode_claims(&stored.token)?)?;
+            Some((expiration, stored.refreshable))
+        })
+        .min_by_key(|(expiration, _)| *expiration)
 }
 
 fn merge_kubeconfigs(
     main: KubeConfig,
     other: KubeConfig,
-    force: bool,
+    on_conflict: OnConflict,
     include_preferences: bool,
-) -> Result<KubeConfig, KubeConfError> {
+    use_context: Option<String>,
+    baseline: Option<&Baseline>,
+    field_rules: Option<&FieldRules>,
+) -> Result<(KubeConfig, MergeReport), KubeConfError> {
     let mut main = main;
+    let mut report = MergeReport::default();
+    let force = on_conflict == OnConflict::Override;
+    let rename = on_conflict == OnConflict::Rename;
+
+    // Resolve the context to use before other is consumed. An empty value means `--use` was given without a name.
+    let use_context = match use_context {
+        Some(name) if name.is_empty() => match other.get_current_context() {
+            Some(context) => Some(context.name.clone()),
+            None => {
+                return Err(KubeConfError::MergeError(
+                    "`--use` was given without a context name but the other kubeconfig has no current-context.".to_string(),
+                ));
+            }
+        },
+        Some(name) => {
+            if other.get_context(&name).is_none() {
+                return Err(KubeConfError::MergeError(format!(
+                    "`--use` context `{}` not found in the other kubeconfig.",
+                    name
+                )));
+            }
+            Some(name)
+        }
+        None => None,
+    };
 
-    // Merge preferences
-    if include_preferences {
-        match other.preferences {
-            Some(other_preferences) => {
-                match main.preferences {
-                    Some(main_preferences) => {
-                        // Some values already exist in main. Merge depending on force.
-                        let mut merged_preferences = Preferences::default();
-
-                        // Merge colors
-                        match main_preferences.colors {
-                            Some(colors) => {
-                                if force {
-                                    warn!(
-                                        "Merging preferences colors value to `{:?}` even though main had it set to `{}` because of --force flag.",
-                                        other_preferences.colors, colors
-                                    );
-                                    // If force, take the value from other.
-                                    merged_preferences.colors = other_preferences.colors;
-                                } else {
-                                    merged_preferences.colors = Some(colors);
-                                }
-                            }
-                            None => {
-                                // No colors in main, just apply other.
-                                merged_preferences.colors = other_preferences.colors;
-                            }
-                        }
+    // Three-way merge everything imported before, the rest continues as a regular merge.
+    let (other_clusters, other_users, other_contexts) = match baseline {
+        Some(baseline) => {
+            let (base_clusters, base_users, base_contexts) = match &baseline.kubeconfig {
+                Some(base) => (&base.clusters[..], &base.users[..], &base.contexts[..]),
+                None => (&[][..], &[][..], &[][..]),
+            };
+            (
+                three_way::merge_entries(
+                    "cluster",
+                    &mut main.clusters,
+                    other.clusters,
+                    base_clusters,
+                    &baseline.source,
+                    &mut report.clusters,
+                )?,
+                three_way::merge_entries(
+                    "user",
+                    &mut main.users,
+                    other.users,
+                    base_users,
+                    &baseline.source,
+                    &mut report.users,
+                )?,
+                {
+                    // Upstream contexts reference clusters and users by their upstream name.
+                    let cluster_renames =
+                        three_way::local_renames(&main.clusters, &baseline.source);
+                    let user_renames = three_way::local_renames(&main.users, &baseline.source);
+                    let mut base_contexts = base_contexts.to_vec();
+                    let mut other_contexts = other.contexts;
+                    for contexts in [&mut base_contexts, &mut other_contexts] {
+                        three_way::apply_local_renames(contexts, &cluster_renames, &user_renames);
+                    }
+                    three_way::merge_entries(
+                        "context",
+                        &mut main.contexts,
+                        other_contexts,
+                        &base_contexts,
+                        &baseline.source,
+                        &mut report.contexts,
+                    )?
+                },
+            )
+        }
+        None => (other.clusters, other.users, other.contexts),
+    };
 
-                        // Merge extensions
-                        let mut merged_extensions = main_preferences.extensions;
-                        for other_extension in other_preferences.extensions {
-                            let existing_extensions_index = merged_extensions
-                                .iter()
-                                .position(|e| e.name == other_extension.name);
-                            if existing_extensions_index.is_none() {
-                                merged_extensions.push(other_extension);
-                            } else if let Some(existing_extensions_index) =
-                                existing_extensions_index
-                            {
-                                if force {
-                                    warn!(
-                                        "Overriding preferences extension with name {} because of --force flag.",
-                                        other_extension.name
-                                    );
-                                    merged_extensions[existing_extensions_index] = other_extension;
-                                }
+    // Merge preferences
+    if include_preferences && let Some(other_preferences) = other.preferences {
+        let mut preferences_report = MergePreferencesReport::default();
+        match main.preferences {
+            Some(main_preferences) => {
+                // Some values already exist in main. Merge depending on force.
+                let mut merged_preferences = Preferences::default();
+
+                // Merge colors
+                match main_preferences.colors {
+                    Some(colors) => {
+                        if force {
+                            warn!(
+                                "Merging preferences colors value to `{:?}` even though main had it set to `{}` because of --force flag.",
+                                other_preferences.colors, colors
+                            );
+                            // If force, take the value from other.
+                            merged_preferences.colors = other_preferences.colors;
+                            preferences_report.colors = Some(MergeDecision::Overridden);
+                        } else {
+                            merged_preferences.colors = Some(colors);
+                            if other_preferences.colors.is_some() {
+                                preferences_report.colors = Some(MergeDecision::Skipped);
                             }
                         }
-                        merged_preferences.extensions = merged_extensions;
-
-                        // Set back to main.
-                        main.preferences = Some(merged_preferences);
                     }
                     None => {
-                        // No override would happen, so just take the new value.
-                        main.preferences = Some(other_preferences);
+                        // No colors in main, just apply other.
+                        merged_preferences.colors = other_preferences.colors;
+                        if other_preferences.colors.is_some() {
+                            preferences_report.colors = Some(MergeDecision::Added);
+                        }
                     }
                 }
+
+                // Merge extensions
+                let mut merged_extensions = main_preferences.extensions;
+                for other_extension in other_preferences.extensions {
+                    let existing_extensions_index = merged_extensions
+                        .iter()
+                        .position(|e| e.name == other_extension.name);
+                    match existing_extensions_index {
+                        None => {
+                            preferences_report
+                                .extensions
+                                .added
+                                .push(other_extension.name.clone());
+                            merged_extensions.push(other_extension);
+                        }
+                        Some(existing_extensions_index) if force => {
+                            warn!(
+                                "Overriding preferences extension with name {} because of --force flag.",
+                                other_extension.name
+                            );
+                            preferences_report
+                                .extensions
+                                .overridden
+                                .push(other_extension.name.clone());
+                            merged_extensions[existing_extensions_index] = other_extension;
+                        }
+                        Some(_) => {
+                            preferences_report
+                                .extensions
+                                .skipped
+                                .push(other_extension.name);
+                        }
+                    }
+                }
+                merged_preferences.extensions = merged_extensions;
+
+                // Set back to main.
+                main.preferences = Some(merged_preferences);
+            }
+            None => {
+                // No override would happen, so just take the new value.
+                if other_preferences.colors.is_some() {
+                    preferences_report.colors = Some(MergeDecision::Added);
+                }
+                preferences_report.extensions.added = other_preferences
+                    .extensions
+                    .iter()
+                    .map(|e| e.name.clone())
+                    .collect();
+                main.preferences = Some(other_preferences);
             }
-            None => {}
         }
+        report.preferences = Some(preferences_report);
     }
 
     // Merge clusters.
+    let mut cluster_renames: HashMap<String, String> = HashMap::new();
     let mut merged_clusters = main.clusters;
-    for other_cluster in other.clusters {
+    for other_cluster in other_clusters {
         let existing_clusters_index = merged_clusters
             .iter()
             .position(|e| e.name == other_cluster.name);
-        if existing_clusters_index.is_none() {
-            merged_clusters.push(other_cluster);
-        } else if let Some(existing_clusters_index) = existing_clusters_index {
-            if force {
+        match existing_clusters_index {
+            None => {
+                report.clusters.added.push(other_cluster.name.clone());
+                merged_clusters.push(other_cluster);
+            }
+            Some(existing_clusters_index) if let Some(field_rules) = field_rules => {
+                merged_clusters[existing_clusters_index] = deep_merge::merge_entry(
+                    &merged_clusters[existing_clusters_index],
+                    &other_cluster,
+                    field_rules,
+                )?;
+                info!(
+                    "Merged cluster with name {} field by field.",
+                    other_cluster.name
+                );
+                report.clusters.merged.push(other_cluster.name);
+            }
+            Some(existing_clusters_index) if force => {
                 warn!(
                     "Overriding cluster with name {} because of --force flag.",
                     other_cluster.name
                 );
+                report.clusters.overridden.push(other_cluster.name.clone());
                 merged_clusters[existing_clusters_index] = other_cluster;
             }
+            Some(existing_clusters_index)
+                if rename
+                    && !same_entry(&merged_clusters[existing_clusters_index], &other_cluster) =>
+            {
+                let mut other_cluster = other_cluster;
+                let name = free_name(&other_cluster.name, |n| {
+                    merged_clusters.iter().any(|c| c.name == n)
+                });
+                warn!(
+                    "Importing cluster {} as {} because a different cluster with that name exists.",
+                    other_cluster.name, name
+                );
+                cluster_renames.insert(other_cluster.name.clone(), name.clone());
+                report.clusters.renamed.push(MergeRename {
+                    from: other_cluster.name,
+                    to: name.clone(),
+                });
+                other_cluster.name = name;
+                merged_clusters.push(other_cluster);
+            }
+            Some(_) => {
+                report.clusters.skipped.push(other_cluster.name);
+            }
         }
     }
     // Set back to main.
     main.clusters = merged_clusters;
 
     // Merge users.
+    let mut user_renames: HashMap<String, String> = HashMap::new();
     let mut merged_users = main.users;
-    for other_user in other.users {
+    for other_user in other_users {
         let existing_users_index = merged_users.iter().position(|e| e.name == other_user.name);
-        if existing_users_index.is_none() {
-            merged_users.push(other_user);
-        } else if let Some(existing_users_index) = existing_users_index {
-            if force {
+        match existing_users_index {
+            None => {
+                report.users.added.push(other_user.name.clone());
+                merged_users.push(other_user);
+            }
+            Some(existing_users_index) if let Some(field_rules) = field_rules => {
+                merged_users[existing_users_index] = deep_merge::merge_entry(
+                    &merged_users[existing_users_index],
+                    &other_user,
+                    field_rules,
+                )?;
+                info!("Merged user with name {} field by field.", other_user.name);
+                report.users.merged.push(other_user.name);
+            }
+            Some(existing_users_index) if force => {
                 warn!(
                     "Overriding user with name {} because of --force flag.",
                     other_user.name
                 );
+                report.users.overridden.push(other_user.name.clone());
                 merged_users[existing_users_index] = other_user;
             }
+            Some(existing_users_index)
+                if rename && !same_entry(&merged_users[existing_users_index], &other_user) =>
+            {
+                let mut other_user = other_user;
+                let name = free_name(&other_user.name, |n| {
+                    merged_users.iter().any(|u| u.name == n)
+                });
+                warn!(
+                    "Importing user {} as {} because a different user with that name exists.",
+                    other_user.name, name
+                );
+                user_renames.insert(other_user.name.clone(), name.clone());
+                report.users.renamed.push(MergeRename {
+                    from: other_user.name,
+                    to: name.clone(),
+                });
+                other_user.name = name;
+                merged_users.push(other_user);
+            }
+            Some(_) => {
+                report.users.skipped.push(other_user.name);
+            }
         }
     }
     // Set back to main.
     main.users = merged_users;
 
-    // Merge contexts.
+    // Merge contexts, pointing them at the renamed clusters and users first.
+    let mut context_renames: HashMap<String, String> = HashMap::new();
     let mut merged_contexts = main.contexts;
-    for other_context in other.contexts {
+    for mut other_context in other_contexts {
+        if let Some(cluster) = cluster_renames.get(&other_context.context.cluster) {
+            other_context.context.cluster = cluster.clone();
+        }
+        if let Some(user) = user_renames.get(&other_context.context.user) {
+            other_context.context.user = user.clone();
+        }
         let existing_contexts_index = merged_contexts
             .iter()
             .position(|e| e.name == other_context.name);
-        if existing_contexts_index.is_none() {
-            merged_contexts.push(other_context);
-        } else if let Some(existing_contexts_index) = existing_contexts_index {
-            if force {
+        match existing_contexts_index {
+            None => {
+                report.contexts.added.push(other_context.name.clone());
+                merged_contexts.push(other_context);
+            }
+            Some(existing_contexts_index) if let Some(field_rules) = field_rules => {
+                merged_contexts[existing_contexts_index] = deep_merge::merge_entry(
+                    &merged_contexts[existing_contexts_index],
+                    &other_context,
+                    field_rules,
+                )?;
+                info!(
+                    "Merged context with name {} field by field.",
+                    other_context.name
+                );
+                report.contexts.merged.push(other_context.name);
+            }
+            Some(existing_contexts_index) if force => {
                 warn!(
                     "Overriding context with name {} because of --force flag.",
                     other_context.name
                 );
+                report.contexts.overridden.push(other_context.name.clone());
                 merged_contexts[existing_contexts_index] = other_context;
             }
+            Some(existing_contexts_index)
+                if rename
+                    && !same_entry(&merged_contexts[existing_contexts_index], &other_context) =>
+            {
+                let name = free_name(&other_context.name, |n| {
+                    merged_contexts.iter().any(|c| c.name == n)
+                });
+                warn!(
+                    "Importing context {} as {} because a different context with that name exists.",
+                    other_context.name, name
+                );
+                context_renames.insert(other_context.name.clone(), name.clone());
+                report.contexts.renamed.push(MergeRename {
+                    from: other_context.name,
+                    to: name.clone(),
+                });
+                other_context.name = name;
+                merged_contexts.push(other_context);
+            }
+            Some(_) => {
+                report.contexts.skipped.push(other_context.name);
+            }
         }
     }
     // Set back to main.
@@ -271,22 +1070,79 @@ fn merge_kubeconfigs(
         let existing_extensions_index = merged_extensions
             .iter()
             .position(|e| e.name == other_extension.name);
-        if existing_extensions_index.is_none() {
-            merged_extensions.push(other_extension);
-        } else if let Some(existing_extensions_index) = existing_extensions_index {
-            if force {
+        match existing_extensions_index {
+            None => {
+                report.extensions.added.push(other_extension.name.clone());
+                merged_extensions.push(other_extension);
+            }
+            Some(existing_extensions_index) if force => {
                 warn!(
                     "Overriding extension with name {} because of --force flag.",
                     other_extension.name
                 );
+                report
+                    .extensions
+                    .overridden
+                    .push(other_extension.name.clone());
                 merged_extensions[existing_extensions_index] = other_extension;
             }
+            Some(_) => {
+                report.extensions.skipped.push(other_extension.name);
+            }
         }
     }
     // Set back to main.
     main.extensions = merged_extensions;
 
-    return Ok(main);
+    // Three-way merges may remove the current context.
+    if let Some(current_context) = &main.current_context
+        && main.get_context(current_context).is_none()
+    {
+        warn!(
+            "Unsetting current-context `{}` because it was removed upstream.",
+            current_context
+        );
+        main.current_context = None;
+    }
+
+    // Switch current context, to the new name if the context was renamed.
+    let use_context = use_context.map(|name| context_renames.get(&name).cloned().unwrap_or(name));
+    if let Some(use_context) = use_context {
+        if report.contexts.skipped.contains(&use_context) {
+            warn!(
+                "Context `{}` was not imported because it already exists in main. The existing context is used instead. Add `--force` to override it.",
+                use_context
+            );
+        }
+        info!("Setting current-context to `{}`.", use_context);
+        report.current_context = Some(use_context.clone());
+        main.current_context = Some(use_context);
+    }
+
+    if let Err(e) = main.validate() {
+        return Err(KubeConfError::MergeError(format!(
+            "Merged kubeconfig would be invalid: {}",
+            e
+        )));
+    }
+
+    Ok((main, report))
+}
+
+/// Whether two entries with the same name are identical, so importing one again changes nothing.
+fn same_entry<T: Serialize>(main: &T, other: &T) -> bool {
+    match (serde_yaml::to_value(main), serde_yaml::to_value(other)) {
+        (Ok(main), Ok(other)) => main == other,
+        _ => false,
+    }
+}
+
+/// The first name not taken yet, made from `name` and a counter starting at 2, e.g. `prod-2`.
+fn free_name(name: &str, taken: impl Fn(&str) -> bool) -> String {
+    (2..)
+        .map(|counter| format!("{}-{}", name, counter))
+        .find(|candidate| !taken(candidate))
+        .unwrap()
 }
 
 fn rename_kubeconfig_values(
@@ -530,7 +1386,7 @@ fn rename_kubeconfig_values(
         );
     }
 
-    return kubeconfig;
+    kubeconfig
 }
 
 fn delete_context(kubeconfig: KubeConfig, context: String, yes: bool) -> KubeConfig {
@@ -601,33 +1457,685 @@ fn delete_context(kubeconfig: KubeConfig, context: String, yes: bool) -> KubeCon
         }
     }
 
-    return kubeconfig;
+    kubeconfig
 }
 
-fn write_kubeconfig(path: PathBuf, kubeconfig: KubeConfig, dry_run: bool) {
-    match serde_yaml::to_string(&kubeconfig) {
-        Ok(merged_kubeconfig_yaml) => {
-            if dry_run {
-                println!("{}", merged_kubeconfig_yaml);
-            } else {
-                match fs::write(&path, merged_kubeconfig_yaml) {
-                    Ok(()) => {
-                        // Done.
-                    }
-                    Err(error) => {
-                        panic!("Writing kubeconfig yaml failed with error: {}", error);
+/// The rows of the merge summary table and the three-way conflicts of all merged files.
+/// Kinds without any merged entry are left out.
+fn merge_summary_rows(reports: &[MergeReport]) -> (Vec<PrettyPrintedMergeSummary>, Vec<String>) {
+    let mut rows: Vec<PrettyPrintedMergeSummary> = vec![];
+    let mut conflicts: Vec<String> = vec![];
+    for report in reports {
+        let mut entities: Vec<(&str, &MergeEntityReport)> = vec![
+            ("clusters", &report.clusters),
+            ("users", &report.users),
+            ("contexts", &report.contexts),
+            ("extensions", &report.extensions),
+        ];
+        if let Some(preferences) = &report.preferences {
+            entities.push(("preferences.extensions", &preferences.extensions));
+            if let Some(colors) = preferences.colors {
+                let colors_name = "colors".to_string();
+                let pick = |decision: MergeDecision| {
+                    if colors == decision {
+                        colors_name.clone()
+                    } else {
+                        "".to_string()
                     }
-                }
+                };
+                rows.push(PrettyPrintedMergeSummary {
+                    file: report.file.clone(),
+                    kind: "preferences".to_string(),
+                    added: pick(MergeDecision::Added),
+                    skipped: pick(MergeDecision::Skipped),
+                    overridden: pick(MergeDecision::Overridden),
+                    renamed: "".to_string(),
+                    merged: "".to_string(),
+                    updated: "".to_string(),
+                    removed: "".to_string(),
+                });
             }
         }
-        Err(error) => {
-            panic!("Converting kubeconfig to yaml failed with error: {}", error);
+
+        for (kind, entity) in entities {
+            for conflict in &entity.conflicts {
+                conflicts.push(format!("{}: {} {}", report.file, kind, conflict));
+            }
+            if entity.added.is_empty()
+                && entity.skipped.is_empty()
+                && entity.overridden.is_empty()
+                && entity.renamed.is_empty()
+                && entity.merged.is_empty()
+                && entity.updated.is_empty()
+                && entity.removed.is_empty()
+            {
+                continue;
+            }
+            rows.push(PrettyPrintedMergeSummary {
+                file: report.file.clone(),
+                kind: kind.to_string(),
+                added: entity.added.join(", "),
+                skipped: entity.skipped.join(", "),
+                overridden: entity.overridden.join(", "),
+                renamed: entity
+                    .renamed
+                    .iter()
+                    .map(|r| format!("{} → {}", r.from, r.to))
+                    .collect::<Vec<String>>()
+                    .join(", "),
+                merged: entity.merged.join(", "),
+                updated: entity.updated.join(", "),
+                removed: entity.removed.join(", "),
+            });
         }
     }
+    (rows, conflicts)
 }
 
-fn main() {
+/// Prints the reports of all merged files either as a table or as json.
+fn print_merge_reports(reports: &[MergeReport], format: ReportFormat, dry_run: bool) {
+    match format {
+        ReportFormat::Json => {
+            let json = match serde_json::to_string_pretty(reports) {
+                Ok(json) => json,
+                Err(error) => panic!(
+                    "Converting merge report to json failed with error: {}",
+                    error
+                ),
+            };
+            if dry_run {
+                eprintln!("{}", json);
+            } else {
+                println!("{}", json);
+            }
+        }
+        ReportFormat::Human => {
+            let (rows, conflicts) = merge_summary_rows(reports);
+            for report in reports {
+                if let Some(current_context) = &report.current_context {
+                    eprintln!(
+                        "{}: current-context set to `{}`",
+                        report.file, current_context
+                    );
+                }
+            }
+
+            let mut table = Table::new(rows);
+            table.with(Style::blank());
+            table.modify(Columns::first(), Padding::zero());
+            eprintln!("{}", table);
+
+            if !conflicts.is_empty() {
+                eprintln!("Conflicts (local values were kept):");
+                for conflict in conflicts {
+                    eprintln!("  {}", conflict.red());
+                }
+            }
+        }
+    }
+}
+
+/// Expands the given `--other` values into the list of files to merge.
+/// Directories and glob patterns are expanded in sorted order, hidden files and `*.bak` backups in directories are skipped.
+fn expand_other_paths(others: Vec<PathBuf>) -> Vec<PathBuf> {
+    let mut paths: Vec<PathBuf> = vec![];
+    for other in others {
+        let other_str = other.to_string_lossy().to_string();
+        if other_str == "-" {
+            paths.push(other);
+        } else if other.is_dir() {
+            let entries = match fs::read_dir(&other) {
+                Ok(entries) => entries,
+                Err(e) => panic!(
+                    "Reading directory {} failed with error: {}",
+                    other.display(),
+                    e
+                ),
+            };
+            let mut dir_paths: Vec<PathBuf> = entries
+                .filter_map(|entry| entry.ok())
+                .map(|entry| entry.path())
+                .filter(|path| path.is_file())
+                .filter(|path| {
+                    !path
+                        .file_name()
+                        .map(|name| {
+                            let name = name.to_string_lossy();
+                            name.starts_with('.') || name.ends_with(".bak")
+                        })
+                        .unwrap_or(false)
+                })
+                .collect();
+            if dir_paths.is_empty() {
+                warn!("Directory {} does not contain any files.", other.display());
+            }
+            dir_paths.sort();
+            paths.append(&mut dir_paths);
+        } else if !other.exists() && other_str.contains(['*', '?', '[']) {
+            let entries = match glob::glob(&other_str) {
+                Ok(entries) => entries,
+                Err(e) => panic!("Invalid glob pattern {}: {}", other_str, e),
+            };
+            let mut glob_paths: Vec<PathBuf> = entries
+                .filter_map(|entry| entry.ok())
+                .filter(|path| path.is_file())
+                .collect();
+            if glob_paths.is_empty() {
+                warn!("Glob pattern {} did not match any files.", other_str);
+            }
+            glob_paths.sort();
+            paths.append(&mut glob_paths);
+        } else {
+            paths.push(other);
+        }
+    }
+
+    if paths.iter().filter(|p| p.to_string_lossy() == "-").count() > 1 {
+        panic!("`-` (stdin) can only be given once as `--other`.");
+    }
+
+    paths
+}
+
+/// Reads and verifies a kubeconfig to merge, either from the given file or from stdin if the path is `-`.
+fn read_other_kubeconfig(path: &PathBuf) -> KubeConfig {
+    if path.to_string_lossy() == "-" {
+        // Read from stdin.
+        let mut buffer = Vec::new();
+        let stdin = std::io::stdin();
+        let mut handle = stdin.lock();
+        match handle.read_to_end(&mut buffer) {
+            Ok(_size) => {
+                let s = match str::from_utf8(&buffer) {
+                    Ok(v) => v,
+                    Err(e) => panic!("invalid utf8 sequence in stdin: {}", e),
+                };
+
+                match KubeConfig::from_yaml(s) {
+                    Ok(k) => k,
+                    Err(e) => panic!(
+                        "Other kubeconfig (to merge) from stdin - could not be verified due to error: {}",
+                        e
+                    ),
+                }
+            }
+            Err(e) => {
+                panic!("error while reading stdin: {}", e);
+            }
+        }
+    } else {
+        match KubeConfig::from_file(path) {
+            Ok(k) => k,
+            Err(e) => panic!(
+                "Other kubeconfig (to merge) with path: {} - could not be verified due to error: {}",
+                path.display(),
+                e
+            ),
+        }
+    }
+}
+
+/// What happens to the backup when a kubeconfig is overwritten, see `diff::backup_path`.
+#[derive(Clone, Copy, PartialEq)]
+enum Backup {
+    /// Keep the previous version for `diff --against-backup`.
+    Keep,
+    /// Remove the backup, for commands that move secrets out of the kubeconfig. It would still hold them in plain text.
+    Remove,
+    /// Files kubeconf creates from the main kubeconfig, like `split` and `export` output, are not backed up.
+    Skip,
+}
+
+/// Writes the kubeconfig to `path`, encrypted again if it was read from a SOPS encrypted file.
+/// The file it replaces is kept as backup, see `diff::backup_path`.
+/// With `dry_run` it is printed instead, with secrets redacted unless `show_secrets` is set.
+fn write_kubeconfig(path: PathBuf, kubeconfig: KubeConfig, dry_run: bool, show_secrets: bool) {
+    write_kubeconfig_with_backup(path, kubeconfig, dry_run, show_secrets, Backup::Keep);
+}
+
+fn write_kubeconfig_with_backup(
+    path: PathBuf,
+    kubeconfig: KubeConfig,
+    dry_run: bool,
+    show_secrets: bool,
+    backup: Backup,
+) {
+    let mut kubeconfig = kubeconfig;
+    if dry_run && !show_secrets && redact::redact_kubeconfig(&mut kubeconfig) > 0 {
+        if stdout().is_terminal() {
+            info!("Secrets are redacted, add `--show-secrets` to print them.");
+        } else {
+            warn!(
+                "Secrets are redacted, the printed kubeconfig is not usable without `--show-secrets`."
+            );
+        }
+    }
+    // Printed decrypted, the SOPS metadata is only meaningful for the file on disk.
+    let yaml = if dry_run {
+        serde_yaml::to_string(&kubeconfig).map_err(|e| e.to_string())
+    } else {
+        kubeconfig.to_yaml().map_err(|e| e.to_string())
+    };
+    match yaml {
+        Ok(merged_kubeconfig_yaml) => {
+            if dry_run {
+                println!("{}", merged_kubeconfig_yaml);
+            } else {
+                // Keep the previous version for `diff --against-backup`, unless nothing changed.
+                let backup_dir = diff::backup_dir();
+                match backup {
+                    Backup::Keep => {
+                        if let Ok(previous) = fs::read(&path)
+                            && previous != merged_kubeconfig_yaml.as_bytes()
+                            && let Err(error) = diff::store_backup(&backup_dir, &path, &previous)
+                        {
+                            panic!(
+                                "Backing up kubeconfig {} failed with error: {}",
+                                path.display(),
+                                error
+                            );
+                        }
+                    }
+                    Backup::Remove => {
+                        let backup = diff::backup_path(&backup_dir, &path);
+                        if backup.exists() {
+                            info!(
+                                "Removing backup {} as it holds secrets in plain text.",
+                                backup.display()
+                            );
+                            if let Err(error) = fs::remove_file(&backup) {
+                                panic!(
+                                    "Removing backup {} failed with error: {}",
+                                    backup.display(),
+                                    error
+                                );
+                            }
+                        }
+                    }
+                    Backup::Skip => {}
+                }
+                // Kubeconfigs hold credentials, like kubectl only the owner gets to read them.
+                match flatten::write_private_file(&path, merged_kubeconfig_yaml.as_bytes()) {
+                    Ok(()) => {
+                        // Done.
+                    }
+                    Err(error) => {
+                        panic!("Writing kubeconfig yaml failed with error: {}", error);
+                    }
+                }
+            }
+        }
+        Err(error) => {
+            panic!("Converting kubeconfig to yaml failed with error: {}", error);
+        }
+    }
+}
+
+/// A unix timestamp as RFC 3339 date with the time until or since it, red once it passed.
+fn format_expiration(expiration: i64) -> String {
+    let seconds = expiration - time::OffsetDateTime::now_utc().unix_timestamp();
+    let date = time::OffsetDateTime::from_unix_timestamp(expiration)
+        .ok()
+        .and_then(|d| {
+            d.format(&time::format_description::well_known::Rfc3339)
+                .ok()
+        })
+        .unwrap_or(expiration.to_string());
+    let formatted = format!("{} ({})", date, certs::format_expiry(seconds));
+    if seconds <= 0 {
+        formatted.red().to_string()
+    } else {
+        formatted
+    }
+}
+
+/// A claim that is either a single string or a list of strings, e.g. `aud`.
+fn format_claim(claim: &serde_json::Value) -> String {
+    match claim {
+        serde_json::Value::String(value) => value.clone(),
+        serde_json::Value::Array(values) => values
+            .iter()
+            .map(|v| v.as_str().map(|s| s.to_string()).unwrap_or(v.to_string()))
+            .collect::<Vec<String>>()
+            .join(", "),
+        serde_json::Value::Null => "-".to_string(),
+        other => other.to_string(),
+    }
+}
+
+/// Shortens a value for a table cell, e.g. certificate data.
+fn truncate_value(value: String) -> String {
+    const MAX_CHARS: usize = 48;
+    if value.chars().count() <= MAX_CHARS {
+        return value;
+    }
+    let truncated: String = value.chars().take(MAX_CHARS - 1).collect();
+    format!("{}…", truncated)
+}
+
+fn print_stored_tokens(
+    context: &str,
+    user: &NamedUser,
+    base_dir: &std::path::Path,
+    show_secrets: bool,
+) {
+    println!("{}", format!("Context {}", context).bold());
+    let tokens = jwt::stored_tokens(user, base_dir);
+    if tokens.is_empty() {
+        println!("  No tokens stored for user `{}`.", user.name);
+        return;
+    }
+
+    for stored in tokens {
+        println!("  user `{}` {}", user.name, stored.source);
+        let (Some(header), Some(claims)) = (
+            jwt::decode_header(&stored.token),
+            jwt::decode_claims(&stored.token),
+        ) else {
+            println!("    Not a JWT.");
+            if show_secrets {
+                println!("    Token:      {}", stored.token);
+            }
+            continue;
+        };
+
+        let mut algorithm = format_claim(&header["alg"]);
+        if let Some(kid) = header["kid"].as_str() {
+            algorithm = format!("{} (kid {})", algorithm, kid);
+        }
+        println!("    Algorithm:  {}", algorithm);
+        println!("    Issuer:     {}", format_claim(&claims["iss"]));
+        println!("    Subject:    {}", format_claim(&claims["sub"]));
+        println!("    Audience:   {}", format_claim(&claims["aud"]));
+        println!("    Groups:     {}", format_claim(&claims["groups"]));
+        if let Some(issued_at) = claims["iat"].as_i64() {
+            println!(
+                "    Issued:     {}",
+                time::OffsetDateTime::from_unix_timestamp(issued_at)
+                    .ok()
+                    .and_then(|d| d
+                        .format(&time::format_description::well_known::Rfc3339)
+                        .ok())
+                    .unwrap_or(issued_at.to_string())
+            );
+        }
+        match jwt::expiration(&claims) {
+            Some(expiration) => println!("    Expires:    {}", format_expiration(expiration)),
+            None => println!("    Expires:    never"),
+        }
+        if show_secrets {
+            println!("    Token:      {}", stored.token);
+        }
+    }
+}
+
+fn print_certificate_reports(context: &str, reports: &[certs::CertificateReport]) {
+    println!("{}", format!("Context {}", context).bold());
+    if reports.is_empty() {
+        println!("  No certificates.");
+        return;
+    }
+
+    for report in reports {
+        println!("  {} ({})", report.description, report.location);
+        let certificates = match &report.certificates {
+            Ok(certificates) => certificates,
+            Err(error) => {
+                println!("    {}", error.red());
+                continue;
+            }
+        };
+        for certificate in certificates {
+            let sans = if certificate.sans.is_empty() {
+                "-".to_string()
+            } else {
+                certificate.sans.join(", ")
+            };
+            println!("    Subject:     {}", certificate.subject);
+            println!("    Issuer:      {}", certificate.issuer);
+            println!("    SANs:        {}", sans);
+            println!("    Not before:  {}", certificate.not_before);
+            let not_after = format!(
+                "{} ({})",
+                certificate.not_after,
+                certs::format_expiry(certificate.seconds_until_expiry())
+            );
+            let not_after = match certificate.expiry(certs::EXPIRY_WARNING) {
+                certs::Expiry::Expired => not_after.red().to_string(),
+                certs::Expiry::ExpiringSoon => not_after.yellow().to_string(),
+                certs::Expiry::Valid => not_after,
+            };
+            println!("    Not after:   {}", not_after);
+            println!(
+                "    Key:         {}{}",
+                certificate.key,
+                if certificate.is_ca { " (CA)" } else { "" }
+            );
+        }
+        match report.key_matches {
+            Some(true) => println!("    Key matches: {}", "yes".green()),
+            Some(false) => println!("    Key matches: {}", "no".red()),
+            None if report.has_key => println!("    Key matches: unknown"),
+            None => {}
+        }
+    }
+}
+
+/// Prints a line diff of the YAML of an entry before and after a change.
+fn print_yaml_diff<T: Serialize>(entry: &str, old: &T, new: &T) {
+    let (old_yaml, new_yaml) = match (serde_yaml::to_string(old), serde_yaml::to_string(new)) {
+        (Ok(old_yaml), Ok(new_yaml)) => (old_yaml, new_yaml),
+        (Err(error), _) | (_, Err(error)) => {
+            panic!("Converting {} to yaml failed with error: {}", entry, error)
+        }
+    };
+    println!("{}", entry.bold());
+    for line in diff::diff_lines(&old_yaml, &new_yaml) {
+        match line {
+            diff::DiffLine::Unchanged(line) => println!("  {}", line),
+            diff::DiffLine::Removed(line) => println!("{}", format!("- {}", line).red()),
+            diff::DiffLine::Added(line) => println!("{}", format!("+ {}", line).green()),
+        }
+    }
+    println!();
+}
+
+/// Installs the stderr logger. Warnings are shown by default.
+/// Without `-v`/`-q`, `KUBECONF_LOG` can be used to configure the level, e.g. `KUBECONF_LOG=debug`.
+/// Colors are disabled if `NO_COLOR` is set or stderr is not a terminal.
+fn init_logger(verbose: u8, quiet: u8) {
+    let mut builder = env_logger::Builder::new();
+    builder.format(|buf, record| {
+        let style = buf.default_level_style(record.level());
+        writeln!(
+            buf,
+            "{style}{}{style:#}: {}",
+            record.level().as_str().to_lowercase(),
+            record.args()
+        )
+    });
+
+    let level = match (verbose, quiet) {
+        (0, 0) => None,
+        (1, _) => Some(LevelFilter::Info),
+        (2, _) => Some(LevelFilter::Debug),
+        (_, 0) => Some(LevelFilter::Trace),
+        (_, 1) => Some(LevelFilter::Error),
+        (_, _) => Some(LevelFilter::Off),
+    };
+    match (level, std::env::var("KUBECONF_LOG")) {
+        (Some(level), _) => {
+            builder.filter_level(level);
+        }
+        (None, Ok(filters)) => {
+            builder.parse_filters(&filters);
+        }
+        (None, Err(_)) => {
+            builder.filter_level(LevelFilter::Warn);
+        }
+    }
+
+    builder.init();
+}
+
+/// Compares two kubeconfigs, see `Commands::Diff`. Exits non-zero if they differ.
+/// The main kubeconfig is only read if it is one of them.
+fn diff_command(
+    config: &PathBuf,
+    old: Option<PathBuf>,
+    new: Option<PathBuf>,
+    against_backup: bool,
+    report: ReportFormat,
+    show_secrets: bool,
+) {
+    let read = |path: &PathBuf| match KubeConfig::from_file(path) {
+        Ok(k) => k,
+        Err(e) => panic!(
+            "Kubeconfig with path: {} - could not be verified due to error: {}",
+            path.display(),
+            e
+        ),
+    };
+    let old_kubeconfig = if against_backup {
+        let backup = diff::backup_path(&diff::backup_dir(), config);
+        if !backup.exists() {
+            panic!(
+                "No backup found at {}, it is created when kubeconf writes the kubeconfig.",
+                backup.display()
+            );
+        }
+        read(&backup)
+    } else {
+        read(&old.expect("clap requires the old kubeconfig without --against-backup"))
+    };
+    let new_kubeconfig = read(new.as_ref().unwrap_or(config));
+
+    let difference = match diff::diff_kubeconfigs(&old_kubeconfig, &new_kubeconfig, show_secrets) {
+        Ok(difference) => difference,
+        Err(error) => panic!("Comparing kubeconfigs failed with error: {:?}", error),
+    };
+
+    match report {
+        ReportFormat::Json => match serde_json::to_string_pretty(&difference) {
+            Ok(json) => println!("{}", json),
+            Err(error) => {
+                panic!("Converting diff to json failed with error: {}", error)
+            }
+        },
+        ReportFormat::Human if difference.is_empty() => {
+            info!("No differences found.");
+        }
+        ReportFormat::Human => {
+            let mut rows: Vec<PrettyPrintedDifference> = vec![];
+            let mut colors: Vec<Color> = vec![];
+            let entities = [
+                ("cluster", &difference.clusters),
+                ("user", &difference.users),
+                ("context", &difference.contexts),
+                ("extension", &difference.extensions),
+            ];
+            for (kind, entity) in entities {
+                for (names, change, color) in [
+                    (&entity.removed, "removed", Color::FG_RED),
+                    (&entity.added, "added", Color::FG_GREEN),
+                ] {
+                    for name in names {
+                        rows.push(PrettyPrintedDifference {
+                            entry: format!("{} `{}`", kind, name),
+                            change: change.to_string(),
+                            field: "-".to_string(),
+                            old: "-".to_string(),
+                            new: "-".to_string(),
+                        });
+                        colors.push(color.clone());
+                    }
+                }
+                for changed in &entity.changed {
+                    for field in &changed.fields {
+                        rows.push(PrettyPrintedDifference {
+                            entry: format!("{} `{}`", kind, changed.name),
+                            change: "changed".to_string(),
+                            field: field.path.clone(),
+                            old: truncate_value(diff::format_value(field.old.as_ref())),
+                            new: truncate_value(diff::format_value(field.new.as_ref())),
+                        });
+                        colors.push(Color::FG_YELLOW);
+                    }
+                }
+            }
+            let preferences = difference
+                .preferences
+                .iter()
+                .map(|field| ("preferences", field.path.clone(), field));
+            let current_context = difference
+                .current_context
+                .iter()
+                .map(|field| ("current-context", "-".to_string(), field));
+            for (entry, path, field) in preferences.chain(current_context) {
+                rows.push(PrettyPrintedDifference {
+                    entry: entry.to_string(),
+                    change: "changed".to_string(),
+                    field: path,
+                    old: truncate_value(diff::format_value(field.old.as_ref())),
+                    new: truncate_value(diff::format_value(field.new.as_ref())),
+                });
+                colors.push(Color::FG_YELLOW);
+            }
+
+            let mut table = Table::new(rows);
+            table.with(Style::blank());
+            table.modify(Columns::first(), Padding::zero());
+            for (index, color) in colors.into_iter().enumerate() {
+                // Plus one because of the header.
+                table.modify(Cell::new(index + 1, 1), color);
+            }
+            println!("{}", table);
+        }
+    }
+
+    if !difference.is_empty() {
+        exit(1);
+    }
+}
+
+fn main() {
     let args = Args::parse();
+    init_logger(args.verbose, args.quiet);
+
+    // Run by kubectl, which may use another kubeconfig than the main one.
+    if let Commands::Credential {
+        name,
+        vault,
+        identity,
+    } = &args.command
+    {
+        let vault = vault
+            .clone()
+            .unwrap_or_else(|| vault::vault_path(&args.config));
+        if let Err(error) = vault::print_credential(name, &vault, identity.as_deref()) {
+            panic!("Reading the credential failed with error: {:?}", error);
+        }
+        return;
+    }
+
+    // Compares arbitrary files, the main kubeconfig does not even have to exist.
+    if let Commands::Diff {
+        old,
+        new,
+        against_backup,
+        report,
+    } = &args.command
+    {
+        diff_command(
+            &args.config,
+            old.clone(),
+            new.clone(),
+            *against_backup,
+            *report,
+            args.show_secrets,
+        );
+        return;
+    }
 
     let kubeconfig = match KubeConfig::from_file(&args.config) {
         Ok(k) => k,
@@ -645,69 +2153,872 @@ fn main() {
         Commands::Merge {
             other,
             force,
+            rename_conflicts,
             include_preferences,
+            use_context,
+            three_way,
+            strategy,
+            field_rule,
+            field_rules_file,
+            relative_paths,
+            report,
             dry_run,
         } => {
-            let mut other_kubeconfig: Option<KubeConfig> = None;
-            if let Some(path) = other.clone().into_os_string().to_str() {
-                if path == "-" {
-                    // Read from stdin.
-                    let mut buffer = Vec::new();
-                    let stdin = std::io::stdin();
-                    let mut handle = stdin.lock();
-                    match handle.read_to_end(&mut buffer) {
-                        Ok(_size) => {
-                            let s = match str::from_utf8(&buffer) {
-                                Ok(v) => v,
-                                Err(e) => panic!("invalid utf8 sequence in stdin: {}", e),
-                            };
-
-                            other_kubeconfig = match KubeConfig::from_yaml(s) {
-                                Ok(k) => Some(k),
+            // Rules given on the command line take precedence over the ones from the file.
+            let field_rules = match strategy {
+                MergeStrategy::Replace => None,
+                MergeStrategy::Deep => {
+                    let mut rules = match field_rules_file {
+                        Some(path) => match deep_merge::load_field_rules_file(&path) {
+                            Ok(rules) => rules,
+                            Err(error) => {
+                                panic!("Loading field rules failed with error: {:?}", error)
+                            }
+                        },
+                        None => HashMap::new(),
+                    };
+                    rules.extend(field_rule);
+                    Some(FieldRules {
+                        rules,
+                        default: if force {
+                            FieldPrecedence::Theirs
+                        } else {
+                            FieldPrecedence::Ours
+                        },
+                    })
+                }
+            };
+
+            let other_paths = expand_other_paths(other);
+            let destination_dir = flatten::kubeconfig_dir(&args.config);
+            let other_kubeconfigs: Vec<(PathBuf, KubeConfig)> = other_paths
+                .into_iter()
+                .map(|path| {
+                    let mut other_kubeconfig = read_other_kubeconfig(&path);
+                    // Relative file references are only valid next to the other file.
+                    let source_dir = if path.to_string_lossy() == "-" {
+                        PathBuf::from(".")
+                    } else {
+                        flatten::kubeconfig_dir(&path)
+                    };
+                    flatten::rebase_paths(
+                        &mut other_kubeconfig,
+                        &source_dir,
+                        if relative_paths {
+                            Some(destination_dir.as_path())
+                        } else {
+                            None
+                        },
+                    );
+                    (path, other_kubeconfig)
+                })
+                .collect();
+
+            // Only the last file providing the requested context gets to set it.
+            let use_context_index = match &use_context {
+                Some(name) if name.is_empty() => other_kubeconfigs
+                    .iter()
+                    .rposition(|(_, k)| k.current_context.is_some()),
+                Some(name) => other_kubeconfigs
+                    .iter()
+                    .rposition(|(_, k)| k.get_context(name).is_some()),
+                None => None,
+            };
+            if use_context.is_some() && use_context_index.is_none() {
+                match use_context.as_deref() {
+                    Some("") => panic!(
+                        "`--use` was given without a context name but none of the other kubeconfigs has a current-context."
+                    ),
+                    Some(name) => panic!(
+                        "`--use` context `{}` not found in any of the other kubeconfigs.",
+                        name
+                    ),
+                    None => {}
+                }
+            }
+
+            let on_conflict = if force {
+                OnConflict::Override
+            } else if rename_conflicts {
+                OnConflict::Rename
+            } else {
+                OnConflict::Skip
+            };
+            let mut merged_kubeconfig = kubeconfig;
+            let mut merge_reports: Vec<MergeReport> = vec![];
+            let mut new_baselines: Vec<(String, String)> = vec![];
+            for (index, (path, other_kubeconfig)) in other_kubeconfigs.into_iter().enumerate() {
+                let use_context = if Some(index) == use_context_index {
+                    use_context.clone()
+                } else {
+                    None
+                };
+
+                let baseline = if three_way {
+                    if path.to_string_lossy() == "-" {
+                        panic!(
+                            "`--three-way` needs a file to track, it can not be used with stdin."
+                        );
+                    }
+                    let source = match fs::canonicalize(&path) {
+                        Ok(p) => p.display().to_string(),
+                        Err(e) => panic!("Resolving {} failed with error: {}", path.display(), e),
+                    };
+                    match three_way::load_baseline(&args.config, &source) {
+                        Ok(baseline) => Some((baseline, other_kubeconfig.clone())),
+                        Err(error) => {
+                            panic!("Merging {} failed with error: {:?}", path.display(), error)
+                        }
+                    }
+                } else {
+                    None
+                };
+
+                match merge_kubeconfigs(
+                    merged_kubeconfig,
+                    other_kubeconfig,
+                    on_conflict,
+                    include_preferences,
+                    use_context,
+                    baseline.as_ref().map(|(baseline, _)| baseline),
+                    field_rules.as_ref(),
+                ) {
+                    Ok((kubeconfig, mut report)) => {
+                        merged_kubeconfig = kubeconfig;
+                        if let Some((baseline, imported)) = baseline {
+                            let imported =
+                                three_way::imported_baseline(imported, &baseline, &report);
+                            // Encrypted again if the source is SOPS encrypted, it holds the same secrets.
+                            match imported.to_yaml() {
+                                Ok(yaml) => new_baselines.push((baseline.source, yaml)),
                                 Err(e) => panic!(
-                                    "Other kubeconfig (to merge) from stdin - could not be verified due to error: {}",
+                                    "Converting {} to yaml failed with error: {}",
+                                    path.display(),
                                     e
                                 ),
                             }
                         }
-                        Err(e) => {
-                            panic!("error while reading stdin: {}", e);
+                        report.file = path.display().to_string();
+                        merge_reports.push(report);
+                    }
+                    Err(error) => {
+                        panic!("Merging {} failed with error: {:?}", path.display(), error);
+                    }
+                }
+            }
+
+            info!("Writing merged kubeconfig to original given kubeconfig location.");
+            write_kubeconfig(
+                args.config.clone(),
+                merged_kubeconfig,
+                dry_run,
+                args.show_secrets,
+            );
+
+            if !dry_run {
+                for (source, yaml) in new_baselines {
+                    if let Err(error) = three_way::store_baseline(&args.config, &source, &yaml) {
+                        panic!("Storing baseline failed with error: {:?}", error);
+                    }
+                }
+            }
+
+            print_merge_reports(&merge_reports, report, dry_run);
+        }
+        Commands::Sync { from, dry_run } => {
+            if !from.is_dir() {
+                panic!("`--from` {} is not a directory.", from.display());
+            }
+            let source_dir = match fs::canonicalize(&from) {
+                Ok(p) => p,
+                Err(e) => panic!(
+                    "Resolving directory {} failed with error: {}",
+                    from.display(),
+                    e
+                ),
+            };
+
+            let mut fragments: Vec<(String, KubeConfig)> = vec![];
+            let mut unreadable: Vec<String> = vec![];
+            for path in expand_other_paths(vec![source_dir.clone()]) {
+                // Fragments may reference shared clusters and users from other fragments.
+                match KubeConfig::from_file_unvalidated(&path) {
+                    Ok(mut fragment) => {
+                        flatten::rebase_paths(&mut fragment, &flatten::kubeconfig_dir(&path), None);
+                        fragments.push((path.display().to_string(), fragment));
+                    }
+                    Err(e) => {
+                        warn!(
+                            "Skipping fragment {} because it could not be parsed: {}",
+                            path.display(),
+                            e
+                        );
+                        unreadable.push(path.display().to_string());
+                    }
+                }
+            }
+
+            match sync::sync_kubeconfig(
+                kubeconfig,
+                fragments,
+                &source_dir.to_string_lossy(),
+                &unreadable,
+            ) {
+                Ok((synced_kubeconfig, summaries)) => {
+                    info!("Writing synced kubeconfig to original given kubeconfig location.");
+                    write_kubeconfig(args.config, synced_kubeconfig, dry_run, args.show_secrets);
+
+                    let summaries: Vec<PrettyPrintedSyncSummary> = summaries
+                        .into_iter()
+                        .map(|(file, summary)| PrettyPrintedSyncSummary {
+                            file,
+                            added: summary.added,
+                            updated: summary.updated,
+                            unchanged: summary.unchanged,
+                            removed: summary.removed,
+                            skipped: summary.skipped,
+                        })
+                        .collect();
+
+                    // Print to stderr so a dry run can still be piped.
+                    let mut table = Table::new(summaries);
+                    table.with(Style::blank());
+                    table.modify(Columns::first(), Padding::zero());
+                    eprintln!("{}", table);
+                }
+                Err(error) => {
+                    panic!("Syncing failed with error: {:?}", error);
+                }
+            }
+        }
+        Commands::Export {
+            contexts,
+            output,
+            no_credentials,
+            flatten,
+        } => match export::export_kubeconfig(&kubeconfig, &contexts, no_credentials) {
+            Ok(mut exported_kubeconfig) => {
+                if flatten
+                    && let Err(error) = flatten::flatten_kubeconfig(
+                        &mut exported_kubeconfig,
+                        &flatten::kubeconfig_dir(&args.config),
+                    )
+                {
+                    panic!("Flattening failed with error: {:?}", error);
+                }
+
+                // Relative paths are relative to the main kubeconfig, make them work where the export ends up.
+                let destination_dir = output.as_deref().map(flatten::kubeconfig_dir);
+                flatten::rebase_paths(
+                    &mut exported_kubeconfig,
+                    &flatten::kubeconfig_dir(&args.config),
+                    destination_dir.as_deref(),
+                );
+
+                match output {
+                    Some(output) => write_kubeconfig_with_backup(
+                        output,
+                        exported_kubeconfig,
+                        false,
+                        args.show_secrets,
+                        Backup::Skip,
+                    ),
+                    None => {
+                        write_kubeconfig(args.config, exported_kubeconfig, true, args.show_secrets)
+                    }
+                }
+            }
+            Err(error) => {
+                panic!("Exporting failed with error: {:?}", error);
+            }
+        },
+        Commands::Split {
+            out_dir,
+            factor_shared,
+            dry_run,
+            force,
+        } => {
+            let files = match export::split_kubeconfig(&kubeconfig, factor_shared) {
+                Ok(files) => files,
+                Err(error) => panic!("Splitting failed with error: {:?}", error),
+            };
+
+            if !dry_run {
+                let existing: Vec<String> = files
+                    .iter()
+                    .map(|(file_name, _)| out_dir.join(file_name))
+                    .filter(|path| path.exists())
+                    .map(|path| path.display().to_string())
+                    .collect();
+                if !existing.is_empty() && !force {
+                    panic!(
+                        "Refusing to overwrite existing files: {}. Add `--force` to overwrite them.",
+                        existing.join(", ")
+                    );
+                }
+                if let Err(e) = fs::create_dir_all(&out_dir) {
+                    panic!(
+                        "Creating directory {} failed with error: {}",
+                        out_dir.display(),
+                        e
+                    );
+                }
+            }
+
+            for (file_name, mut file) in files {
+                flatten::rebase_paths(
+                    &mut file,
+                    &flatten::kubeconfig_dir(&args.config),
+                    Some(&out_dir),
+                );
+                let path = out_dir.join(file_name);
+                if dry_run {
+                    println!("# {}", path.display());
+                } else {
+                    info!("Writing {}", path.display());
+                }
+                write_kubeconfig_with_backup(path, file, dry_run, args.show_secrets, Backup::Skip);
+            }
+        }
+        Commands::Unflatten { dir, dry_run } => {
+            let mut kubeconfig = kubeconfig;
+            match flatten::unflatten_kubeconfig(&mut kubeconfig, &dir, dry_run) {
+                Ok(files) => {
+                    info!("Extracted {} certificates and keys.", files.len());
+                    write_kubeconfig(args.config, kubeconfig, dry_run, args.show_secrets);
+                }
+                Err(error) => {
+                    panic!("Unflattening failed with error: {:?}", error);
+                }
+            }
+        }
+        Commands::Flatten { dry_run } => {
+            let mut kubeconfig = kubeconfig;
+            match flatten::flatten_kubeconfig(
+                &mut kubeconfig,
+                &flatten::kubeconfig_dir(&args.config),
+            ) {
+                Ok(()) => {
+                    write_kubeconfig(args.config, kubeconfig, dry_run, args.show_secrets);
+                }
+                Err(error) => {
+                    panic!("Flattening failed with error: {:?}", error);
+                }
+            }
+        }
+        Commands::Certs {
+            context,
+            expiring_within,
+        } => {
+            let contexts: Vec<String> = match context {
+                Some(context) => vec![context],
+                None => kubeconfig.contexts.iter().map(|c| c.name.clone()).collect(),
+            };
+            let base_dir = flatten::kubeconfig_dir(&args.config);
+
+            let mut expiring: Vec<PrettyPrintedExpiringCertificate> = vec![];
+            let mut unreadable = 0;
+            for (index, context) in contexts.iter().enumerate() {
+                let reports = match certs::inspect_context(&kubeconfig, context, &base_dir) {
+                    Ok(reports) => reports,
+                    Err(error) => panic!("Inspecting certificates failed with error: {:?}", error),
+                };
+
+                let Some(expiring_within) = expiring_within else {
+                    if index > 0 {
+                        println!();
+                    }
+                    print_certificate_reports(context, &reports);
+                    continue;
+                };
+                for report in reports {
+                    let certificates = match report.certificates {
+                        Ok(certificates) => certificates,
+                        Err(error) => {
+                            warn!(
+                                "Could not check {} of context `{}`: {}",
+                                report.description, context, error
+                            );
+                            unreadable += 1;
+                            continue;
+                        }
+                    };
+                    for certificate in certificates {
+                        if certificate.expiry(expiring_within) != certs::Expiry::Valid {
+                            expiring.push(PrettyPrintedExpiringCertificate {
+                                context: context.clone(),
+                                certificate: report.description.clone(),
+                                subject: certificate.subject.clone(),
+                                expires: certs::format_expiry(certificate.seconds_until_expiry()),
+                            });
                         }
                     }
                 }
             }
 
-            if other_kubeconfig.is_none() {
-                other_kubeconfig = match KubeConfig::from_file(&other) {
-                    Ok(k) => Some(k),
+            if expiring_within.is_some() {
+                if expiring.is_empty() {
+                    if unreadable > 0 {
+                        warn!(
+                            "No readable certificates expire within the given time, but {} could not be checked.",
+                            unreadable
+                        );
+                        exit(1);
+                    }
+                    info!("No certificates expire within the given time.");
+                    return;
+                }
+                let mut table = Table::new(expiring);
+                table.with(Style::blank());
+                table.modify(Columns::first(), Padding::zero());
+                println!("{}", table);
+                exit(1);
+            }
+        }
+        Commands::Lint { server_certificate } => {
+            let mut server_certificates: HashMap<String, Vec<u8>> = HashMap::new();
+            for (cluster, path) in server_certificate {
+                if kubeconfig.get_cluster(&cluster).is_none() {
+                    panic!("Cluster `{}` not found in kubeconfig.", cluster);
+                }
+                match fs::read(&path) {
+                    Ok(contents) => {
+                        server_certificates.insert(cluster, contents);
+                    }
                     Err(e) => panic!(
-                        "Other kubeconfig (to merge) with path: {} - could not be verified due to error: {}",
-                        other.display(),
+                        "Reading server certificate {} failed with error: {}",
+                        path.display(),
                         e
                     ),
+                }
+            }
+
+            let findings = lint::lint_kubeconfig(
+                &kubeconfig,
+                &flatten::kubeconfig_dir(&args.config),
+                &server_certificates,
+            );
+            if findings.is_empty() {
+                info!("No problems found.");
+                return;
+            }
+
+            let has_errors = findings.iter().any(|f| f.severity == lint::Severity::Error);
+            let severities: Vec<lint::Severity> = findings.iter().map(|f| f.severity).collect();
+            let mut table = Table::new(findings.into_iter().map(|f| PrettyPrintedFinding {
+                severity: match f.severity {
+                    lint::Severity::Error => "error".to_string(),
+                    lint::Severity::Warning => "warning".to_string(),
+                },
+                rule: f.rule.to_string(),
+                entry: f.entry,
+                message: f.message,
+            }));
+            table.with(Style::blank());
+            table.modify(Columns::first(), Padding::zero());
+            for (index, severity) in severities.into_iter().enumerate() {
+                let color = match severity {
+                    lint::Severity::Error => Color::FG_RED,
+                    lint::Severity::Warning => Color::FG_YELLOW,
                 };
+                // Plus one because of the header.
+                table.modify(Cell::new(index + 1, 0), color);
             }
+            println!("{}", table);
 
-            let other_kubeconfig = other_kubeconfig.unwrap();
+            if has_errors {
+                exit(1);
+            }
+        }
+        Commands::Check { selector, timeout } => {
+            let pattern = match selector.as_deref().map(glob::Pattern::new) {
+                Some(Ok(pattern)) => Some(pattern),
+                Some(Err(e)) => panic!("Invalid selector: {}", e),
+                None => None,
+            };
+            let contexts: Vec<String> = kubeconfig
+                .contexts
+                .iter()
+                .map(|c| c.name.clone())
+                .filter(|name| pattern.as_ref().is_none_or(|p| p.matches(name)))
+                .collect();
+            if contexts.is_empty() {
+                panic!("No context matches the selector.");
+            }
 
-            match merge_kubeconfigs(kubeconfig, other_kubeconfig, force, include_preferences) {
-                Ok(merged_kubeconfig) => {
-                    info!("Writing merged kubeconfig to original given kubeconfig location.");
+            let base_dir = flatten::kubeconfig_dir(&args.config);
+            let mut healthy: Vec<bool> = vec![];
+            let mut results: Vec<PrettyPrintedCheckResult> = vec![];
+            for context in &contexts {
+                let result = match check::check_context(
+                    &kubeconfig,
+                    context,
+                    &base_dir,
+                    timeout,
+                    Some(&exec::exec_cache_dir(&args.config)),
+                ) {
+                    Ok(result) => result,
+                    Err(error) => panic!("Checking failed with error: {:?}", error),
+                };
+                healthy.push(result.error.is_none() && result.authenticated == Some(true));
+                results.push(PrettyPrintedCheckResult {
+                    context: result.context,
+                    server: result.server,
+                    version: result.version.unwrap_or("-".to_string()),
+                    authenticated: match result.authenticated {
+                        Some(true) => "yes".to_string(),
+                        Some(false) => "no".to_string(),
+                        None => "-".to_string(),
+                    },
+                    identity: result.identity.unwrap_or("-".to_string()),
+                    error: result.error.unwrap_or_default(),
+                });
+            }
 
-                    write_kubeconfig(args.config, merged_kubeconfig, dry_run);
+            let mut table = Table::new(results);
+            table.with(Style::blank());
+            table.modify(Columns::first(), Padding::zero());
+            for (index, healthy) in healthy.iter().enumerate() {
+                if !healthy {
+                    // Plus one because of the header.
+                    table.modify(Rows::one(index + 1), Color::FG_RED);
                 }
-                Err(error) => {
-                    panic!("Merging failed with error: {:?}", error);
+            }
+            println!("{}", table);
+
+            if healthy.contains(&false) {
+                exit(1);
+            }
+        }
+        Commands::Token {
+            context,
+            refresh,
+            raw,
+        } => {
+            let show_secrets = args.show_secrets;
+            let (user, credential) = match credentials::resolve_credential(
+                &kubeconfig,
+                &context,
+                &flatten::kubeconfig_dir(&args.config),
+                Some(&exec::exec_cache_dir(&args.config)),
+                refresh,
+            ) {
+                Ok(resolved) => resolved,
+                Err(error) => panic!("Resolving the credential failed with error: {}", error),
+            };
+            let Some(credential) = credential else {
+                let message = if user.user.client_certificate.is_some()
+                    || user.user.client_certificate_data.is_some()
+                {
+                    format!(
+                        "User `{}` only authenticates with a client certificate, see `kubeconf certs {}`.",
+                        user.name, context
+                    )
+                } else {
+                    format!("User `{}` has no credentials.", user.name)
+                };
+                // Scripts get nothing on stdout and a failing exit code.
+                if raw {
+                    warn!("{}", message);
+                    exit(1);
                 }
+                println!("{}", message);
+                return;
+            };
+
+            if raw {
+                match &credential.authorization {
+                    Some(credentials::Authorization::Bearer(token)) => print!("{}", token),
+                    Some(credentials::Authorization::Basic { password, .. }) => {
+                        print!("{}", password)
+                    }
+                    None => {
+                        warn!(
+                            "The credential of context `{}` has no token or password.",
+                            context
+                        );
+                        exit(1);
+                    }
+                }
+                return;
+            }
+
+            let secret = |value: &str| {
+                if show_secrets {
+                    value.to_string()
+                } else {
+                    redact::redact_secret(value)
+                }
+            };
+            println!("Source:      {}", credential.source);
+            let mut claims = None;
+            match &credential.authorization {
+                Some(credentials::Authorization::Bearer(token)) => {
+                    println!("Type:        bearer token");
+                    println!("Token:       {}", secret(token));
+                    claims = jwt::decode_claims(token);
+                }
+                Some(credentials::Authorization::Basic { username, password }) => {
+                    println!("Type:        basic auth");
+                    println!("Username:    {}", username);
+                    println!("Password:    {}", secret(password));
+                }
+                None => println!("Type:        none"),
+            }
+            if credential.exec_client_certificate.is_some() {
+                println!("Certificate: client certificate and key returned by the exec plugin");
+            }
+            if let Some(expiration) = credential.expiration {
+                println!("Expires:     {}", format_expiration(expiration));
+            }
+            if let Some(claims) = claims {
+                println!("Claims:");
+                match serde_json::to_string_pretty(&claims) {
+                    Ok(claims) => {
+                        for line in claims.lines() {
+                            println!("  {}", line);
+                        }
+                    }
+                    Err(e) => warn!("Printing the claims failed: {}", e),
+                }
+            }
+        }
+        Commands::Refresh {
+            context,
+            timeout,
+            dry_run,
+        } => {
+            let Some(user_name) = kubeconfig
+                .get_context(&context)
+                .map(|c| c.context.user.clone())
+            else {
+                panic!("Context `{}` not found in kubeconfig.", context);
+            };
+            let mut kubeconfig = kubeconfig;
+            let Some(user) = kubeconfig.users.iter_mut().find(|u| u.name == user_name) else {
+                panic!("User `{}` not found in kubeconfig.", user_name);
+            };
+            let Some(auth_provider) = user.user.auth_provider.as_mut() else {
+                panic!("User `{}` has no auth-provider.", user_name);
+            };
+
+            // Refresh tokens may be rotated on use, so a dry run must not spend the stored one.
+            if dry_run {
+                match oidc::check_refreshable(auth_provider) {
+                    Ok(()) => info!(
+                        "User `{}` can be refreshed, not contacting the identity provider on a dry run.",
+                        user_name
+                    ),
+                    Err(error) => panic!("Refreshing failed with error: {:?}", error),
+                }
+                return;
+            }
+            if let Err(error) = oidc::refresh_oidc(
+                auth_provider,
+                &flatten::kubeconfig_dir(&args.config),
+                timeout,
+            ) {
+                panic!("Refreshing failed with error: {:?}", error);
+            }
+            if let Some(expiration) = auth_provider
+                .config
+                .get("id-token")
+                .and_then(|token| jwt::decode_claims(token))
+                .as_ref()
+                .and_then(jwt::expiration)
+            {
+                info!("New id-token expires at {}", format_expiration(expiration));
+            }
+            write_kubeconfig(args.config, kubeconfig, dry_run, args.show_secrets);
+        }
+        Commands::Migrate {
+            command:
+                MigrateCommands::Auth {
+                    user,
+                    include_oidc,
+                    include_client_secret,
+                    dry_run,
+                    yes,
+                },
+        } => {
+            if let Some(user) = &user
+                && kubeconfig.get_user(user).is_none()
+            {
+                panic!("User `{}` not found in kubeconfig.", user);
+            }
+
+            let mut kubeconfig = kubeconfig;
+            let mut migrated_users = 0;
+            for named_user in kubeconfig.users.iter_mut() {
+                if user.as_ref().is_some_and(|u| *u != named_user.name) {
+                    continue;
+                }
+                let migrated =
+                    match migrate::migrate_user(named_user, include_oidc, include_client_secret) {
+                        Ok(Some(migrated)) => migrated,
+                        Ok(None) => continue,
+                        Err(error) => panic!("Migrating failed with error: {:?}", error),
+                    };
+                let (mut old_user, mut new_user) = (named_user.clone(), migrated.clone());
+                if !args.show_secrets {
+                    redact::redact_user(&mut old_user.user);
+                    redact::redact_user(&mut new_user.user);
+                }
+                print_yaml_diff(&format!("user `{}`", named_user.name), &old_user, &new_user);
+                *named_user = migrated;
+                migrated_users += 1;
+            }
+
+            if migrated_users == 0 {
+                info!("No auth-providers to migrate.");
+                return;
+            }
+            if dry_run {
+                return;
+            }
+            if !yes {
+                let mut s = String::new();
+                print!(
+                    "Are you sure you want to migrate {} users? (y/n) ",
+                    migrated_users
+                );
+                let _ = stdout().flush();
+                stdin()
+                    .read_line(&mut s)
+                    .expect("User input broken. Please try again.");
+
+                if s.trim().to_lowercase() != "y" {
+                    println!("User cancelled migrating the auth-providers.");
+                    exit(1);
+                }
+            }
+            write_kubeconfig_with_backup(
+                args.config,
+                kubeconfig,
+                false,
+                args.show_secrets,
+                Backup::Remove,
+            );
+        }
+        Commands::Vault { command } => {
+            let (user, vault, identity, dry_run, sealing) = match command {
+                VaultCommands::Seal {
+                    user,
+                    vault,
+                    identity,
+                    dry_run,
+                } => (user, vault, identity, dry_run, true),
+                VaultCommands::Unseal {
+                    user,
+                    vault,
+                    identity,
+                    dry_run,
+                } => (user, vault, identity, dry_run, false),
+            };
+            if let Some(user) = &user
+                && kubeconfig.get_user(user).is_none()
+            {
+                panic!("User `{}` not found in kubeconfig.", user);
+            }
+
+            // The exec blocks have to find the vault and identity from any working directory.
+            let absolute = |path: PathBuf| match std::path::absolute(&path) {
+                Ok(path) => path,
+                Err(e) => panic!("Resolving {} failed with error: {}", path.display(), e),
+            };
+            let vault = absolute(vault.unwrap_or_else(|| vault::vault_path(&args.config)));
+            let key = match identity {
+                Some(identity) => vault::VaultKey::IdentityFile(absolute(identity)),
+                None => match vault::read_passphrase(sealing && !vault.exists()) {
+                    Ok(passphrase) => vault::VaultKey::Passphrase(passphrase),
+                    Err(error) => panic!("Opening the vault failed with error: {:?}", error),
+                },
+            };
+
+            let mut kubeconfig = kubeconfig;
+            if sealing {
+                // The vault is written first, the kubeconfig must not reference credentials that are not in it.
+                match vault::seal(&mut kubeconfig, user.as_deref(), &vault, &key, dry_run) {
+                    Ok(0) => info!("No inline credentials to seal."),
+                    Ok(count) => {
+                        info!("Sealed {} users.", count);
+                        write_kubeconfig_with_backup(
+                            args.config,
+                            kubeconfig,
+                            dry_run,
+                            args.show_secrets,
+                            Backup::Remove,
+                        );
+                    }
+                    Err(error) => panic!("Updating the vault failed with error: {:?}", error),
+                }
+            } else {
+                // The credentials only leave the vault once they are back in the kubeconfig.
+                match vault::unseal(&mut kubeconfig, user.as_deref(), &vault, &key) {
+                    Ok(unsealed) if unsealed.is_empty() => info!("No sealed users to unseal."),
+                    Ok(unsealed) => {
+                        info!("Unsealed {} users.", unsealed.len());
+                        write_kubeconfig(args.config, kubeconfig, dry_run, args.show_secrets);
+                        if !dry_run && let Err(error) = vault::forget(&vault, &key, &unsealed) {
+                            panic!("Updating the vault failed with error: {:?}", error);
+                        }
+                    }
+                    Err(error) => panic!("Updating the vault failed with error: {:?}", error),
+                }
+            }
+        }
+        Commands::Credential { .. } => unreachable!("handled before loading the kubeconfig"),
+        Commands::Jwt { context } => {
+            let show_secrets = args.show_secrets;
+            let contexts: Vec<String> = match context {
+                Some(context) => vec![context],
+                None => kubeconfig.contexts.iter().map(|c| c.name.clone()).collect(),
+            };
+            let base_dir = flatten::kubeconfig_dir(&args.config);
+
+            for (index, context) in contexts.iter().enumerate() {
+                let Some(user) = kubeconfig
+                    .get_context(context)
+                    .and_then(|c| kubeconfig.get_user(&c.context.user))
+                else {
+                    panic!("Context `{}` or its user not found in kubeconfig.", context);
+                };
+                if index > 0 {
+                    println!();
+                }
+                print_stored_tokens(context, user, &base_dir, show_secrets);
             }
         }
+        Commands::Diff { .. } => unreachable!("handled before loading the kubeconfig"),
         Commands::List { long } => {
             let mut context_namespaces: Vec<PrettyPrintedContextNamespace> = vec![];
+            let base_dir = flatten::kubeconfig_dir(&args.config);
+            // Only shown with `--long`, the plain list stays a list of names that scripts can use.
+            let first_expiring: Vec<Option<certs::CertificateInfo>> = if long {
+                kubeconfig
+                    .contexts
+                    .iter()
+                    .map(|context| {
+                        first_expiring_certificate(&kubeconfig, &context.name, &base_dir)
+                    })
+                    .collect()
+            } else {
+                vec![]
+            };
+            let now = time::OffsetDateTime::now_utc().unix_timestamp();
+            let token_expirations: Vec<Option<(i64, bool)>> = if long {
+                kubeconfig
+                    .contexts
+                    .iter()
+                    .map(|context| first_expiring_token(&kubeconfig, &context.name, &base_dir))
+                    .collect()
+            } else {
+                vec![]
+            };
 
             let current_context = kubeconfig.current_context.unwrap_or("".to_string());
             let mut current_context_index = 0;
-            let mut iterator = 0;
-            for context in kubeconfig.contexts {
+            for (iterator, context) in kubeconfig.contexts.into_iter().enumerate() {
                 let mut context_name = context.name;
                 let mut context_namespace_name =
                     context.context.namespace.unwrap_or("default".to_string());
@@ -723,14 +3034,22 @@ fn main() {
 
                 if long {
                     context_namespaces.push(PrettyPrintedContextNamespace {
-                        CONTEXT: context_name.to_string(),
-                        NAMESPACE: context_namespace_name.to_string(),
+                        context: context_name.to_string(),
+                        namespace: context_namespace_name.to_string(),
+                        cert_expiry: match &first_expiring[iterator] {
+                            Some(certificate) => {
+                                certs::format_expiry(certificate.seconds_until_expiry())
+                            }
+                            None => "-".to_string(),
+                        },
+                        token_expiry: match token_expirations[iterator] {
+                            Some((expiration, _)) => certs::format_expiry(expiration - now),
+                            None => "-".to_string(),
+                        },
                     });
                 } else {
                     println!("{}", context_name);
                 }
-
-                iterator += 1;
             }
 
             if long {
@@ -743,9 +3062,31 @@ fn main() {
                 );
                 // table.with(Padding::zero());
                 table.modify(Columns::first(), Padding::zero());
+                for (index, certificate) in first_expiring.iter().enumerate() {
+                    let color = match certificate
+                        .as_ref()
+                        .map(|c| c.expiry(certs::EXPIRY_WARNING))
+                    {
+                        Some(certs::Expiry::Expired) => Color::FG_RED,
+                        Some(certs::Expiry::ExpiringSoon) => Color::FG_YELLOW,
+                        _ => continue,
+                    };
+                    // Plus one because of the header.
+                    table.modify(Cell::new(index + 1, 2), color);
+                }
+                // Same severity as the `token-expired` lint, tokens kubectl refreshes on its own are only a warning.
+                for (index, token) in token_expirations.iter().enumerate() {
+                    let color = match token {
+                        Some((expiration, true)) if *expiration <= now => Color::FG_YELLOW,
+                        Some((expiration, false)) if *expiration <= now => Color::FG_RED,
+                        _ => continue,
+                    };
+                    // Plus one because of the header.
+                    table.modify(Cell::new(index + 1, 3), color);
+                }
 
                 // Print the table.
-                println!("{}", table.to_string());
+                println!("{}", table);
             }
         }
         Commands::Rename {
@@ -759,7 +3100,7 @@ fn main() {
             let new_kubeconfig =
                 rename_kubeconfig_values(kubeconfig, context, cluster, user, all, force);
 
-            write_kubeconfig(args.config, new_kubeconfig, dry_run);
+            write_kubeconfig(args.config, new_kubeconfig, dry_run, args.show_secrets);
         }
         Commands::Delete {
             context,
@@ -768,7 +3109,7 @@ fn main() {
         } => {
             let new_kubeconfig = delete_context(kubeconfig, context, dry_run || yes);
 
-            write_kubeconfig(args.config, new_kubeconfig, dry_run);
+            write_kubeconfig(args.config, new_kubeconfig, dry_run, args.show_secrets);
         }
     }
 
@@ -776,3 +3117,189 @@ fn main() {
     //     println!("Hello {}!", args.name);
     // }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn kubeconfig(yaml: &str) -> KubeConfig {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    #[test]
+    fn renames_conflicting_entries_and_repoints_contexts() {
+        let main = kubeconfig(
+            "
+apiVersion: v1
+kind: Config
+clusters:
+- {name: prod, cluster: {server: https://prod}}
+- {name: prod-2, cluster: {server: https://taken}}
+- {name: shared, cluster: {server: https://shared}}
+users:
+- {name: admin, user: {token: main}}
+contexts:
+- {name: prod, context: {cluster: prod, user: admin}}
+current-context: prod
+",
+        );
+        let other = kubeconfig(
+            "
+apiVersion: v1
+kind: Config
+clusters:
+- {name: prod, cluster: {server: https://other-prod}}
+- {name: shared, cluster: {server: https://shared}}
+- {name: dev, cluster: {server: https://dev}}
+users:
+- {name: admin, user: {token: other}}
+contexts:
+- {name: prod, context: {cluster: prod, user: admin}}
+- {name: dev, context: {cluster: dev, user: admin}}
+",
+        );
+
+        let (merged, report) = merge_kubeconfigs(
+            main,
+            other,
+            OnConflict::Rename,
+            false,
+            Some("prod".to_string()),
+            None,
+            None,
+        )
+        .unwrap();
+
+        // `prod-2` is taken already, identical entries are not imported twice.
+        assert_eq!(report.clusters.added, ["dev"]);
+        assert_eq!(report.clusters.skipped, ["shared"]);
+        assert_eq!(report.clusters.renamed.len(), 1);
+        assert_eq!(report.clusters.renamed[0].from, "prod");
+        assert_eq!(report.clusters.renamed[0].to, "prod-3");
+        assert_eq!(report.users.renamed[0].to, "admin-2");
+        assert_eq!(report.contexts.renamed[0].to, "prod-2");
+        assert_eq!(report.contexts.added, ["dev"]);
+
+        let renamed = merged.get_context("prod-2").unwrap();
+        assert_eq!(renamed.context.cluster, "prod-3");
+        assert_eq!(renamed.context.user, "admin-2");
+        assert_eq!(merged.get_context("dev").unwrap().context.user, "admin-2");
+        assert_eq!(
+            merged.get_cluster("prod-3").unwrap().cluster.server,
+            "https://other-prod"
+        );
+        // The existing entries are left alone and `--use` follows the rename.
+        assert_eq!(merged.get_context("prod").unwrap().context.cluster, "prod");
+        assert_eq!(merged.current_context.as_deref(), Some("prod-2"));
+        assert_eq!(report.current_context.as_deref(), Some("prod-2"));
+    }
+
+    #[test]
+    fn skips_conflicting_entries_without_rename() {
+        let main = kubeconfig(
+            "{apiVersion: v1, kind: Config, clusters: [{name: prod, cluster: {server: https://prod}}]}",
+        );
+        let other = kubeconfig(
+            "{apiVersion: v1, kind: Config, clusters: [{name: prod, cluster: {server: https://other-prod}}]}",
+        );
+        let (merged, report) =
+            merge_kubeconfigs(main, other, OnConflict::Skip, false, None, None, None).unwrap();
+        assert_eq!(report.clusters.skipped, ["prod"]);
+        assert!(report.clusters.renamed.is_empty());
+        assert_eq!(merged.clusters.len(), 1);
+        assert_eq!(
+            merged.get_cluster("prod").unwrap().cluster.server,
+            "https://prod"
+        );
+    }
+
+    fn sample_report() -> MergeReport {
+        let mut report = MergeReport {
+            file: "other.yaml".to_string(),
+            current_context: Some("prod-2".to_string()),
+            ..Default::default()
+        };
+        report.clusters.added.push("dev".to_string());
+        report.clusters.renamed.push(MergeRename {
+            from: "prod".to_string(),
+            to: "prod-2".to_string(),
+        });
+        report.users.skipped.push("admin".to_string());
+        report
+            .contexts
+            .conflicts
+            .push("`prod` field namespace".to_string());
+        report
+    }
+
+    #[test]
+    fn serializes_the_json_report() {
+        let reports = [sample_report()];
+        let empty = json!({
+            "added": [], "skipped": [], "overridden": [], "renamed": [], "merged": [],
+            "updated": [], "removed": [], "conflicts": []
+        });
+        let mut clusters = empty.clone();
+        clusters["added"] = json!(["dev"]);
+        clusters["renamed"] = json!([{"from": "prod", "to": "prod-2"}]);
+        let mut users = empty.clone();
+        users["skipped"] = json!(["admin"]);
+        let mut contexts = empty.clone();
+        contexts["conflicts"] = json!(["`prod` field namespace"]);
+
+        assert_eq!(
+            serde_json::to_value(reports).unwrap(),
+            json!([{
+                "file": "other.yaml",
+                "clusters": clusters,
+                "users": users,
+                "contexts": contexts,
+                "extensions": empty,
+                "current-context": "prod-2",
+            }])
+        );
+    }
+
+    #[test]
+    fn summarizes_merged_kinds() {
+        let mut with_preferences = sample_report();
+        with_preferences.file = "preferences.yaml".to_string();
+        with_preferences.preferences = Some(MergePreferencesReport {
+            colors: Some(MergeDecision::Skipped),
+            extensions: MergeEntityReport::default(),
+        });
+
+        let (rows, conflicts) = merge_summary_rows(&[sample_report(), with_preferences]);
+        let rows: Vec<(&str, &str, &str, &str, &str)> = rows
+            .iter()
+            .map(|r| {
+                (
+                    r.file.as_str(),
+                    r.kind.as_str(),
+                    r.added.as_str(),
+                    r.skipped.as_str(),
+                    r.renamed.as_str(),
+                )
+            })
+            .collect();
+        // Contexts only have conflicts, which are listed separately.
+        assert_eq!(
+            rows,
+            [
+                ("other.yaml", "clusters", "dev", "", "prod → prod-2"),
+                ("other.yaml", "users", "", "admin", ""),
+                ("preferences.yaml", "preferences", "", "colors", ""),
+                ("preferences.yaml", "clusters", "dev", "", "prod → prod-2"),
+                ("preferences.yaml", "users", "", "admin", ""),
+            ]
+        );
+        assert_eq!(
+            conflicts,
+            [
+                "other.yaml: contexts `prod` field namespace",
+                "preferences.yaml: contexts `prod` field namespace",
+            ]
+        );
+    }
+}
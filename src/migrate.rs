@@ -0,0 +1,317 @@
+use crate::KubeConfError;
+use crate::kubeconfig::{AuthProvider, ExecConfig, NamedUser};
+use log::warn;
+
+const EXEC_API_VERSION: &str = "client.authentication.k8s.io/v1beta1";
+
+/// Config keys that only hold state cached by the auth provider and are dropped without a warning.
+const CACHED_KEYS: [&str; 6] = [
+    "access-token",
+    "expiry",
+    "expires-in",
+    "expires-on",
+    "id-token",
+    "refresh-token",
+];
+
+fn required<'a>(auth_provider: &'a AuthProvider, key: &str) -> Result<&'a str, KubeConfError> {
+    match auth_provider.config.get(key) {
+        Some(value) if !value.is_empty() => Ok(value),
+        _ => Err(KubeConfError::MigrateError(format!(
+            "The {} auth-provider has no {}, which the exec plugin needs.",
+            auth_provider.name, key
+        ))),
+    }
+}
+
+fn warn_dropped_keys(user: &str, auth_provider: &AuthProvider, used: &[&str]) {
+    let mut dropped: Vec<&String> = auth_provider
+        .config
+        .keys()
+        .filter(|key| !used.contains(&key.as_str()) && !CACHED_KEYS.contains(&key.as_str()))
+        .collect();
+    dropped.sort();
+    for key in dropped {
+        warn!(
+            "The {} auth-provider of user `{}` sets {}, which the exec plugin does not support.",
+            auth_provider.name, user, key
+        );
+    }
+}
+
+/// gke-gcloud-auth-plugin gets everything it needs from gcloud, like `gcloud container clusters get-credentials` writes it.
+fn gcp(user: &str, auth_provider: &AuthProvider) -> ExecConfig {
+    warn_dropped_keys(user, auth_provider, &[]);
+    ExecConfig {
+        command: "gke-gcloud-auth-plugin".to_string(),
+        args: None,
+        env: None,
+        api_version: Some(EXEC_API_VERSION.to_string()),
+        install_hint: Some(
+            "Install gke-gcloud-auth-plugin for use with kubectl by following https://cloud.google.com/kubernetes-engine/docs/how-to/cluster-access-for-kubectl#install_plugin"
+                .to_string(),
+        ),
+        provide_cluster_info: Some(true),
+        interactive_mode: None,
+    }
+}
+
+/// Follows `kubelogin convert-kubeconfig`, which keeps the device code login of the auth-provider.
+fn azure(user: &str, auth_provider: &AuthProvider) -> Result<ExecConfig, KubeConfError> {
+    let mut args: Vec<String> = vec!["get-token".to_string()];
+    args.extend(["--login".to_string(), "devicecode".to_string()]);
+    for (key, flag) in [
+        ("apiserver-id", "--server-id"),
+        ("client-id", "--client-id"),
+        ("tenant-id", "--tenant-id"),
+    ] {
+        args.extend([flag.to_string(), required(auth_provider, key)?.to_string()]);
+    }
+    if let Some(environment) = auth_provider.config.get("environment") {
+        args.extend(["--environment".to_string(), environment.clone()]);
+    }
+    // Config mode 1 omits the `spn:` prefix of the audience, everything else is the legacy mode.
+    if auth_provider.config.get("config-mode").map(|m| m.as_str()) != Some("1") {
+        args.push("--legacy".to_string());
+    }
+    warn_dropped_keys(
+        user,
+        auth_provider,
+        &[
+            "apiserver-id",
+            "client-id",
+            "tenant-id",
+            "environment",
+            "config-mode",
+        ],
+    );
+    Ok(ExecConfig {
+        command: "kubelogin".to_string(),
+        args: Some(args),
+        env: None,
+        api_version: Some(EXEC_API_VERSION.to_string()),
+        install_hint: Some(
+            "Install kubelogin for use with kubectl by following https://aka.ms/aks/kubelogin"
+                .to_string(),
+        ),
+        provide_cluster_info: Some(false),
+        interactive_mode: None,
+    })
+}
+
+/// Uses the `oidc-login` kubectl plugin, which logs in with the browser instead of relying on a refresh token.
+fn oidc(
+    user: &str,
+    auth_provider: &AuthProvider,
+    include_client_secret: bool,
+) -> Result<ExecConfig, KubeConfError> {
+    let mut args: Vec<String> = vec!["oidc-login".to_string(), "get-token".to_string()];
+    args.push(format!(
+        "--oidc-issuer-url={}",
+        required(auth_provider, "idp-issuer-url")?
+    ));
+    args.push(format!(
+        "--oidc-client-id={}",
+        required(auth_provider, "client-id")?
+    ));
+    // Arguments end up in the process list, where every user of the machine can read them.
+    if let Some(client_secret) = auth_provider.config.get("client-secret") {
+        if include_client_secret {
+            warn!(
+                "The client-secret of user `{}` is passed as argument to oidc-login and visible in the process list while it runs.",
+                user
+            );
+            args.push(format!("--oidc-client-secret={}", client_secret));
+        } else {
+            warn!(
+                "Leaving out the client-secret of user `{}`, add `--include-client-secret` or pass `--oidc-client-secret` to oidc-login yourself if the client needs it.",
+                user
+            );
+        }
+    }
+    if let Some(extra_scopes) = auth_provider.config.get("extra-scopes") {
+        for scope in extra_scopes.split(',').filter(|s| !s.is_empty()) {
+            args.push(format!("--oidc-extra-scope={}", scope));
+        }
+    }
+    if let Some(data) = auth_provider.config.get("idp-certificate-authority-data") {
+        args.push(format!("--certificate-authority-data={}", data));
+    } else if let Some(path) = auth_provider.config.get("idp-certificate-authority") {
+        args.push(format!("--certificate-authority={}", path));
+    }
+    warn_dropped_keys(
+        user,
+        auth_provider,
+        &[
+            "idp-issuer-url",
+            "client-id",
+            "client-secret",
+            "extra-scopes",
+            "idp-certificate-authority-data",
+            "idp-certificate-authority",
+        ],
+    );
+    Ok(ExecConfig {
+        command: "kubectl".to_string(),
+        args: Some(args),
+        env: None,
+        api_version: Some(EXEC_API_VERSION.to_string()),
+        install_hint: Some(
+            "Install the oidc-login kubectl plugin by following https://github.com/int128/kubelogin"
+                .to_string(),
+        ),
+        provide_cluster_info: Some(false),
+        interactive_mode: None,
+    })
+}
+
+/// Returns the user with its auth-provider replaced by the equivalent exec plugin.
+/// `None` if the user has no auth-provider that should be migrated.
+/// `oidc` is still supported by kubectl and only migrated if `include_oidc` is set,
+/// its client-secret is only kept if `include_client_secret` is set.
+pub fn migrate_user(
+    user: &NamedUser,
+    include_oidc: bool,
+    include_client_secret: bool,
+) -> Result<Option<NamedUser>, KubeConfError> {
+    let Some(auth_provider) = &user.user.auth_provider else {
+        return Ok(None);
+    };
+    if user.user.exec.is_some() {
+        return Err(KubeConfError::MigrateError(format!(
+            "User `{}` has both exec and auth-provider, remove one of them first.",
+            user.name
+        )));
+    }
+    let exec = match auth_provider.name.as_str() {
+        "gcp" => gcp(&user.name, auth_provider),
+        "azure" => azure(&user.name, auth_provider)?,
+        "oidc" if include_oidc => oidc(&user.name, auth_provider, include_client_secret)?,
+        "oidc" => return Ok(None),
+        other => {
+            warn!(
+                "Not migrating user `{}`, there is no exec plugin known for the {} auth-provider.",
+                user.name, other
+            );
+            return Ok(None);
+        }
+    };
+
+    let mut migrated = user.clone();
+    migrated.user.auth_provider = None;
+    migrated.user.exec = Some(exec);
+    Ok(Some(migrated))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn user(auth_provider: &str) -> NamedUser {
+        serde_yaml::from_str(&format!(
+            "{{name: admin, user: {{auth-provider: {}}}}}",
+            auth_provider
+        ))
+        .unwrap()
+    }
+
+    fn args(user: &NamedUser) -> Vec<String> {
+        user.user
+            .exec
+            .as_ref()
+            .unwrap()
+            .args
+            .clone()
+            .unwrap_or_default()
+    }
+
+    #[test]
+    fn migrates_gcp_to_the_gke_plugin() {
+        let migrated = migrate_user(
+            &user("{name: gcp, config: {access-token: cached, cmd-path: /usr/bin/gcloud}}"),
+            false,
+            false,
+        )
+        .unwrap()
+        .unwrap();
+        assert!(migrated.user.auth_provider.is_none());
+        let exec = migrated.user.exec.unwrap();
+        assert_eq!(exec.command, "gke-gcloud-auth-plugin");
+        assert_eq!(exec.api_version.as_deref(), Some(EXEC_API_VERSION));
+        assert_eq!(exec.provide_cluster_info, Some(true));
+        assert!(exec.args.is_none());
+    }
+
+    #[test]
+    fn migrates_azure_to_kubelogin() {
+        let azure = "{name: azure, config: {apiserver-id: server, client-id: client, tenant-id: tenant, environment: AzurePublicCloud, config-mode: '1'}}";
+        let migrated = migrate_user(&user(azure), false, false).unwrap().unwrap();
+        assert_eq!(migrated.user.exec.as_ref().unwrap().command, "kubelogin");
+        assert_eq!(
+            args(&migrated),
+            vec![
+                "get-token",
+                "--login",
+                "devicecode",
+                "--server-id",
+                "server",
+                "--client-id",
+                "client",
+                "--tenant-id",
+                "tenant",
+                "--environment",
+                "AzurePublicCloud",
+            ]
+        );
+
+        // Any other config mode is the legacy one.
+        let legacy = azure.replace("config-mode: '1'", "config-mode: '0'");
+        let migrated = migrate_user(&user(&legacy), false, false).unwrap().unwrap();
+        assert_eq!(args(&migrated).last().map(|a| a.as_str()), Some("--legacy"));
+        let without_mode = azure.replace(", config-mode: '1'", "");
+        let migrated = migrate_user(&user(&without_mode), false, false)
+            .unwrap()
+            .unwrap();
+        assert_eq!(args(&migrated).last().map(|a| a.as_str()), Some("--legacy"));
+
+        let without_tenant = azure.replace("tenant-id: tenant, ", "");
+        assert!(migrate_user(&user(&without_tenant), false, false).is_err());
+    }
+
+    #[test]
+    fn migrates_oidc_only_when_asked_and_leaves_out_the_client_secret() {
+        let oidc = user(
+            "{name: oidc, config: {idp-issuer-url: https://issuer, client-id: kube, client-secret: s3cret, extra-scopes: 'groups,email', id-token: cached}}",
+        );
+        assert!(migrate_user(&oidc, false, false).unwrap().is_none());
+
+        let migrated = migrate_user(&oidc, true, false).unwrap().unwrap();
+        assert_eq!(migrated.user.exec.as_ref().unwrap().command, "kubectl");
+        assert_eq!(
+            args(&migrated),
+            vec![
+                "oidc-login",
+                "get-token",
+                "--oidc-issuer-url=https://issuer",
+                "--oidc-client-id=kube",
+                "--oidc-extra-scope=groups",
+                "--oidc-extra-scope=email",
+            ]
+        );
+
+        let migrated = migrate_user(&oidc, true, true).unwrap().unwrap();
+        assert!(args(&migrated).contains(&"--oidc-client-secret=s3cret".to_string()));
+    }
+
+    #[test]
+    fn refuses_users_with_exec_and_auth_provider() {
+        let user: NamedUser = serde_yaml::from_str(
+            "{name: admin, user: {auth-provider: {name: gcp, config: {}}, exec: {apiVersion: client.authentication.k8s.io/v1beta1, command: gke-gcloud-auth-plugin}}}",
+        )
+        .unwrap();
+        assert!(migrate_user(&user, false, false).is_err());
+        // Nothing to migrate without an auth-provider.
+        let plain: NamedUser = serde_yaml::from_str("{name: admin, user: {token: t}}").unwrap();
+        assert!(migrate_user(&plain, true, true).unwrap().is_none());
+    }
+}
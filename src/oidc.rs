@@ -0,0 +1,305 @@
+use crate::KubeConfError;
+use crate::flatten::resolve_path;
+use crate::kubeconfig::AuthProvider;
+use base64::Engine;
+use log::{debug, info};
+use serde::Deserialize;
+use std::fs;
+use std::path::Path;
+use std::time::Duration;
+
+/// The parts of `.well-known/openid-configuration` needed to refresh tokens.
+#[derive(Deserialize)]
+struct Discovery {
+    issuer: String,
+    token_endpoint: String,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    id_token: Option<String>,
+    refresh_token: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ErrorResponse {
+    error: String,
+    error_description: Option<String>,
+}
+
+fn config_value<'a>(auth_provider: &'a AuthProvider, key: &str) -> Result<&'a str, KubeConfError> {
+    match auth_provider.config.get(key) {
+        Some(value) if !value.is_empty() => Ok(value),
+        _ => Err(KubeConfError::RefreshError(format!(
+            "The oidc auth-provider has no {}.",
+            key
+        ))),
+    }
+}
+
+/// HTTP client trusting only `idp-certificate-authority(-data)` if set, like kubectl.
+fn http_client(
+    auth_provider: &AuthProvider,
+    base_dir: &Path,
+    timeout: Duration,
+) -> Result<reqwest::blocking::Client, KubeConfError> {
+    let certificate_authority = match (
+        auth_provider.config.get("idp-certificate-authority-data"),
+        auth_provider.config.get("idp-certificate-authority"),
+    ) {
+        (Some(data), _) => Some(
+            base64::engine::general_purpose::STANDARD
+                .decode(data)
+                .map_err(|e| {
+                    KubeConfError::RefreshError(format!(
+                        "idp-certificate-authority-data is not valid base64: {}",
+                        e
+                    ))
+                })?,
+        ),
+        (None, Some(path)) => {
+            let resolved = resolve_path(base_dir, path);
+            Some(fs::read(&resolved).map_err(|e| {
+                KubeConfError::RefreshError(format!(
+                    "Reading idp-certificate-authority {} failed: {}",
+                    resolved.display(),
+                    e
+                ))
+            })?)
+        }
+        (None, None) => None,
+    };
+
+    let mut builder = reqwest::blocking::Client::builder()
+        .timeout(timeout)
+        .user_agent(concat!("kubeconf/", env!("CARGO_PKG_VERSION")));
+    if let Some(certificate_authority) = certificate_authority {
+        let certificates =
+            reqwest::Certificate::from_pem_bundle(&certificate_authority).map_err(|e| {
+                KubeConfError::RefreshError(format!(
+                    "idp-certificate-authority is not valid PEM: {}",
+                    e
+                ))
+            })?;
+        builder = builder.tls_built_in_root_certs(false);
+        for certificate in certificates {
+            builder = builder.add_root_certificate(certificate);
+        }
+    }
+    builder
+        .build()
+        .map_err(|e| KubeConfError::RefreshError(e.to_string()))
+}
+
+/// Encodes a value for `application/x-www-form-urlencoded`.
+fn form_urlencode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|b| match b {
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'*' | b'-' | b'.' | b'_' => {
+                (b as char).to_string()
+            }
+            b' ' => "+".to_string(),
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+/// Checks that the auth-provider has everything needed to refresh its tokens, without contacting the IdP.
+pub fn check_refreshable(auth_provider: &AuthProvider) -> Result<(), KubeConfError> {
+    if auth_provider.name != "oidc" {
+        return Err(KubeConfError::RefreshError(format!(
+            "Only the oidc auth-provider can be refreshed, not {}.",
+            auth_provider.name
+        )));
+    }
+    config_value(auth_provider, "idp-issuer-url")?;
+    config_value(auth_provider, "client-id")?;
+    config_value(auth_provider, "refresh-token")?;
+    Ok(())
+}
+
+/// Exchanges the refresh token of an oidc auth-provider for new tokens and stores them in its config.
+/// The token endpoint is discovered through `<idp-issuer-url>/.well-known/openid-configuration`.
+pub fn refresh_oidc(
+    auth_provider: &mut AuthProvider,
+    base_dir: &Path,
+    timeout: Duration,
+) -> Result<(), KubeConfError> {
+    check_refreshable(auth_provider)?;
+    let issuer = config_value(auth_provider, "idp-issuer-url")?.trim_end_matches('/');
+    let client_id = config_value(auth_provider, "client-id")?;
+    let refresh_token = config_value(auth_provider, "refresh-token")?;
+    let client_secret = auth_provider
+        .config
+        .get("client-secret")
+        .filter(|s| !s.is_empty());
+
+    let client = http_client(auth_provider, base_dir, timeout)?;
+
+    let discovery_url = format!("{}/.well-known/openid-configuration", issuer);
+    debug!("Discovering the token endpoint at {}", discovery_url);
+    let discovery: Discovery = client
+        .get(&discovery_url)
+        .send()
+        .and_then(|response| response.error_for_status())
+        .and_then(|response| response.json())
+        .map_err(|e| {
+            KubeConfError::RefreshError(format!("Discovery at {} failed: {}", discovery_url, e))
+        })?;
+    if discovery.issuer.trim_end_matches('/') != issuer {
+        return Err(KubeConfError::RefreshError(format!(
+            "The discovery document of {} is for issuer {}.",
+            issuer, discovery.issuer
+        )));
+    }
+
+    let mut form: Vec<(&str, &str)> = vec![
+        ("grant_type", "refresh_token"),
+        ("refresh_token", refresh_token),
+    ];
+    if let Some(extra_scopes) = auth_provider.config.get("extra-scopes") {
+        // Only needed if the IdP should narrow down the scopes, so do not add the defaults.
+        form.push(("scope", extra_scopes));
+    }
+    let mut request = client.post(&discovery.token_endpoint);
+    request = match client_secret {
+        // Confidential clients authenticate with basic auth, which every IdP has to support.
+        // Both parts are form-urlencoded first, see RFC 6749 section 2.3.1.
+        Some(client_secret) => request.basic_auth(
+            form_urlencode(client_id),
+            Some(form_urlencode(client_secret)),
+        ),
+        None => {
+            form.push(("client_id", client_id));
+            request
+        }
+    };
+
+    info!(
+        "Refreshing tokens at {} for client {}",
+        discovery.token_endpoint, client_id
+    );
+    let response = request.form(&form).send().map_err(|e| {
+        KubeConfError::RefreshError(format!(
+            "Requesting {} failed: {}",
+            discovery.token_endpoint, e
+        ))
+    })?;
+    let status = response.status();
+    if !status.is_success() {
+        let message = match response.json::<ErrorResponse>() {
+            Ok(error) => match error.error_description {
+                Some(description) => format!("{}: {}", error.error, description),
+                None => error.error,
+            },
+            Err(_) => status.to_string(),
+        };
+        return Err(KubeConfError::RefreshError(format!(
+            "The token endpoint refused to refresh the tokens: {}",
+            message
+        )));
+    }
+    let tokens: TokenResponse = response.json().map_err(|e| {
+        KubeConfError::RefreshError(format!("The token endpoint returned invalid JSON: {}", e))
+    })?;
+
+    let Some(id_token) = tokens.id_token else {
+        return Err(KubeConfError::RefreshError(
+            "The token endpoint returned no id_token.".to_string(),
+        ));
+    };
+    auth_provider
+        .config
+        .insert("id-token".to_string(), id_token);
+    // Some IdPs rotate the refresh token, others keep it.
+    if let Some(refresh_token) = tokens.refresh_token {
+        auth_provider
+            .config
+            .insert("refresh-token".to_string(), refresh_token);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_http::{Request, serve};
+    use std::collections::HashMap;
+
+    fn identity_provider(issuer: &str, request: &Request) -> (u16, String) {
+        match request.line.as_str() {
+            "GET /.well-known/openid-configuration HTTP/1.1" => (
+                200,
+                format!(
+                    r#"{{"issuer": "{}", "token_endpoint": "{}/token"}}"#,
+                    issuer, issuer
+                ),
+            ),
+            "POST /token HTTP/1.1" => {
+                // base64 of `my%3Aclient:s%C3%A9cret+%26`, the form-urlencoded client id and secret.
+                let authorized = request.authorization.as_deref()
+                    == Some("Basic bXklM0FjbGllbnQ6cyVDMyVBOWNyZXQrJTI2");
+                let valid_grant = request.body == "grant_type=refresh_token&refresh_token=old";
+                if authorized && valid_grant {
+                    (
+                        200,
+                        r#"{"id_token": "new-id", "refresh_token": "new-refresh"}"#.to_string(),
+                    )
+                } else {
+                    (
+                        400,
+                        r#"{"error": "invalid_grant", "error_description": "expired"}"#.to_string(),
+                    )
+                }
+            }
+            _ => (404, "{}".to_string()),
+        }
+    }
+
+    fn auth_provider(issuer: &str, refresh_token: &str) -> AuthProvider {
+        AuthProvider {
+            name: "oidc".to_string(),
+            config: HashMap::from([
+                ("idp-issuer-url".to_string(), issuer.to_string()),
+                ("client-id".to_string(), "my:client".to_string()),
+                ("client-secret".to_string(), "sécret &".to_string()),
+                ("refresh-token".to_string(), refresh_token.to_string()),
+                ("id-token".to_string(), "old-id".to_string()),
+            ]),
+        }
+    }
+
+    #[test]
+    fn stores_refreshed_tokens() {
+        let issuer = serve(identity_provider);
+        let mut auth_provider = auth_provider(&issuer, "old");
+        refresh_oidc(&mut auth_provider, Path::new("."), Duration::from_secs(5)).unwrap();
+        assert_eq!(auth_provider.config["id-token"], "new-id");
+        assert_eq!(auth_provider.config["refresh-token"], "new-refresh");
+    }
+
+    #[test]
+    fn reports_refused_refreshes() {
+        let issuer = serve(identity_provider);
+        let mut auth_provider = auth_provider(&issuer, "revoked");
+        let error = refresh_oidc(&mut auth_provider, Path::new("."), Duration::from_secs(5))
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("invalid_grant: expired"), "{}", error);
+        assert_eq!(auth_provider.config["id-token"], "old-id");
+    }
+
+    #[test]
+    fn rejects_discovery_documents_of_other_issuers() {
+        let issuer = serve(|_, _| {
+            (
+                200,
+                r#"{"issuer": "https://evil.example", "token_endpoint": "https://evil.example/token"}"#
+                    .to_string(),
+            )
+        });
+        let mut auth_provider = auth_provider(&issuer, "old");
+        assert!(refresh_oidc(&mut auth_provider, Path::new("."), Duration::from_secs(5)).is_err());
+    }
+}
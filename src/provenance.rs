@@ -0,0 +1,109 @@
+use crate::kubeconfig::{NamedCluster, NamedContext, NamedExtension, NamedUser};
+use serde::Serialize;
+use serde_yaml::{Mapping, Value};
+
+/// Name of the extension kubeconf uses to track where an entry was imported from.
+pub const PROVENANCE_EXTENSION_NAME: &str = "kubeconf";
+
+/// Key inside the provenance extension holding the absolute path of the source file.
+const PROVENANCE_SOURCE_KEY: &str = "source";
+
+/// Key inside the provenance extension holding the name the entry had in the source file.
+const PROVENANCE_NAME_KEY: &str = "name";
+
+/// Clusters, users and contexts all carry a name and extensions, which is all provenance tracking needs.
+pub trait ProvenanceEntry: Serialize {
+    fn name(&self) -> &str;
+    fn extensions(&self) -> &Vec<NamedExtension>;
+    fn extensions_mut(&mut self) -> &mut Vec<NamedExtension>;
+}
+
+impl ProvenanceEntry for NamedCluster {
+    fn name(&self) -> &str {
+        &self.name
+    }
+    fn extensions(&self) -> &Vec<NamedExtension> {
+        &self.cluster.extensions
+    }
+    fn extensions_mut(&mut self) -> &mut Vec<NamedExtension> {
+        &mut self.cluster.extensions
+    }
+}
+
+impl ProvenanceEntry for NamedUser {
+    fn name(&self) -> &str {
+        &self.name
+    }
+    fn extensions(&self) -> &Vec<NamedExtension> {
+        &self.user.extensions
+    }
+    fn extensions_mut(&mut self) -> &mut Vec<NamedExtension> {
+        &mut self.user.extensions
+    }
+}
+
+impl ProvenanceEntry for NamedContext {
+    fn name(&self) -> &str {
+        &self.name
+    }
+    fn extensions(&self) -> &Vec<NamedExtension> {
+        &self.context.extensions
+    }
+    fn extensions_mut(&mut self) -> &mut Vec<NamedExtension> {
+        &mut self.context.extensions
+    }
+}
+
+fn provenance_value<T: ProvenanceEntry>(entry: &T, key: &str) -> Option<String> {
+    entry
+        .extensions()
+        .iter()
+        .find(|e| e.name == PROVENANCE_EXTENSION_NAME)
+        .and_then(|e| e.extension.get(key))
+        .and_then(|v| v.as_str())
+        .map(|s| s.to_string())
+}
+
+/// Returns the file the entry was imported from, if it was imported by kubeconf at all.
+pub fn provenance_source<T: ProvenanceEntry>(entry: &T) -> Option<String> {
+    provenance_value(entry, PROVENANCE_SOURCE_KEY)
+}
+
+/// Returns the name the entry had in the file it was imported from.
+/// Falls back to the current name for entries imported before the name was tracked.
+pub fn provenance_name<T: ProvenanceEntry>(entry: &T) -> Option<String> {
+    provenance_source(entry)?;
+    provenance_value(entry, PROVENANCE_NAME_KEY).or(Some(entry.name().to_string()))
+}
+
+/// Removes the provenance extension from the entry, if any.
+pub fn remove_provenance<T: ProvenanceEntry>(entry: &mut T) {
+    entry
+        .extensions_mut()
+        .retain(|e| e.name != PROVENANCE_EXTENSION_NAME);
+}
+
+/// Marks the entry as imported from the given file under its current name, replacing any previous provenance.
+pub fn set_provenance_source<T: ProvenanceEntry>(entry: &mut T, source: &str) {
+    let name = entry.name().to_string();
+    set_provenance(entry, source, &name);
+}
+
+/// Marks the entry as imported from the given file where it is called `name`, replacing any previous provenance.
+pub fn set_provenance<T: ProvenanceEntry>(entry: &mut T, source: &str, name: &str) {
+    let mut extension = Mapping::new();
+    extension.insert(
+        Value::String(PROVENANCE_SOURCE_KEY.to_string()),
+        Value::String(source.to_string()),
+    );
+    extension.insert(
+        Value::String(PROVENANCE_NAME_KEY.to_string()),
+        Value::String(name.to_string()),
+    );
+
+    remove_provenance(entry);
+    entry.extensions_mut().push(NamedExtension {
+        name: PROVENANCE_EXTENSION_NAME.to_string(),
+        extension: Value::Mapping(extension),
+    });
+}
@@ -0,0 +1,148 @@
+use crate::kubeconfig::{KubeConfig, User};
+
+/// Hides a secret while keeping enough of it to tell secrets apart.
+pub fn redact_secret(secret: &str) -> String {
+    // Short secrets would be given away by their prefix.
+    if secret.chars().count() <= 8 {
+        return "REDACTED".to_string();
+    }
+    let prefix: String = secret.chars().take(4).collect();
+    format!("{}…REDACTED", prefix)
+}
+
+/// Auth provider config keys holding credentials. Everything else, e.g. `idp-issuer-url`, is configuration.
+const SECRET_AUTH_PROVIDER_KEYS: [&str; 4] =
+    ["access-token", "client-secret", "id-token", "refresh-token"];
+
+/// Parts of exec plugin flag names whose values are credentials, e.g. `--oidc-client-secret` or `--token`.
+const SECRET_ARG_WORDS: [&str; 6] = [
+    "secret",
+    "token",
+    "password",
+    "passwd",
+    "api-key",
+    "credential",
+];
+
+fn is_secret_flag(flag: &str) -> bool {
+    let flag = flag.to_lowercase();
+    // e.g. `--token-cache-dir` points to where secrets are, which is fine to show.
+    let is_location = ["-dir", "-file", "-path", "-url"]
+        .iter()
+        .any(|suffix| flag.ends_with(suffix));
+    flag.starts_with('-') && !is_location && SECRET_ARG_WORDS.iter().any(|word| flag.contains(word))
+}
+
+/// Masks the values of secret-looking flags, both `--flag=value` and `--flag value`.
+fn redact_args(args: &mut [String]) -> usize {
+    let mut redacted = 0;
+    let mut value_follows = false;
+    for arg in args.iter_mut() {
+        if value_follows && !arg.starts_with('-') {
+            *arg = redact_secret(arg);
+            redacted += 1;
+            value_follows = false;
+            continue;
+        }
+        match arg.split_once('=') {
+            Some((flag, value)) if is_secret_flag(flag) => {
+                *arg = format!("{}={}", flag, redact_secret(value));
+                redacted += 1;
+                value_follows = false;
+            }
+            Some(_) => value_follows = false,
+            None => value_follows = is_secret_flag(arg),
+        }
+    }
+    redacted
+}
+
+fn redact_field(field: &mut Option<String>) -> usize {
+    match field {
+        Some(secret) => {
+            *secret = redact_secret(secret);
+            1
+        }
+        None => 0,
+    }
+}
+
+/// Masks the secret-bearing fields of a user: `token`, `password`, `client-key-data`,
+/// the tokens and client secret in the auth-provider config, all exec plugin env values
+/// and the values of secret-looking exec plugin flags. Returns the number of masked fields.
+pub fn redact_user(user: &mut User) -> usize {
+    let mut redacted = redact_field(&mut user.token)
+        + redact_field(&mut user.password)
+        + redact_field(&mut user.client_key_data);
+    if let Some(auth_provider) = &mut user.auth_provider {
+        for (key, value) in auth_provider.config.iter_mut() {
+            if SECRET_AUTH_PROVIDER_KEYS.contains(&key.as_str()) {
+                *value = redact_secret(value);
+                redacted += 1;
+            }
+        }
+    }
+    if let Some(exec) = &mut user.exec {
+        // Plugins are configured through env, there is no telling which values are credentials.
+        for env in exec.env.iter_mut().flatten() {
+            env.value = redact_secret(&env.value);
+            redacted += 1;
+        }
+        if let Some(args) = &mut exec.args {
+            redacted += redact_args(args);
+        }
+    }
+    redacted
+}
+
+/// Masks the secrets of all users in the kubeconfig, see `redact_user`. Returns the number of masked fields.
+pub fn redact_kubeconfig(kubeconfig: &mut KubeConfig) -> usize {
+    kubeconfig
+        .users
+        .iter_mut()
+        .map(|user| redact_user(&mut user.user))
+        .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn redacts_exec_env_values_and_secret_flags() {
+        let mut user: User = serde_yaml::from_str(
+            "
+exec:
+  apiVersion: client.authentication.k8s.io/v1
+  command: kubectl
+  args:
+  - oidc-login
+  - --oidc-client-id=kubernetes
+  - --oidc-client-secret=0123456789abcdef
+  - --token
+  - abc
+  - --verbose
+  - --token-cache-dir=/tmp/cache
+  env:
+  - name: VAULT_TOKEN
+    value: s.0123456789abcdef
+",
+        )
+        .unwrap();
+        assert_eq!(redact_user(&mut user), 3);
+        let exec = user.exec.unwrap();
+        assert_eq!(
+            exec.args.unwrap(),
+            vec![
+                "oidc-login",
+                "--oidc-client-id=kubernetes",
+                "--oidc-client-secret=0123…REDACTED",
+                "--token",
+                "REDACTED",
+                "--verbose",
+                "--token-cache-dir=/tmp/cache",
+            ]
+        );
+        assert_eq!(exec.env.unwrap()[0].value, "s.01…REDACTED");
+    }
+}
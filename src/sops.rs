@@ -0,0 +1,622 @@
+use crate::kubeconfig::{KubeConfig, KubeConfigError};
+use aes_gcm::aead::consts::U32;
+use aes_gcm::aead::{AeadCore, AeadInPlace, KeyInit, OsRng};
+use aes_gcm::aes::Aes256;
+use aes_gcm::{AesGcm, Key, Nonce, Tag};
+use base64::Engine;
+use log::debug;
+use regex::Regex;
+use serde_yaml::{Mapping, Value};
+use sha2::{Digest, Sha512};
+use std::collections::HashMap;
+use std::io::Read;
+use std::path::PathBuf;
+use time::OffsetDateTime;
+use time::format_description::well_known::Rfc3339;
+
+/// Top level key SOPS stores its metadata in.
+const SOPS_KEY: &str = "sops";
+
+/// SOPS uses AES-GCM with a 256 bit nonce.
+type SopsCipher = AesGcm<Aes256, U32>;
+
+/// Hashed first into the MAC if only encrypted values are authenticated.
+const MAC_ONLY_ENCRYPTED_INIT: [u8; 32] = [
+    0x8a, 0x3f, 0xd2, 0xad, 0x54, 0xce, 0x66, 0x52, 0x7b, 0x10, 0x34, 0xf3, 0xd1, 0x47, 0xbe, 0x0b,
+    0x0b, 0x97, 0x5b, 0x3b, 0xf4, 0x4f, 0x72, 0xc6, 0xfd, 0xad, 0xec, 0x81, 0x76, 0xf2, 0x7d, 0x69,
+];
+
+fn sops_error(message: String) -> KubeConfigError {
+    KubeConfigError::SopsError(message)
+}
+
+/// Which keys SOPS encrypts, from the `*_suffix`/`*_regex` metadata.
+/// The first key on the path to a value that matches decides, unmatched values use the default.
+#[derive(Clone, Debug)]
+enum EncryptionRule {
+    EncryptedSuffix(String),
+    EncryptedRegex(Regex),
+    UnencryptedSuffix(String),
+    UnencryptedRegex(Regex),
+}
+
+impl EncryptionRule {
+    fn from_metadata(metadata: &Mapping) -> Result<Option<EncryptionRule>, KubeConfigError> {
+        let get = |key: &str| metadata.get(key).and_then(|v| v.as_str());
+        let regex = |pattern: &str| {
+            Regex::new(pattern).map_err(|e| {
+                sops_error(format!("Invalid regex {} in sops metadata: {}", pattern, e))
+            })
+        };
+        Ok(if let Some(suffix) = get("unencrypted_suffix") {
+            Some(EncryptionRule::UnencryptedSuffix(suffix.to_string()))
+        } else if let Some(suffix) = get("encrypted_suffix") {
+            Some(EncryptionRule::EncryptedSuffix(suffix.to_string()))
+        } else if let Some(pattern) = get("unencrypted_regex") {
+            Some(EncryptionRule::UnencryptedRegex(regex(pattern)?))
+        } else if let Some(pattern) = get("encrypted_regex") {
+            Some(EncryptionRule::EncryptedRegex(regex(pattern)?))
+        } else {
+            None
+        })
+    }
+
+    /// Whether values below the key are encrypted, `None` if the key does not decide it.
+    fn resolve(&self, key: &str) -> Option<bool> {
+        match self {
+            EncryptionRule::EncryptedSuffix(suffix) => {
+                key.ends_with(suffix.as_str()).then_some(true)
+            }
+            EncryptionRule::EncryptedRegex(regex) => regex.is_match(key).then_some(true),
+            EncryptionRule::UnencryptedSuffix(suffix) => {
+                key.ends_with(suffix.as_str()).then_some(false)
+            }
+            EncryptionRule::UnencryptedRegex(regex) => regex.is_match(key).then_some(false),
+        }
+    }
+
+    fn default_encrypted(rule: &Option<EncryptionRule>) -> bool {
+        matches!(
+            rule,
+            None | Some(EncryptionRule::UnencryptedSuffix(_))
+                | Some(EncryptionRule::UnencryptedRegex(_))
+        )
+    }
+}
+
+/// The `sops` metadata of an encrypted kubeconfig and its data key, needed to encrypt it again when writing.
+#[derive(Clone)]
+pub struct SopsMetadata {
+    metadata: Mapping,
+    data_key: [u8; 32],
+    rule: Option<EncryptionRule>,
+    mac_only_encrypted: bool,
+    /// Decrypted MAC of the file as read. Kept with its timestamp if the contents did not change.
+    mac: String,
+    /// The encrypted values as read by key path, type and plaintext, so unchanged values keep their ciphertext.
+    ciphertexts: HashMap<(String, String, Vec<u8>), String>,
+}
+
+impl std::fmt::Debug for SopsMetadata {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SopsMetadata")
+            .field("rule", &self.rule)
+            .field("mac_only_encrypted", &self.mac_only_encrypted)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Formats a float like Go's `strconv.FormatFloat(f, 'f', -1, 64)` which SOPS uses:
+/// the shortest representation that parses back to the same value, never in exponent notation.
+/// e.g.: 1 for 1.0, 0.0000001 for 1e-7
+fn format_float(f: f64) -> String {
+    if f.is_infinite() {
+        return if f > 0.0 { "+Inf" } else { "-Inf" }.to_string();
+    }
+    // Rust's Display already is the shortest round-tripping representation without exponent.
+    f.to_string()
+}
+
+/// A leaf value as SOPS sees it: its type name and the bytes that get encrypted and authenticated.
+fn leaf_bytes(value: &Value) -> Option<(&'static str, Vec<u8>)> {
+    match value {
+        Value::String(s) => Some(("str", s.as_bytes().to_vec())),
+        // Capitalized for compatibility with the original Python implementation.
+        Value::Bool(true) => Some(("bool", b"True".to_vec())),
+        Value::Bool(false) => Some(("bool", b"False".to_vec())),
+        Value::Number(n) if n.is_f64() => Some(("float", format_float(n.as_f64()?).into_bytes())),
+        Value::Number(n) => Some(("int", n.to_string().into_bytes())),
+        _ => None,
+    }
+}
+
+fn leaf_value(value_type: &str, bytes: Vec<u8>) -> Result<Value, String> {
+    let text = String::from_utf8(bytes).map_err(|e| e.to_string())?;
+    match value_type {
+        "str" | "bytes" => Ok(Value::String(text)),
+        "bool" => match text.to_lowercase().as_str() {
+            "true" => Ok(Value::Bool(true)),
+            "false" => Ok(Value::Bool(false)),
+            _ => Err(format!("{} is not a bool", text)),
+        },
+        "int" => text
+            .parse::<i64>()
+            .map(|i| Value::Number(i.into()))
+            .map_err(|e| e.to_string()),
+        "float" => text
+            .parse::<f64>()
+            .map(|f| Value::Number(f.into()))
+            .map_err(|e| e.to_string()),
+        other => Err(format!("unknown type {}", other)),
+    }
+}
+
+/// Calls `visit` with every leaf, its key path (e.g. `users:user:token:`) and whether it is encrypted.
+/// Items of sequences share the path of the sequence, like in SOPS.
+fn walk<E>(
+    value: &mut Value,
+    path: &str,
+    encrypted: Option<bool>,
+    rule: &Option<EncryptionRule>,
+    visit: &mut impl FnMut(&mut Value, &str, bool) -> Result<(), E>,
+) -> Result<(), E> {
+    match value {
+        Value::Mapping(mapping) => {
+            for (key, child) in mapping.iter_mut() {
+                let key = match key {
+                    Value::String(key) => key.clone(),
+                    other => serde_yaml::to_string(other)
+                        .unwrap_or_default()
+                        .trim()
+                        .to_string(),
+                };
+                let encrypted = encrypted.or_else(|| rule.as_ref().and_then(|r| r.resolve(&key)));
+                walk(child, &format!("{}{}:", path, key), encrypted, rule, visit)?;
+            }
+            Ok(())
+        }
+        Value::Sequence(sequence) => {
+            for child in sequence.iter_mut() {
+                walk(child, path, encrypted, rule, visit)?;
+            }
+            Ok(())
+        }
+        Value::Null | Value::Tagged(_) => Ok(()),
+        leaf => visit(
+            leaf,
+            path,
+            encrypted.unwrap_or_else(|| EncryptionRule::default_encrypted(rule)),
+        ),
+    }
+}
+
+/// A value in the syntax `ENC[AES256_GCM,data:...,iv:...,tag:...,type:...]`.
+struct EncryptedValue {
+    data: Vec<u8>,
+    iv: Vec<u8>,
+    tag: Vec<u8>,
+    value_type: String,
+}
+
+impl EncryptedValue {
+    fn parse(value: &str) -> Option<EncryptedValue> {
+        let fields = value.strip_prefix("ENC[AES256_GCM,")?.strip_suffix(']')?;
+        let mut parts: HashMap<&str, &str> = HashMap::new();
+        for field in fields.split(',') {
+            let (name, content) = field.split_once(':')?;
+            parts.insert(name, content);
+        }
+        let decode = |name: &str| {
+            base64::engine::general_purpose::STANDARD
+                .decode(parts.get(name)?)
+                .ok()
+        };
+        Some(EncryptedValue {
+            data: decode("data")?,
+            iv: decode("iv")?,
+            tag: decode("tag")?,
+            value_type: parts.get("type").unwrap_or(&"str").to_string(),
+        })
+    }
+}
+
+/// Returns the type and plaintext of an encrypted value.
+fn decrypt_value(
+    data_key: &[u8; 32],
+    encrypted: &str,
+    additional_data: &str,
+) -> Result<(String, Vec<u8>), String> {
+    let Some(mut encrypted) = EncryptedValue::parse(encrypted) else {
+        return Err("invalid ENC[] value".to_string());
+    };
+    if encrypted.iv.len() != 32 || encrypted.tag.len() != 16 {
+        return Err("unexpected iv or tag length".to_string());
+    }
+    SopsCipher::new(Key::<SopsCipher>::from_slice(data_key))
+        .decrypt_in_place_detached(
+            Nonce::from_slice(&encrypted.iv),
+            additional_data.as_bytes(),
+            &mut encrypted.data,
+            Tag::from_slice(&encrypted.tag),
+        )
+        .map_err(|_| "authentication failed".to_string())?;
+    Ok((encrypted.value_type, encrypted.data))
+}
+
+fn encrypt_value(
+    data_key: &[u8; 32],
+    value_type: &str,
+    plaintext: &[u8],
+    additional_data: &str,
+) -> String {
+    let nonce = SopsCipher::generate_nonce(&mut OsRng);
+    let mut data = plaintext.to_vec();
+    let tag = SopsCipher::new(Key::<SopsCipher>::from_slice(data_key))
+        .encrypt_in_place_detached(&nonce, additional_data.as_bytes(), &mut data)
+        .expect("plaintext fits into AES-GCM");
+    let encode = |bytes: &[u8]| base64::engine::general_purpose::STANDARD.encode(bytes);
+    format!(
+        "ENC[AES256_GCM,data:{},iv:{},tag:{},type:{}]",
+        encode(&data),
+        encode(&nonce),
+        encode(&tag),
+        value_type
+    )
+}
+
+/// Where SOPS looks for age keys: `SOPS_AGE_KEY`, `SOPS_AGE_KEY_FILE` and `sops/age/keys.txt` in the user config directory.
+fn age_identities() -> Result<Vec<Box<dyn age::Identity>>, KubeConfigError> {
+    let mut identities: Vec<Box<dyn age::Identity>> = vec![];
+    let mut add =
+        |source: &str, file: std::io::Result<age::IdentityFile<age::NoCallbacks>>| match file
+            .map_err(|e| e.to_string())
+            .and_then(|f| f.into_identities().map_err(|e| e.to_string()))
+        {
+            Ok(found) => {
+                debug!("Found {} age keys in {}", found.len(), source);
+                identities.extend(found);
+                Ok(())
+            }
+            Err(e) => Err(sops_error(format!(
+                "Reading age keys from {} failed: {}",
+                source, e
+            ))),
+        };
+
+    if let Ok(keys) = std::env::var("SOPS_AGE_KEY") {
+        add(
+            "SOPS_AGE_KEY",
+            age::IdentityFile::from_buffer(keys.as_bytes()),
+        )?;
+    }
+    if let Ok(path) = std::env::var("SOPS_AGE_KEY_FILE") {
+        add(&path, age::IdentityFile::from_file(path.clone()))?;
+    }
+    let config_dir = match std::env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) if !dir.is_empty() => Some(PathBuf::from(dir)),
+        _ if cfg!(target_os = "macos") => {
+            std::env::home_dir().map(|home| home.join("Library").join("Application Support"))
+        }
+        _ => std::env::home_dir().map(|home| home.join(".config")),
+    };
+    if let Some(config_dir) = config_dir {
+        let path = config_dir.join("sops").join("age").join("keys.txt");
+        if path.exists() {
+            let path = path.display().to_string();
+            add(&path, age::IdentityFile::from_file(path.clone()))?;
+        }
+    }
+    Ok(identities)
+}
+
+/// Decrypts the data key with the first age key that is a recipient of the file.
+fn decrypt_data_key(
+    metadata: &Mapping,
+    identities: &[Box<dyn age::Identity>],
+) -> Result<[u8; 32], KubeConfigError> {
+    let recipients: Vec<&str> = metadata
+        .get("age")
+        .and_then(|age| age.as_sequence())
+        .map(|entries| {
+            entries
+                .iter()
+                .filter_map(|entry| entry.get("enc").and_then(|enc| enc.as_str()))
+                .collect()
+        })
+        .unwrap_or_default();
+    if recipients.is_empty() {
+        return Err(sops_error(
+            "The file is not encrypted for any age recipient, other SOPS key types are not supported.".to_string(),
+        ));
+    }
+
+    if identities.is_empty() {
+        return Err(sops_error(
+            "No age keys found. Set SOPS_AGE_KEY_FILE or put them into sops/age/keys.txt in your config directory.".to_string(),
+        ));
+    }
+    for encrypted_key in recipients {
+        let Ok(decryptor) =
+            age::Decryptor::new_buffered(age::armor::ArmoredReader::new(encrypted_key.as_bytes()))
+        else {
+            continue;
+        };
+        let Ok(mut reader) =
+            decryptor.decrypt(identities.iter().map(|i| i.as_ref() as &dyn age::Identity))
+        else {
+            continue;
+        };
+        let mut data_key = vec![];
+        if reader.read_to_end(&mut data_key).is_ok()
+            && let Ok(data_key) = <[u8; 32]>::try_from(data_key)
+        {
+            return Ok(data_key);
+        }
+    }
+    Err(sops_error(
+        "None of the age keys can decrypt the SOPS data key.".to_string(),
+    ))
+}
+
+/// The hex encoded SHA-512 over all values SOPS authenticates.
+fn compute_mac(
+    value: &mut Value,
+    rule: &Option<EncryptionRule>,
+    mac_only_encrypted: bool,
+) -> String {
+    let mut hasher = Sha512::new();
+    if mac_only_encrypted {
+        hasher.update(MAC_ONLY_ENCRYPTED_INIT);
+    }
+    let _ = walk::<()>(value, "", None, rule, &mut |leaf, _, encrypted| {
+        if (encrypted || !mac_only_encrypted)
+            && let Some((_, bytes)) = leaf_bytes(leaf)
+        {
+            hasher.update(bytes);
+        }
+        Ok(())
+    });
+    hasher
+        .finalize()
+        .iter()
+        .map(|b| format!("{:02X}", b))
+        .collect()
+}
+
+/// Whether the parsed YAML document was encrypted with SOPS.
+pub fn is_encrypted(document: &Value) -> bool {
+    document
+        .get(SOPS_KEY)
+        .is_some_and(|metadata| metadata.get("mac").is_some())
+}
+
+/// Decrypts all values of a SOPS encrypted document in place and removes its `sops` metadata.
+/// The MAC is verified, so files modified without SOPS are rejected.
+pub fn decrypt(document: &mut Value) -> Result<SopsMetadata, KubeConfigError> {
+    decrypt_with(document, &age_identities()?)
+}
+
+/// Like `decrypt`, with the given age keys instead of those SOPS would look for.
+fn decrypt_with(
+    document: &mut Value,
+    identities: &[Box<dyn age::Identity>],
+) -> Result<SopsMetadata, KubeConfigError> {
+    let Some(Value::Mapping(metadata)) = document
+        .as_mapping_mut()
+        .and_then(|mapping| mapping.remove(SOPS_KEY))
+    else {
+        return Err(sops_error("The file has no sops metadata.".to_string()));
+    };
+    let data_key = decrypt_data_key(&metadata, identities)?;
+    let rule = EncryptionRule::from_metadata(&metadata)?;
+    let mac_only_encrypted = metadata
+        .get("mac_only_encrypted")
+        .and_then(|v| v.as_bool())
+        .unwrap_or(false);
+
+    let mut ciphertexts = HashMap::new();
+    walk(document, "", None, &rule, &mut |leaf, path, _| {
+        let Value::String(encrypted) = leaf else {
+            return Ok(());
+        };
+        if !encrypted.starts_with("ENC[") {
+            return Ok(());
+        }
+        let (value_type, plaintext) = decrypt_value(&data_key, encrypted, path)
+            .map_err(|e| sops_error(format!("Decrypting the value at {} failed: {}", path, e)))?;
+        ciphertexts.insert(
+            (path.to_string(), value_type.clone(), plaintext.clone()),
+            encrypted.clone(),
+        );
+        *leaf = leaf_value(&value_type, plaintext)
+            .map_err(|e| sops_error(format!("Decrypting the value at {} failed: {}", path, e)))?;
+        Ok(())
+    })?;
+
+    let (Some(last_modified), Some(encrypted_mac)) = (
+        metadata.get("lastmodified").and_then(|v| v.as_str()),
+        metadata.get("mac").and_then(|v| v.as_str()),
+    ) else {
+        return Err(sops_error(
+            "The sops metadata has no lastmodified or mac.".to_string(),
+        ));
+    };
+    let mac = decrypt_value(&data_key, encrypted_mac, last_modified)
+        .ok()
+        .and_then(|(_, mac)| String::from_utf8(mac).ok())
+        .ok_or_else(|| sops_error("Decrypting the MAC failed.".to_string()))?;
+    if compute_mac(document, &rule, mac_only_encrypted) != mac {
+        return Err(sops_error(
+            "The MAC does not match, the file was modified without SOPS.".to_string(),
+        ));
+    }
+
+    Ok(SopsMetadata {
+        metadata,
+        data_key,
+        rule,
+        mac_only_encrypted,
+        mac,
+        ciphertexts,
+    })
+}
+
+/// Serializes the kubeconfig encrypted like the file it was read from: the same keys are encrypted,
+/// unchanged values keep their ciphertext and the MAC and `lastmodified` are only updated if something changed.
+pub fn to_encrypted_yaml(
+    kubeconfig: &KubeConfig,
+    sops: &SopsMetadata,
+) -> Result<String, KubeConfigError> {
+    let mut document = serde_yaml::to_value(kubeconfig).map_err(KubeConfigError::ParseError)?;
+    let mut metadata = sops.metadata.clone();
+
+    let mac = compute_mac(&mut document, &sops.rule, sops.mac_only_encrypted);
+    if mac != sops.mac {
+        let last_modified = OffsetDateTime::now_utc()
+            .replace_nanosecond(0)
+            .ok()
+            .and_then(|now| now.format(&Rfc3339).ok())
+            .ok_or_else(|| sops_error("Formatting the current time failed.".to_string()))?;
+        metadata.insert(
+            "mac".into(),
+            encrypt_value(&sops.data_key, "str", mac.as_bytes(), &last_modified).into(),
+        );
+        metadata.insert("lastmodified".into(), last_modified.into());
+    }
+
+    walk::<KubeConfigError>(
+        &mut document,
+        "",
+        None,
+        &sops.rule,
+        &mut |leaf, path, encrypted| {
+            if !encrypted {
+                return Ok(());
+            }
+            let Some((value_type, plaintext)) = leaf_bytes(leaf) else {
+                return Ok(());
+            };
+            let key = (path.to_string(), value_type.to_string(), plaintext);
+            *leaf = match sops.ciphertexts.get(&key) {
+                Some(ciphertext) => ciphertext.clone().into(),
+                None => encrypt_value(&sops.data_key, value_type, &key.2, path).into(),
+            };
+            Ok(())
+        },
+    )?;
+
+    if let Some(mapping) = document.as_mapping_mut() {
+        mapping.insert(SOPS_KEY.into(), Value::Mapping(metadata));
+    }
+    serde_yaml::to_string(&document).map_err(KubeConfigError::ParseError)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ENCRYPTED: &str = include_str!("../tests/fixtures/sops/kubeconfig.enc.yaml");
+
+    /// Reads an encrypted kubeconfig with the fixture key, like `KubeConfig::from_yaml` does with the keys SOPS finds.
+    fn read_with_fixture_key(yaml: &str) -> Result<KubeConfig, KubeConfigError> {
+        let identities = age::IdentityFile::from_buffer(
+            include_str!("../tests/fixtures/sops/age-key.txt").as_bytes(),
+        )
+        .unwrap()
+        .into_identities()
+        .unwrap();
+        let mut document: Value = serde_yaml::from_str(yaml).unwrap();
+        let metadata = decrypt_with(&mut document, &identities)?;
+        let mut kubeconfig: KubeConfig =
+            serde_yaml::from_value(document).map_err(KubeConfigError::ParseError)?;
+        kubeconfig.validate()?;
+        kubeconfig.sops = Some(metadata);
+        Ok(kubeconfig)
+    }
+
+    #[test]
+    fn formats_floats_like_go() {
+        // Expected values from strconv.FormatFloat(f, 'f', -1, 64).
+        for (value, expected) in [
+            (1.0, "1"),
+            (1.5, "1.5"),
+            (-0.25, "-0.25"),
+            (1e21, "1000000000000000000000"),
+            (1e-7, "0.0000001"),
+            (0.1 + 0.2, "0.30000000000000004"),
+            (f64::INFINITY, "+Inf"),
+        ] {
+            assert_eq!(format_float(value), expected);
+        }
+    }
+
+    #[test]
+    fn decrypts_and_verifies_the_fixture() {
+        let kubeconfig = read_with_fixture_key(ENCRYPTED).unwrap();
+        assert_eq!(
+            kubeconfig.get_user("admin").unwrap().user.token.as_deref(),
+            Some("secret-token")
+        );
+        assert_eq!(
+            kubeconfig.extensions[0].extension["ratio"],
+            Value::from(1.5)
+        );
+    }
+
+    #[test]
+    fn decrypts_the_fixture_to_its_plain_version() {
+        let kubeconfig = read_with_fixture_key(ENCRYPTED).unwrap();
+        let plain: Value =
+            serde_yaml::from_str(include_str!("../tests/fixtures/sops/kubeconfig.yaml")).unwrap();
+        assert_eq!(serde_yaml::to_value(&kubeconfig).unwrap(), plain);
+    }
+
+    #[test]
+    fn rejects_fixture_modified_without_sops() {
+        let (head, tail) = ENCRYPTED.split_once("    replicas: ").unwrap();
+        let tail = tail.split_once('\n').unwrap().1;
+        // A plain value where an encrypted one was changes what the MAC covers.
+        let modified = format!("{}    replicas: 4\n{}", head, tail);
+        let error = read_with_fixture_key(&modified).unwrap_err();
+        assert!(format!("{:?}", error).contains("MAC does not match"));
+    }
+
+    #[test]
+    fn round_trips_the_fixture() {
+        let kubeconfig = read_with_fixture_key(ENCRYPTED).unwrap();
+        // Unchanged files keep their ciphertexts and MAC.
+        let unchanged = kubeconfig.to_yaml().unwrap();
+        assert!(!unchanged.contains("secret-token"));
+        let token_line = |yaml: &str| {
+            yaml.lines()
+                .find(|line| line.contains("token:"))
+                .unwrap()
+                .to_string()
+        };
+        assert_eq!(token_line(&unchanged), token_line(ENCRYPTED));
+
+        let mut kubeconfig = read_with_fixture_key(&unchanged).unwrap();
+        kubeconfig.users[0].user.token = Some("new-token".to_string());
+        let changed = kubeconfig.to_yaml().unwrap();
+        assert!(!changed.contains("new-token"));
+        let kubeconfig = read_with_fixture_key(&changed).unwrap();
+        assert_eq!(kubeconfig.users[0].user.token.as_deref(), Some("new-token"));
+    }
+
+    #[test]
+    fn exports_and_splits_stay_encrypted() {
+        let kubeconfig = read_with_fixture_key(ENCRYPTED).unwrap();
+        let exported =
+            crate::export::export_kubeconfig(&kubeconfig, &["prod".to_string()], false).unwrap();
+        let mut files = crate::export::split_kubeconfig(&kubeconfig, false).unwrap();
+        for file in [exported, files.remove(0).1] {
+            let yaml = file.to_yaml().unwrap();
+            assert!(!yaml.contains("secret-token"));
+            let file = read_with_fixture_key(&yaml).unwrap();
+            assert_eq!(
+                file.get_user("admin").unwrap().user.token.as_deref(),
+                Some("secret-token")
+            );
+        }
+    }
+}
@@ -0,0 +1,333 @@
+use crate::KubeConfError;
+use crate::kubeconfig::{KubeConfig, NamedCluster, NamedContext, NamedUser};
+use crate::provenance::{ProvenanceEntry, provenance_source, set_provenance_source};
+use log::{info, warn};
+use std::path::Path;
+
+/// What happened to the entries of a single fragment during sync.
+#[derive(Debug, Default)]
+pub struct SyncSummary {
+    pub added: usize,
+    pub updated: usize,
+    pub unchanged: usize,
+    pub removed: usize,
+    pub skipped: usize,
+}
+
+/// Syncs one kind of entries. `fragments` holds the entries of every fragment with their source.
+/// Entries in `main` imported from a source below `source_dir` that no longer exists in the fragments are removed,
+/// unless the source is one of the `unreadable` fragments.
+/// Also returns the names of fragment entries skipped because a hand-added entry has the same name.
+fn sync_entries<T: ProvenanceEntry>(
+    kind: &str,
+    main: Vec<T>,
+    fragments: Vec<(String, Vec<T>)>,
+    source_dir: &str,
+    unreadable: &[String],
+    summaries: &mut Vec<(String, SyncSummary)>,
+) -> (Vec<T>, Vec<String>) {
+    let mut merged: Vec<T> = vec![];
+    let mut shadowed: Vec<String> = vec![];
+    let mut fragment_entries: Vec<(String, T)> = vec![];
+    for (source, entries) in fragments {
+        for entry in entries {
+            if let Some((other_source, _)) = fragment_entries
+                .iter()
+                .find(|(_, e)| e.name() == entry.name())
+            {
+                warn!(
+                    "Skipping {} `{}` from {} because it is already defined in {}.",
+                    kind,
+                    entry.name(),
+                    source,
+                    other_source
+                );
+                summary_for(summaries, &source).skipped += 1;
+                continue;
+            }
+            fragment_entries.push((source.clone(), entry));
+        }
+    }
+
+    // Keep, update or remove existing entries.
+    for entry in main {
+        let existing_source = provenance_source(&entry);
+        let fragment_index = fragment_entries
+            .iter()
+            .position(|(_, e)| e.name() == entry.name());
+
+        match (existing_source, fragment_index) {
+            (None, Some(fragment_index)) => {
+                // Hand-added entry with the same name. Never touch it.
+                let (source, _) = fragment_entries.remove(fragment_index);
+                warn!(
+                    "Skipping {} `{}` from {} because a {} with that name was not imported by kubeconf.",
+                    kind,
+                    entry.name(),
+                    source,
+                    kind
+                );
+                summary_for(summaries, &source).skipped += 1;
+                shadowed.push(entry.name().to_string());
+                merged.push(entry);
+            }
+            (None, None) => {
+                merged.push(entry);
+            }
+            (Some(_), Some(fragment_index)) => {
+                let (source, mut new_entry) = fragment_entries.remove(fragment_index);
+                set_provenance_source(&mut new_entry, &source);
+                let unchanged =
+                    serde_yaml::to_value(&entry).ok() == serde_yaml::to_value(&new_entry).ok();
+                if unchanged {
+                    summary_for(summaries, &source).unchanged += 1;
+                } else {
+                    info!("Updating {} `{}` from {}.", kind, entry.name(), source);
+                    summary_for(summaries, &source).updated += 1;
+                }
+                merged.push(new_entry);
+            }
+            (Some(existing_source), None) => {
+                if unreadable.contains(&existing_source) {
+                    // Keep what was imported before until the fragment can be read again.
+                    merged.push(entry);
+                } else if Path::new(&existing_source).starts_with(source_dir) {
+                    info!(
+                        "Removing {} `{}` because it is no longer provided by {}.",
+                        kind,
+                        entry.name(),
+                        existing_source
+                    );
+                    summary_for(summaries, &existing_source).removed += 1;
+                } else {
+                    // Imported from somewhere else, not ours to manage.
+                    merged.push(entry);
+                }
+            }
+        }
+    }
+
+    // Whatever is left is new.
+    for (source, mut entry) in fragment_entries {
+        info!("Adding {} `{}` from {}.", kind, entry.name(), source);
+        set_provenance_source(&mut entry, &source);
+        summary_for(summaries, &source).added += 1;
+        merged.push(entry);
+    }
+
+    (merged, shadowed)
+}
+
+fn summary_for<'a>(
+    summaries: &'a mut Vec<(String, SyncSummary)>,
+    source: &str,
+) -> &'a mut SyncSummary {
+    let index = match summaries.iter().position(|(s, _)| s == source) {
+        Some(index) => index,
+        None => {
+            summaries.push((source.to_string(), SyncSummary::default()));
+            summaries.len() - 1
+        }
+    };
+    &mut summaries[index].1
+}
+
+/// Makes `main` reflect the given fragments, which are expected to be every file of `source_dir`
+/// except the `unreadable` ones, whose previously imported entries are kept as they are.
+/// Returns the synced kubeconfig and a summary per fragment source.
+pub fn sync_kubeconfig(
+    main: KubeConfig,
+    fragments: Vec<(String, KubeConfig)>,
+    source_dir: &str,
+    unreadable: &[String],
+) -> Result<(KubeConfig, Vec<(String, SyncSummary)>), KubeConfError> {
+    let mut main = main;
+    let mut summaries: Vec<(String, SyncSummary)> = fragments
+        .iter()
+        .map(|(source, _)| (source.clone(), SyncSummary::default()))
+        .collect();
+
+    let mut fragment_clusters: Vec<(String, Vec<NamedCluster>)> = vec![];
+    let mut fragment_users: Vec<(String, Vec<NamedUser>)> = vec![];
+    let mut fragment_contexts: Vec<(String, Vec<NamedContext>)> = vec![];
+    for (source, fragment) in fragments {
+        fragment_clusters.push((source.clone(), fragment.clusters));
+        fragment_users.push((source.clone(), fragment.users));
+        fragment_contexts.push((source, fragment.contexts));
+    }
+
+    let shadowed_clusters: Vec<String>;
+    let shadowed_users: Vec<String>;
+    (main.clusters, shadowed_clusters) = sync_entries(
+        "cluster",
+        main.clusters,
+        fragment_clusters,
+        source_dir,
+        unreadable,
+        &mut summaries,
+    );
+    (main.users, shadowed_users) = sync_entries(
+        "user",
+        main.users,
+        fragment_users,
+        source_dir,
+        unreadable,
+        &mut summaries,
+    );
+
+    // A context whose cluster or user was skipped would silently use the hand-added one instead.
+    for (source, contexts) in fragment_contexts.iter_mut() {
+        contexts.retain(|context| {
+            let shadowed = if shadowed_clusters.contains(&context.context.cluster) {
+                Some(("cluster", &context.context.cluster))
+            } else if shadowed_users.contains(&context.context.user) {
+                Some(("user", &context.context.user))
+            } else {
+                None
+            };
+            let Some((kind, name)) = shadowed else {
+                return true;
+            };
+            warn!(
+                "Skipping context `{}` from {} because its {} `{}` was not imported.",
+                context.name, source, kind, name
+            );
+            summary_for(&mut summaries, source).skipped += 1;
+            false
+        });
+    }
+    (main.contexts, _) = sync_entries(
+        "context",
+        main.contexts,
+        fragment_contexts,
+        source_dir,
+        unreadable,
+        &mut summaries,
+    );
+
+    if let Some(current_context) = &main.current_context
+        && main.get_context(current_context).is_none()
+    {
+        warn!(
+            "Unsetting current-context `{}` because it was removed by sync.",
+            current_context
+        );
+        main.current_context = None;
+    }
+
+    if let Err(e) = main.validate() {
+        return Err(KubeConfError::SyncError(format!(
+            "Synced kubeconfig would be invalid: {}",
+            e
+        )));
+    }
+
+    Ok((main, summaries))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAIN: &str = "
+apiVersion: v1
+kind: Config
+clusters:
+- name: prod
+  cluster:
+    server: https://prod.example:6443
+users:
+- name: me
+  user:
+    token: hand-added
+contexts:
+- name: mine
+  context:
+    cluster: prod
+    user: me
+";
+
+    fn fragment(cluster: &str, server: &str) -> KubeConfig {
+        serde_yaml::from_str(&format!(
+            "
+apiVersion: v1
+kind: Config
+clusters:
+- name: {cluster}
+  cluster:
+    server: {server}
+users:
+- name: {cluster}-user
+  user:
+    token: fragment
+contexts:
+- name: {cluster}-context
+  context:
+    cluster: {cluster}
+    user: {cluster}-user
+"
+        ))
+        .unwrap()
+    }
+
+    fn summary<'a>(summaries: &'a [(String, SyncSummary)], source: &str) -> &'a SyncSummary {
+        &summaries.iter().find(|(s, _)| s == source).unwrap().1
+    }
+
+    #[test]
+    fn adds_updates_and_removes_fragment_entries() {
+        let main = KubeConfig::from_yaml(MAIN).unwrap();
+        let fragments = vec![
+            ("/d/a.yaml".to_string(), fragment("a", "https://a:6443")),
+            ("/d/b.yaml".to_string(), fragment("b", "https://b:6443")),
+        ];
+        let (main, summaries) = sync_kubeconfig(main, fragments, "/d", &[]).unwrap();
+        assert_eq!(summary(&summaries, "/d/a.yaml").added, 3);
+        assert_eq!(main.contexts.len(), 3);
+
+        let fragments = vec![("/d/a.yaml".to_string(), fragment("a", "https://new-a:6443"))];
+        let (main, summaries) = sync_kubeconfig(main, fragments, "/d", &[]).unwrap();
+        assert_eq!(summary(&summaries, "/d/a.yaml").updated, 1);
+        assert_eq!(summary(&summaries, "/d/a.yaml").unchanged, 2);
+        assert_eq!(summary(&summaries, "/d/b.yaml").removed, 3);
+        assert_eq!(
+            main.get_cluster("a").unwrap().cluster.server,
+            "https://new-a:6443"
+        );
+        assert!(main.get_context("b-context").is_none());
+        // Hand-added entries are never touched.
+        assert!(main.get_context("mine").is_some());
+    }
+
+    #[test]
+    fn skips_contexts_of_clusters_shadowed_by_hand_added_ones() {
+        let main = KubeConfig::from_yaml(MAIN).unwrap();
+        let fragments = vec![(
+            "/d/prod.yaml".to_string(),
+            fragment("prod", "https://other-prod:6443"),
+        )];
+        let (main, summaries) = sync_kubeconfig(main, fragments, "/d", &[]).unwrap();
+
+        assert_eq!(
+            main.get_cluster("prod").unwrap().cluster.server,
+            "https://prod.example:6443"
+        );
+        assert!(main.get_context("prod-context").is_none());
+        // The cluster and the context pointing at it.
+        assert_eq!(summary(&summaries, "/d/prod.yaml").skipped, 2);
+    }
+
+    #[test]
+    fn keeps_entries_of_unreadable_fragments() {
+        let main = KubeConfig::from_yaml(MAIN).unwrap();
+        let fragments = vec![("/d/a.yaml".to_string(), fragment("a", "https://a:6443"))];
+        let (main, _) = sync_kubeconfig(main, fragments, "/d", &[]).unwrap();
+
+        let unreadable = vec!["/d/a.yaml".to_string()];
+        let (kept, _) = sync_kubeconfig(main.clone(), vec![], "/d", &unreadable).unwrap();
+        assert!(kept.get_context("a-context").is_some());
+
+        let (removed, _) = sync_kubeconfig(main, vec![], "/d", &[]).unwrap();
+        assert!(removed.get_context("a-context").is_none());
+    }
+}
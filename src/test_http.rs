@@ -0,0 +1,65 @@
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpListener;
+
+/// A request received by the test server.
+pub struct Request {
+    /// e.g.: `GET /version HTTP/1.1`
+    pub line: String,
+    pub authorization: Option<String>,
+    pub body: String,
+}
+
+/// Serves plain HTTP on a local port until the test ends and returns its base URL.
+/// Every request is answered with the status and JSON body `respond` returns for it, which also gets the base URL.
+pub fn serve<F>(respond: F) -> String
+where
+    F: Fn(&str, &Request) -> (u16, String) + Send + 'static,
+{
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let base_url = format!("http://{}", listener.local_addr().unwrap());
+    let server_url = base_url.clone();
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let Ok(mut stream) = stream else {
+                continue;
+            };
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            let mut authorization: Option<String> = None;
+            let mut content_length = 0;
+            loop {
+                let mut header = String::new();
+                reader.read_line(&mut header).unwrap();
+                let header = header.trim_end();
+                if header.is_empty() {
+                    break;
+                }
+                let (name, value) = header.split_once(": ").unwrap();
+                match name.to_lowercase().as_str() {
+                    "authorization" => authorization = Some(value.to_string()),
+                    "content-length" => content_length = value.parse().unwrap(),
+                    _ => {}
+                }
+            }
+            let mut body = vec![0; content_length];
+            reader.read_exact(&mut body).unwrap();
+
+            let request = Request {
+                line: line.trim_end().to_string(),
+                authorization,
+                body: String::from_utf8_lossy(&body).to_string(),
+            };
+            let (status, body) = respond(&server_url, &request);
+            write!(
+                stream,
+                "HTTP/1.1 {} X\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                status,
+                body.len(),
+                body
+            )
+            .unwrap();
+        }
+    });
+    base_url
+}
@@ -0,0 +1,521 @@
+use crate::flatten::write_private_file;
+use crate::kubeconfig::{KubeConfig, NamedContext};
+use crate::provenance::{
+    ProvenanceEntry, provenance_name, provenance_source, remove_provenance, set_provenance,
+};
+use crate::{KubeConfError, MergeEntityReport, MergeReport};
+use log::{info, warn};
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use serde_yaml::{Mapping, Value};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// The previously imported version of a source file, used as the common ancestor of a three-way merge.
+pub struct Baseline {
+    /// Absolute path of the source file.
+    pub source: String,
+    /// The source as it was last imported, `None` on the first import.
+    pub kubeconfig: Option<KubeConfig>,
+}
+
+/// Baselines are stored next to the main kubeconfig, one file per source. They are named after the
+/// source file and a hash of its canonical path, so sources with the same file name get their own baseline.
+/// e.g.: ~/.kube/kubeconf/baselines/vendor.yaml-5d41402abc4b2a76
+pub fn baseline_path(main_config: &Path, source: &str) -> PathBuf {
+    let hash: String = Sha256::digest(source.as_bytes())[..8]
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect();
+    let file_name = Path::new(source)
+        .file_name()
+        .map(|n| n.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let mut path = main_config
+        .parent()
+        .map(|p| p.to_path_buf())
+        .unwrap_or_default();
+    path.push("kubeconf");
+    path.push("baselines");
+    path.push(format!("{}-{}", file_name, hash));
+    path
+}
+
+/// Loads the last imported version of the given source, if any.
+pub fn load_baseline(main_config: &Path, source: &str) -> Result<Baseline, KubeConfError> {
+    let path = baseline_path(main_config, source);
+    if !path.exists() {
+        info!(
+            "No baseline found for {}, this is the first three-way import.",
+            source
+        );
+        return Ok(Baseline {
+            source: source.to_string(),
+            kubeconfig: None,
+        });
+    }
+
+    match KubeConfig::from_file(&path) {
+        Ok(kubeconfig) => Ok(Baseline {
+            source: source.to_string(),
+            kubeconfig: Some(kubeconfig),
+        }),
+        Err(e) => Err(KubeConfError::MergeError(format!(
+            "Baseline {} for {} could not be loaded: {}",
+            path.display(),
+            source,
+            e
+        ))),
+    }
+}
+
+/// The new baseline of a source: the imported kubeconfig without the entries the merge did not import
+/// because an entry with the same name was added by hand. Those have no history to merge against later.
+pub fn imported_baseline(
+    other: KubeConfig,
+    previous: &Baseline,
+    report: &MergeReport,
+) -> KubeConfig {
+    let mut other = other;
+    let previous = previous.kubeconfig.as_ref();
+    other.clusters.retain(|c| {
+        !report.clusters.skipped.contains(&c.name)
+            || previous.is_some_and(|p| p.get_cluster(&c.name).is_some())
+    });
+    other.users.retain(|u| {
+        !report.users.skipped.contains(&u.name)
+            || previous.is_some_and(|p| p.get_user(&u.name).is_some())
+    });
+    other.contexts.retain(|c| {
+        !report.contexts.skipped.contains(&c.name)
+            || previous.is_some_and(|p| p.get_context(&c.name).is_some())
+    });
+    other
+}
+
+/// Stores the given kubeconfig yaml as the new baseline of the source, readable by the owner only.
+pub fn store_baseline(main_config: &Path, source: &str, yaml: &str) -> Result<(), KubeConfError> {
+    let path = baseline_path(main_config, source);
+    if let Some(parent) = path.parent()
+        && let Err(e) = fs::create_dir_all(parent)
+    {
+        return Err(KubeConfError::MergeError(format!(
+            "Creating baseline directory {} failed: {}",
+            parent.display(),
+            e
+        )));
+    }
+
+    match write_private_file(&path, yaml.as_bytes()) {
+        Ok(()) => Ok(()),
+        Err(e) => Err(KubeConfError::MergeError(format!(
+            "Writing baseline {} failed: {}",
+            path.display(),
+            e
+        ))),
+    }
+}
+
+/// Three-way merges a single value. Mappings are merged key by key, everything else is compared as a whole.
+/// On a true conflict the local value is kept and the path is recorded.
+fn merge_values(
+    path: &str,
+    base: Option<&Value>,
+    local: Option<&Value>,
+    other: Option<&Value>,
+    conflicts: &mut Vec<String>,
+) -> Option<Value> {
+    if local == other || other == base {
+        return local.cloned();
+    }
+    if local == base {
+        return other.cloned();
+    }
+
+    if let (Some(Value::Mapping(local_map)), Some(Value::Mapping(other_map))) = (local, other) {
+        let empty = Mapping::new();
+        let base_map = match base {
+            Some(Value::Mapping(base_map)) => base_map,
+            _ => &empty,
+        };
+
+        let mut merged = Mapping::new();
+        let mut keys: Vec<&Value> = local_map.keys().collect();
+        for key in other_map.keys() {
+            if !keys.contains(&key) {
+                keys.push(key);
+            }
+        }
+        for key in keys {
+            let key_path = match key.as_str() {
+                Some(k) if path.is_empty() => k.to_string(),
+                Some(k) => format!("{}.{}", path, k),
+                None => format!("{}.{:?}", path, key),
+            };
+            if let Some(value) = merge_values(
+                &key_path,
+                base_map.get(key),
+                local_map.get(key),
+                other_map.get(key),
+                conflicts,
+            ) {
+                merged.insert(key.clone(), value);
+            }
+        }
+        return Some(Value::Mapping(merged));
+    }
+
+    conflicts.push(path.to_string());
+    local.cloned()
+}
+
+fn to_value<T: Serialize>(entry: &T) -> Result<Value, KubeConfError> {
+    serde_yaml::to_value(entry).map_err(|e| KubeConfError::MergeError(e.to_string()))
+}
+
+fn from_value<T: DeserializeOwned>(value: Value) -> Result<T, KubeConfError> {
+    serde_yaml::from_value(value).map_err(|e| KubeConfError::MergeError(e.to_string()))
+}
+
+/// Local entry as a value without the provenance extension, which never exists in base or other.
+fn local_value<T: ProvenanceEntry + DeserializeOwned>(local: &T) -> Result<Value, KubeConfError> {
+    let mut local: T = from_value(to_value(local)?)?;
+    remove_provenance(&mut local);
+    to_value(&local)
+}
+
+/// Entries imported from `source` that were renamed locally, their upstream name mapped to the local one.
+pub fn local_renames<T: ProvenanceEntry>(main: &[T], source: &str) -> HashMap<String, String> {
+    main.iter()
+        .filter(|e| provenance_source(*e).as_deref() == Some(source))
+        .filter_map(|e| {
+            let upstream = provenance_name(e)?;
+            (upstream != e.name()).then(|| (upstream, e.name().to_string()))
+        })
+        .collect()
+}
+
+/// Points upstream contexts at the local names of clusters and users that were renamed locally,
+/// otherwise new upstream contexts would reference clusters and users that no longer exist in main.
+pub fn apply_local_renames(
+    contexts: &mut [NamedContext],
+    cluster_renames: &HashMap<String, String>,
+    user_renames: &HashMap<String, String>,
+) {
+    for context in contexts {
+        if let Some(cluster) = cluster_renames.get(&context.context.cluster) {
+            context.context.cluster = cluster.clone();
+        }
+        if let Some(user) = user_renames.get(&context.context.user) {
+            context.context.user = user.clone();
+        }
+    }
+}
+
+/// Three-way merges the entries of one kind that were imported from `source` before.
+/// Entries of `other` without a baseline entry are returned (with provenance) to be merged the regular way.
+pub fn merge_entries<T: ProvenanceEntry + DeserializeOwned>(
+    kind: &str,
+    main: &mut Vec<T>,
+    other: Vec<T>,
+    base: &[T],
+    source: &str,
+    report: &mut MergeEntityReport,
+) -> Result<Vec<T>, KubeConfError> {
+    let mut remaining: Vec<T> = vec![];
+    let other_names: Vec<String> = other.iter().map(|e| e.name().to_string()).collect();
+
+    for mut other_entry in other {
+        let name = other_entry.name().to_string();
+        let base_entry = base.iter().find(|e| e.name() == name);
+        let local_index = main.iter().position(|e| {
+            provenance_source(e).as_deref() == Some(source)
+                && provenance_name(e) == Some(name.clone())
+        });
+
+        match (base_entry, local_index) {
+            (Some(base_entry), Some(local_index)) => {
+                let base_value = to_value(base_entry)?;
+                let local_value = local_value(&main[local_index])?;
+                let other_value = to_value(&other_entry)?;
+
+                let mut conflicts: Vec<String> = vec![];
+                let merged_value = merge_values(
+                    "",
+                    Some(&base_value),
+                    Some(&local_value),
+                    Some(&other_value),
+                    &mut conflicts,
+                );
+                for conflict in conflicts {
+                    warn!(
+                        "Conflict in {} `{}` at `{}`: changed locally and upstream, keeping the local value.",
+                        kind,
+                        main[local_index].name(),
+                        conflict
+                    );
+                    report
+                        .conflicts
+                        .push(format!("{}: {}", main[local_index].name(), conflict));
+                }
+
+                if let Some(merged_value) = merged_value
+                    && merged_value != local_value
+                {
+                    let mut merged: T = from_value(merged_value)?;
+                    set_provenance(&mut merged, source, &name);
+                    info!("Applying upstream changes to {} `{}`.", kind, merged.name());
+                    report.updated.push(merged.name().to_string());
+                    main[local_index] = merged;
+                }
+            }
+            (Some(base_entry), None) => {
+                // Deleted locally.
+                if to_value(base_entry)? == to_value(&other_entry)? {
+                    info!(
+                        "Not re-adding {} `{}` as it was deleted locally.",
+                        kind, name
+                    );
+                    report.skipped.push(name);
+                } else {
+                    warn!(
+                        "Conflict in {} `{}`: deleted locally but changed upstream, keeping it deleted.",
+                        kind, name
+                    );
+                    report
+                        .conflicts
+                        .push(format!("{}: deleted locally but changed upstream", name));
+                }
+            }
+            (None, _) => {
+                set_provenance(&mut other_entry, source, &name);
+                remaining.push(other_entry);
+            }
+        }
+    }
+
+    // Entries removed upstream are removed locally unless they were edited.
+    for base_entry in base {
+        if other_names.iter().any(|n| n == base_entry.name()) {
+            continue;
+        }
+        let local_index = main.iter().position(|e| {
+            provenance_source(e).as_deref() == Some(source)
+                && provenance_name(e).as_deref() == Some(base_entry.name())
+        });
+        if let Some(local_index) = local_index {
+            if local_value(&main[local_index])? == to_value(base_entry)? {
+                info!(
+                    "Removing {} `{}` as it was removed upstream.",
+                    kind,
+                    main[local_index].name()
+                );
+                report
+                    .removed
+                    .push(main.remove(local_index).name().to_string());
+            } else {
+                warn!(
+                    "Conflict in {} `{}`: changed locally but removed upstream, keeping it.",
+                    kind,
+                    main[local_index].name()
+                );
+                report.conflicts.push(format!(
+                    "{}: changed locally but removed upstream",
+                    main[local_index].name()
+                ));
+            }
+        }
+    }
+
+    Ok(remaining)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kubeconfig::NamedCluster;
+
+    fn yaml(s: &str) -> Value {
+        serde_yaml::from_str(s).unwrap()
+    }
+
+    fn cluster(name: &str, server: &str) -> NamedCluster {
+        serde_yaml::from_str(&format!("{{name: {name}, cluster: {{server: {server}}}}}")).unwrap()
+    }
+
+    fn imported(name: &str, server: &str) -> NamedCluster {
+        let mut cluster = cluster(name, server);
+        set_provenance(&mut cluster, "/vendor.yaml", name);
+        cluster
+    }
+
+    #[test]
+    fn merges_changes_of_both_sides_and_keeps_local_value_on_conflict() {
+        let base = yaml("{server: a, proxy-url: p, tls-server-name: t}");
+        let local = yaml("{server: a, proxy-url: local, tls-server-name: local}");
+        let other = yaml("{server: b, proxy-url: p, tls-server-name: other}");
+        let mut conflicts: Vec<String> = vec![];
+        let merged = merge_values(
+            "cluster",
+            Some(&base),
+            Some(&local),
+            Some(&other),
+            &mut conflicts,
+        );
+        assert_eq!(
+            merged,
+            Some(yaml(
+                "{server: b, proxy-url: local, tls-server-name: local}"
+            ))
+        );
+        assert_eq!(conflicts, vec!["cluster.tls-server-name"]);
+    }
+
+    #[test]
+    fn keeps_fields_deleted_on_one_side_deleted() {
+        let base = yaml("{server: a, proxy-url: p}");
+        let local = yaml("{server: a}");
+        let other = yaml("{server: a, proxy-url: p}");
+        let mut conflicts: Vec<String> = vec![];
+        let merged = merge_values("", Some(&base), Some(&local), Some(&other), &mut conflicts);
+        assert_eq!(merged, Some(yaml("{server: a}")));
+        assert!(conflicts.is_empty());
+
+        // Deleted locally but changed upstream.
+        let other = yaml("{server: a, proxy-url: q}");
+        let merged = merge_values("", Some(&base), Some(&local), Some(&other), &mut conflicts);
+        assert_eq!(merged, Some(yaml("{server: a}")));
+        assert_eq!(conflicts, vec!["proxy-url"]);
+    }
+
+    #[test]
+    fn does_not_re_add_entries_deleted_locally() {
+        let base = vec![cluster("a", "https://a"), cluster("b", "https://b")];
+        let other = vec![cluster("a", "https://a"), cluster("b", "https://new-b")];
+        let mut main: Vec<NamedCluster> = vec![];
+        let mut report = MergeEntityReport::default();
+        let remaining = merge_entries(
+            "cluster",
+            &mut main,
+            other,
+            &base,
+            "/vendor.yaml",
+            &mut report,
+        )
+        .unwrap();
+        assert!(remaining.is_empty());
+        assert!(main.is_empty());
+        assert_eq!(report.skipped, vec!["a"]);
+        assert_eq!(
+            report.conflicts,
+            vec!["b: deleted locally but changed upstream"]
+        );
+    }
+
+    #[test]
+    fn removes_entries_removed_upstream_unless_edited() {
+        let base = vec![cluster("a", "https://a"), cluster("b", "https://b")];
+        let mut main = vec![imported("a", "https://a"), imported("b", "https://local-b")];
+        let mut report = MergeEntityReport::default();
+        merge_entries(
+            "cluster",
+            &mut main,
+            vec![],
+            &base,
+            "/vendor.yaml",
+            &mut report,
+        )
+        .unwrap();
+        assert_eq!(report.removed, vec!["a"]);
+        assert_eq!(main.len(), 1);
+        assert_eq!(main[0].cluster.server, "https://local-b");
+        assert_eq!(
+            report.conflicts,
+            vec!["b: changed locally but removed upstream"]
+        );
+    }
+
+    #[test]
+    fn points_new_upstream_contexts_at_locally_renamed_clusters() {
+        let base: KubeConfig = serde_yaml::from_str(
+            "
+apiVersion: v1
+kind: Config
+clusters: [{name: prod, cluster: {server: https://prod}}]
+users: [{name: admin, user: {token: t}}]
+contexts: [{name: prod, context: {cluster: prod, user: admin}}]
+",
+        )
+        .unwrap();
+        let mut main = base.clone();
+        for cluster in &mut main.clusters {
+            set_provenance(cluster, "/vendor.yaml", "prod");
+        }
+        for user in &mut main.users {
+            set_provenance(user, "/vendor.yaml", "admin");
+        }
+        for context in &mut main.contexts {
+            set_provenance(context, "/vendor.yaml", "prod");
+        }
+        // Renamed locally, e.g. with `kubeconf rename`.
+        main.clusters[0].name = "vendor-prod".to_string();
+        main.contexts[0].context.cluster = "vendor-prod".to_string();
+
+        let mut other = base.clone();
+        let mut readonly = other.contexts[0].clone();
+        readonly.name = "prod-readonly".to_string();
+        other.contexts.push(readonly);
+        let baseline = Baseline {
+            source: "/vendor.yaml".to_string(),
+            kubeconfig: Some(base),
+        };
+
+        let (merged, report) = crate::merge_kubeconfigs(
+            main,
+            other,
+            crate::OnConflict::Skip,
+            false,
+            None,
+            Some(&baseline),
+            None,
+        )
+        .unwrap();
+        assert_eq!(report.contexts.added, vec!["prod-readonly"]);
+        assert_eq!(
+            merged.get_context("prod-readonly").unwrap().context.cluster,
+            "vendor-prod"
+        );
+        // The renamed cluster is not imported again under its upstream name.
+        assert!(merged.get_cluster("prod").is_none());
+        assert!(report.contexts.updated.is_empty());
+    }
+
+    #[test]
+    fn baseline_leaves_out_entries_never_imported() {
+        let other: KubeConfig = serde_yaml::from_str(
+            "{apiVersion: v1, kind: Config, clusters: [{name: a, cluster: {server: https://a}}, {name: b, cluster: {server: https://b}}]}",
+        )
+        .unwrap();
+        let previous = Baseline {
+            source: "/vendor.yaml".to_string(),
+            kubeconfig: None,
+        };
+        let mut report = MergeReport::default();
+        report.clusters.added.push("a".to_string());
+        report.clusters.skipped.push("b".to_string());
+        let baseline = imported_baseline(other, &previous, &report);
+        assert_eq!(baseline.clusters.len(), 1);
+        assert!(baseline.get_cluster("a").is_some());
+    }
+
+    #[test]
+    fn baseline_path_depends_on_the_whole_source_path() {
+        let main = Path::new("/home/me/.kube/config");
+        let a = baseline_path(main, "/a/vendor.yaml");
+        let b = baseline_path(main, "/b/vendor.yaml");
+        assert_ne!(a, b);
+        assert!(a.starts_with("/home/me/.kube/kubeconf/baselines"));
+    }
+}
@@ -0,0 +1,553 @@
+use crate::KubeConfError;
+use crate::exec::{EXEC_INFO_ENV, ExecCredentialStatus};
+use crate::flatten::write_private_file;
+use crate::kubeconfig::{ExecConfig, InteractiveMode, KubeConfig, NamedUser};
+use age::secrecy::SecretString;
+use base64::Engine;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{IsTerminal, Read, Write};
+use std::path::{Path, PathBuf};
+
+/// Environment variable holding the vault passphrase, so `kubeconf credential` can run without a terminal.
+pub const PASSPHRASE_ENV: &str = "KUBECONF_VAULT_PASSPHRASE";
+
+const EXEC_API_VERSION: &str = "client.authentication.k8s.io/v1";
+
+/// The command the exec blocks of sealed users run.
+const CREDENTIAL_COMMAND: &str = "kubeconf";
+
+/// The secrets of one user, as base64 encoded in the kubeconfig.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct SealedCredential {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub token: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_certificate_data: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_key_data: Option<String>,
+}
+
+/// The decrypted contents of the vault file.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Vault {
+    /// Keyed by the name of the user the credential was taken from.
+    credentials: BTreeMap<String, SealedCredential>,
+}
+
+/// How the vault file is encrypted.
+pub enum VaultKey {
+    Passphrase(SecretString),
+    /// An age identity file. The vault is encrypted to all recipients in it.
+    IdentityFile(PathBuf),
+}
+
+/// The vault lives next to the main kubeconfig.
+/// e.g.: ~/.kube/kubeconf/vault.age
+pub fn vault_path(main_config: &Path) -> PathBuf {
+    let mut path = main_config
+        .parent()
+        .map(|p| p.to_path_buf())
+        .unwrap_or_default();
+    path.push("kubeconf");
+    path.push("vault.age");
+    path
+}
+
+/// Reads the passphrase from `KUBECONF_VAULT_PASSPHRASE` or asks for it on the terminal.
+/// `confirm` asks twice, for vaults that are about to be created.
+pub fn read_passphrase(confirm: bool) -> Result<SecretString, KubeConfError> {
+    if let Ok(passphrase) = std::env::var(PASSPHRASE_ENV) {
+        return Ok(SecretString::from(passphrase));
+    }
+    let prompt_error = |e: std::io::Error| {
+        KubeConfError::VaultError(format!(
+            "Reading the vault passphrase failed: {}. Set {} to pass it without a terminal.",
+            e, PASSPHRASE_ENV
+        ))
+    };
+    let passphrase = rpassword::prompt_password("Vault passphrase: ").map_err(prompt_error)?;
+    if passphrase.is_empty() {
+        return Err(KubeConfError::VaultError(
+            "The vault passphrase can not be empty.".to_string(),
+        ));
+    }
+    if confirm {
+        let repeated =
+            rpassword::prompt_password("Repeat vault passphrase: ").map_err(prompt_error)?;
+        if repeated != passphrase {
+            return Err(KubeConfError::VaultError(
+                "The passphrases do not match.".to_string(),
+            ));
+        }
+    }
+    Ok(SecretString::from(passphrase))
+}
+
+fn identity_file(path: &Path) -> Result<age::IdentityFile<age::NoCallbacks>, KubeConfError> {
+    age::IdentityFile::from_file(path.display().to_string()).map_err(|e| {
+        KubeConfError::VaultError(format!(
+            "Reading identity file {} failed: {}",
+            path.display(),
+            e
+        ))
+    })
+}
+
+fn encrypt(key: &VaultKey, plaintext: &[u8]) -> Result<String, KubeConfError> {
+    let encrypt_error = |e: age::EncryptError| {
+        KubeConfError::VaultError(format!("Encrypting the vault failed: {}", e))
+    };
+    match key {
+        VaultKey::Passphrase(passphrase) => {
+            age::encrypt_and_armor(&age::scrypt::Recipient::new(passphrase.clone()), plaintext)
+                .map_err(encrypt_error)
+        }
+        VaultKey::IdentityFile(path) => {
+            let recipients = identity_file(path)?
+                .to_recipients()
+                .map_err(encrypt_error)?;
+            let encryptor = age::Encryptor::with_recipients(
+                recipients.iter().map(|r| r.as_ref() as &dyn age::Recipient),
+            )
+            .map_err(encrypt_error)?;
+
+            let io_error = |e: std::io::Error| {
+                KubeConfError::VaultError(format!("Encrypting the vault failed: {}", e))
+            };
+            let mut ciphertext = vec![];
+            let mut writer = encryptor
+                .wrap_output(
+                    age::armor::ArmoredWriter::wrap_output(
+                        &mut ciphertext,
+                        age::armor::Format::AsciiArmor,
+                    )
+                    .map_err(io_error)?,
+                )
+                .map_err(io_error)?;
+            writer.write_all(plaintext).map_err(io_error)?;
+            writer
+                .finish()
+                .and_then(|armor| armor.finish())
+                .map_err(io_error)?;
+            Ok(String::from_utf8_lossy(&ciphertext).to_string())
+        }
+    }
+}
+
+fn decrypt(key: &VaultKey, ciphertext: &[u8]) -> Result<Vec<u8>, KubeConfError> {
+    let decrypt_error = |e: age::DecryptError| {
+        KubeConfError::VaultError(format!("Decrypting the vault failed: {}", e))
+    };
+    match key {
+        VaultKey::Passphrase(passphrase) => {
+            age::decrypt(&age::scrypt::Identity::new(passphrase.clone()), ciphertext)
+                .map_err(decrypt_error)
+        }
+        VaultKey::IdentityFile(path) => {
+            let identities = identity_file(path)?
+                .into_identities()
+                .map_err(decrypt_error)?;
+            let decryptor =
+                age::Decryptor::new_buffered(age::armor::ArmoredReader::new(ciphertext))
+                    .map_err(decrypt_error)?;
+            let mut reader = decryptor
+                .decrypt(identities.iter().map(|i| i.as_ref() as &dyn age::Identity))
+                .map_err(decrypt_error)?;
+            let mut plaintext = vec![];
+            reader.read_to_end(&mut plaintext).map_err(|e| {
+                KubeConfError::VaultError(format!("Decrypting the vault failed: {}", e))
+            })?;
+            Ok(plaintext)
+        }
+    }
+}
+
+fn load_vault(path: &Path, key: &VaultKey) -> Result<Vault, KubeConfError> {
+    let ciphertext = match fs::read(path) {
+        Ok(ciphertext) => ciphertext,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vault::default()),
+        Err(e) => {
+            return Err(KubeConfError::VaultError(format!(
+                "Reading vault {} failed: {}",
+                path.display(),
+                e
+            )));
+        }
+    };
+    let plaintext = decrypt(key, &ciphertext)?;
+    serde_json::from_slice(&plaintext).map_err(|e| {
+        KubeConfError::VaultError(format!("Vault {} is invalid: {}", path.display(), e))
+    })
+}
+
+fn store_vault(path: &Path, key: &VaultKey, vault: &Vault) -> Result<(), KubeConfError> {
+    if vault.credentials.is_empty() {
+        info!("Removing the empty vault {}", path.display());
+        return fs::remove_file(path).or_else(|e| match e.kind() {
+            std::io::ErrorKind::NotFound => Ok(()),
+            _ => Err(KubeConfError::VaultError(format!(
+                "Removing vault {} failed: {}",
+                path.display(),
+                e
+            ))),
+        });
+    }
+    if let Some(parent) = path.parent()
+        && let Err(e) = fs::create_dir_all(parent)
+    {
+        return Err(KubeConfError::VaultError(format!(
+            "Creating vault directory {} failed: {}",
+            parent.display(),
+            e
+        )));
+    }
+    let plaintext = serde_json::to_vec(vault)
+        .map_err(|e| KubeConfError::VaultError(format!("Serializing the vault failed: {}", e)))?;
+    let ciphertext = encrypt(key, &plaintext)?;
+    write_private_file(path, ciphertext.as_bytes()).map_err(|e| {
+        KubeConfError::VaultError(format!("Writing vault {} failed: {}", path.display(), e))
+    })
+}
+
+/// The exec block a sealed user gets. Paths are absolute as kubectl runs the plugin from anywhere.
+fn credential_exec(name: &str, vault_path: &Path, identity: Option<&Path>) -> ExecConfig {
+    let mut args = vec![
+        "credential".to_string(),
+        name.to_string(),
+        "--vault".to_string(),
+        vault_path.display().to_string(),
+    ];
+    if let Some(identity) = identity {
+        args.extend(["--identity".to_string(), identity.display().to_string()]);
+    }
+    ExecConfig {
+        command: CREDENTIAL_COMMAND.to_string(),
+        args: Some(args),
+        env: None,
+        api_version: Some(EXEC_API_VERSION.to_string()),
+        install_hint: Some(
+            "Install kubeconf to unlock the credentials sealed in its vault.".to_string(),
+        ),
+        provide_cluster_info: Some(false),
+        // A passphrase has to be typed unless it is in the environment.
+        interactive_mode: Some(match identity {
+            Some(_) => InteractiveMode::Never,
+            None => InteractiveMode::IfAvailable,
+        }),
+    }
+}
+
+/// The name of the vault entry if the user runs `kubeconf credential`.
+fn sealed_name(user: &NamedUser) -> Option<String> {
+    let exec = user.user.exec.as_ref()?;
+    let args = exec.args.as_ref()?;
+    match (
+        exec.command.as_str(),
+        args.first().map(|a| a.as_str()),
+        args.get(1),
+    ) {
+        (CREDENTIAL_COMMAND, Some("credential"), Some(name)) => Some(name.clone()),
+        _ => None,
+    }
+}
+
+/// Takes the inline secrets out of the user. `None` if there is nothing an exec plugin could return.
+fn take_secrets(user: &mut NamedUser) -> Option<SealedCredential> {
+    if user.user.exec.is_some() || user.user.auth_provider.is_some() {
+        warn!(
+            "Not sealing user `{}`, it already uses an exec plugin or auth-provider.",
+            user.name
+        );
+        return None;
+    }
+    if user.user.password.is_some() {
+        warn!(
+            "Not sealing the password of user `{}`, exec plugins can not return basic auth credentials.",
+            user.name
+        );
+    }
+
+    let mut sealed = SealedCredential::default();
+    if user.user.client_key_data.is_some() {
+        // kubectl refuses client certificates from both the kubeconfig and the plugin, so both move.
+        if user.user.client_certificate_data.is_some() {
+            sealed.client_certificate_data = user.user.client_certificate_data.take();
+            sealed.client_key_data = user.user.client_key_data.take();
+        } else {
+            warn!(
+                "Not sealing client-key-data of user `{}`, its certificate is not embedded as client-certificate-data.",
+                user.name
+            );
+        }
+    }
+    // Without a certificate the token is all the plugin returns, which is fine.
+    sealed.token = user.user.token.take();
+
+    if sealed.token.is_none() && sealed.client_key_data.is_none() {
+        return None;
+    }
+    Some(sealed)
+}
+
+/// Moves the inline token and client key of the users into the vault and replaces them with an exec block
+/// running `kubeconf credential`. Only the given user is sealed if set. Returns the number of sealed users.
+/// With `dry_run` only the kubeconfig is changed and the vault is not written.
+pub fn seal(
+    kubeconfig: &mut KubeConfig,
+    user: Option<&str>,
+    vault_path: &Path,
+    key: &VaultKey,
+    dry_run: bool,
+) -> Result<usize, KubeConfError> {
+    let mut vault = load_vault(vault_path, key)?;
+    let identity = match key {
+        VaultKey::IdentityFile(path) => Some(path.as_path()),
+        VaultKey::Passphrase(_) => None,
+    };
+
+    let mut sealed_users = 0;
+    for named_user in kubeconfig.users.iter_mut() {
+        if user.is_some_and(|u| u != named_user.name) {
+            continue;
+        }
+        let Some(sealed) = take_secrets(named_user) else {
+            continue;
+        };
+        if vault.credentials.contains_key(&named_user.name) {
+            warn!(
+                "Replacing the credential of user `{}` already in the vault.",
+                named_user.name
+            );
+        }
+        info!("Sealing user `{}`", named_user.name);
+        named_user.user.exec = Some(credential_exec(&named_user.name, vault_path, identity));
+        vault.credentials.insert(named_user.name.clone(), sealed);
+        sealed_users += 1;
+    }
+
+    if sealed_users > 0 && !dry_run {
+        store_vault(vault_path, key, &vault)?;
+    }
+    Ok(sealed_users)
+}
+
+/// Puts the credentials of users sealed with `seal` back into the kubeconfig. Only the given user is unsealed if set.
+/// The vault is left untouched, call `forget` once the kubeconfig is written. Returns the unsealed credential names.
+pub fn unseal(
+    kubeconfig: &mut KubeConfig,
+    user: Option<&str>,
+    vault_path: &Path,
+    key: &VaultKey,
+) -> Result<Vec<String>, KubeConfError> {
+    let vault = load_vault(vault_path, key)?;
+
+    let mut unsealed: Vec<String> = vec![];
+    for named_user in kubeconfig.users.iter_mut() {
+        if user.is_some_and(|u| u != named_user.name) {
+            continue;
+        }
+        let Some(name) = sealed_name(named_user) else {
+            continue;
+        };
+        let Some(sealed) = vault.credentials.get(&name).cloned() else {
+            return Err(KubeConfError::VaultError(format!(
+                "The credential `{}` of user `{}` is not in vault {}.",
+                name,
+                named_user.name,
+                vault_path.display()
+            )));
+        };
+        info!("Unsealing user `{}`", named_user.name);
+        named_user.user.exec = None;
+        named_user.user.token = sealed.token;
+        named_user.user.client_certificate_data = sealed.client_certificate_data;
+        named_user.user.client_key_data = sealed.client_key_data;
+        unsealed.push(name);
+    }
+    Ok(unsealed)
+}
+
+/// Removes the given credentials from the vault, and the vault itself once it is empty.
+pub fn forget(vault_path: &Path, key: &VaultKey, names: &[String]) -> Result<(), KubeConfError> {
+    let mut vault = load_vault(vault_path, key)?;
+    for name in names {
+        vault.credentials.remove(name);
+    }
+    store_vault(vault_path, key, &vault)
+}
+
+fn decode_pem(data: &str, field: &str) -> Result<String, KubeConfError> {
+    base64::engine::general_purpose::STANDARD
+        .decode(data)
+        .ok()
+        .and_then(|pem| String::from_utf8(pem).ok())
+        .ok_or_else(|| {
+            KubeConfError::VaultError(format!("The sealed {} is not valid base64 PEM.", field))
+        })
+}
+
+/// Prints the sealed credential as ExecCredential, answering the request kubectl passes in `KUBERNETES_EXEC_INFO`.
+/// The passphrase is only asked for if the request allows interaction.
+pub fn print_credential(
+    name: &str,
+    vault_path: &Path,
+    identity: Option<&Path>,
+) -> Result<(), KubeConfError> {
+    let request: Option<serde_json::Value> = std::env::var(EXEC_INFO_ENV)
+        .ok()
+        .and_then(|info| serde_json::from_str(&info).ok());
+    let (api_version, interactive) = match &request {
+        Some(request) => (
+            request["apiVersion"]
+                .as_str()
+                .unwrap_or(EXEC_API_VERSION)
+                .to_string(),
+            request["spec"]["interactive"].as_bool().unwrap_or(false),
+        ),
+        None => (EXEC_API_VERSION.to_string(), std::io::stdin().is_terminal()),
+    };
+
+    let key = match identity {
+        Some(identity) => VaultKey::IdentityFile(identity.to_path_buf()),
+        None if interactive || std::env::var(PASSPHRASE_ENV).is_ok() => {
+            VaultKey::Passphrase(read_passphrase(false)?)
+        }
+        None => {
+            return Err(KubeConfError::VaultError(format!(
+                "The vault passphrase can not be asked for without a terminal, set {}.",
+                PASSPHRASE_ENV
+            )));
+        }
+    };
+    println!("{}", exec_credential(name, vault_path, &key, &api_version)?);
+    Ok(())
+}
+
+/// The sealed credential as ExecCredential of the given api version.
+fn exec_credential(
+    name: &str,
+    vault_path: &Path,
+    key: &VaultKey,
+    api_version: &str,
+) -> Result<serde_json::Value, KubeConfError> {
+    let vault = load_vault(vault_path, key)?;
+    let Some(sealed) = vault.credentials.get(name) else {
+        return Err(KubeConfError::VaultError(format!(
+            "The credential `{}` is not in vault {}.",
+            name,
+            vault_path.display()
+        )));
+    };
+
+    let status = ExecCredentialStatus {
+        token: sealed.token.clone(),
+        client_certificate_data: match &sealed.client_certificate_data {
+            Some(data) => Some(decode_pem(data, "client-certificate-data")?),
+            None => None,
+        },
+        client_key_data: match &sealed.client_key_data {
+            Some(data) => Some(decode_pem(data, "client-key-data")?),
+            None => None,
+        },
+        expiration_timestamp: None,
+    };
+    Ok(serde_json::json!({
+        "apiVersion": api_version,
+        "kind": "ExecCredential",
+        "status": status,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use age::secrecy::ExposeSecret;
+
+    const KUBECONFIG: &str = "
+apiVersion: v1
+kind: Config
+clusters:
+- name: prod
+  cluster:
+    server: https://prod.example:6443
+users:
+- name: admin
+  user:
+    token: secret-token
+contexts:
+- name: prod
+  context:
+    cluster: prod
+    user: admin
+";
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("kubeconf-vault-{}-{}", name, std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Seals, reads the credential like kubectl would and unseals again.
+    fn round_trip(key: &VaultKey, dir: &Path) {
+        let vault_path = dir.join("vault.age");
+        let mut kubeconfig = KubeConfig::from_yaml(KUBECONFIG).unwrap();
+
+        assert_eq!(
+            seal(&mut kubeconfig, None, &vault_path, key, false).unwrap(),
+            1
+        );
+        let user = &kubeconfig.get_user("admin").unwrap().user;
+        assert_eq!(user.token, None);
+        assert!(user.exec.is_some());
+        assert!(
+            !fs::read_to_string(&vault_path)
+                .unwrap()
+                .contains("secret-token")
+        );
+
+        let credential = exec_credential("admin", &vault_path, key, EXEC_API_VERSION).unwrap();
+        assert_eq!(credential["apiVersion"], EXEC_API_VERSION);
+        assert_eq!(credential["kind"], "ExecCredential");
+        assert_eq!(credential["status"]["token"], "secret-token");
+
+        let unsealed = unseal(&mut kubeconfig, None, &vault_path, key).unwrap();
+        assert_eq!(unsealed, vec!["admin"]);
+        let user = &kubeconfig.get_user("admin").unwrap().user;
+        assert_eq!(user.token.as_deref(), Some("secret-token"));
+        assert!(user.exec.is_none());
+        // Still in the vault until the kubeconfig is written.
+        assert!(vault_path.exists());
+
+        forget(&vault_path, key, &unsealed).unwrap();
+        assert!(!vault_path.exists());
+    }
+
+    #[test]
+    fn round_trips_with_an_identity_file() {
+        let dir = temp_dir("identity");
+        let identity_path = dir.join("identity.txt");
+        let identity = age::x25519::Identity::generate();
+        fs::write(&identity_path, identity.to_string().expose_secret()).unwrap();
+
+        round_trip(&VaultKey::IdentityFile(identity_path), &dir);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn round_trips_with_a_passphrase() {
+        let dir = temp_dir("passphrase");
+        let key = VaultKey::Passphrase(SecretString::from(
+            "correct horse battery staple".to_string(),
+        ));
+
+        round_trip(&key, &dir);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
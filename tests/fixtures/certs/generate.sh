@@ -0,0 +1,30 @@
+#!/bin/sh
+# Regenerates the test certificates. Dates are fixed so the tests do not depend on when this ran.
+set -e
+cd "$(dirname "$0")"
+
+openssl req -x509 -newkey rsa:2048 -nodes -keyout ca.key -out ca.crt \
+  -subj "/CN=kubeconf-test-ca" -not_before 20250101000000Z -not_after 21250101000000Z \
+  -addext basicConstraints=critical,CA:TRUE -addext keyUsage=critical,keyCertSign,cRLSign
+
+openssl req -x509 -newkey rsa:2048 -nodes -keyout other-ca.key -out other-ca.crt \
+  -subj "/CN=kubeconf-other-ca" -not_before 20250101000000Z -not_after 21250101000000Z \
+  -addext basicConstraints=critical,CA:TRUE -addext keyUsage=critical,keyCertSign,cRLSign
+
+openssl req -new -newkey ec -pkeyopt ec_paramgen_curve:P-256 -nodes -keyout server.key \
+  -subj "/CN=api.example.com" -out server.csr
+openssl x509 -req -in server.csr -CA ca.crt -CAkey ca.key -out server.crt \
+  -not_before 20250101000000Z -not_after 21250101000000Z \
+  -extfile /dev/stdin <<EXT
+basicConstraints=critical,CA:FALSE
+subjectAltName=DNS:api.example.com,DNS:*.apps.example.com,IP:10.0.0.1
+EXT
+
+openssl req -new -newkey rsa:2048 -nodes -keyout client.key -subj "/CN=admin" -out client.csr
+openssl x509 -req -in client.csr -CA ca.crt -CAkey ca.key -out client.crt \
+  -not_before 20250101000000Z -not_after 20260101000000Z \
+  -extfile /dev/stdin <<EXT
+basicConstraints=critical,CA:FALSE
+EXT
+
+rm ca.key other-ca.key server.csr client.csr